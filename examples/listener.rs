@@ -1,8 +1,8 @@
 use nt::*;
 
 #[tokio::main]
-async fn main() {
-    let mut nt = NetworkTables::bind("0.0.0.0:1735", "nt-rs-server");
+async fn main() -> Result<()> {
+    let mut nt = NetworkTables::bind("0.0.0.0:1735", "nt-rs-server").await?;
 
     nt.add_connection_callback(ConnectionCallbackType::ClientConnected, |addr| {
         println!("Client connected! {}", addr);
@@ -15,5 +15,7 @@ async fn main() {
         println!("Got new entry {:?}", data);
     });
 
-    loop {}
+    loop {
+        tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+    }
 }
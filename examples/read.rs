@@ -13,5 +13,7 @@ async fn main() -> anyhow::Result<()> {
         println!("{} => {:?}", id, data);
     }
 
-    loop { /* Spin lock */ }
+    loop {
+        tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+    }
 }
@@ -9,17 +9,15 @@ async fn main() -> Result<()> {
     let mut i = 0;
     loop {
         println!("RUNNING LOOP");
-        client
-            .entries()
-            .iter()
-            .for_each(|(id, entry)| match entry.value {
-                EntryValue::RpcDefinition(RpcDefinition::V0) => {
-                    client.call_rpc(*id, (0..(i % 20)).collect(), |res| {
-                        println!("RECEIVED RESPONSE: {:?}", res);
-                    })
-                }
-                _ => {}
-            });
+        for (id, entry) in client.entries() {
+            if let EntryValue::RpcDefinition(RpcDefinition::V1(ref def)) = entry.value {
+                let params = [EntryValue::Double(i as f64), EntryValue::Double(1.0)];
+                let def = def.clone();
+                client.call_rpc(id, def.encode_parameters(&params)?, move |res| {
+                    println!("RECEIVED RESPONSE: {:?}", def.decode_results(&res));
+                })?;
+            }
+        }
         thread::sleep(Duration::from_millis(100));
         i += 1;
     }
@@ -3,8 +3,8 @@ use std::thread;
 use std::time::Duration;
 
 #[tokio::main]
-async fn main() {
-    let mut nt = NetworkTables::bind("0.0.0.0:1735", "nt-rs-server");
+async fn main() -> Result<()> {
+    let mut nt = NetworkTables::bind("0.0.0.0:1735", "nt-rs-server").await?;
 
     nt.add_connection_callback(ConnectionCallbackType::ClientConnected, |addr| {
         println!("Client connected! {}", addr);
@@ -17,22 +17,40 @@ async fn main() {
         println!("Got new entry {:?}", data);
     });
 
+    let definition = RpcDefinitionV1::new(
+        "sum".into(),
+        vec![
+            RpcParameter::new("a".into(), EntryValue::Double(0.0)),
+            RpcParameter::new("b".into(), EntryValue::Double(0.0)),
+        ],
+        vec![RpcResult::new(EntryType::Double, "sum".into())],
+    );
+
     nt.create_rpc(
         EntryData::new(
             "TEST_RPC".into(),
             0,
-            EntryValue::RpcDefinition(RpcDefinition::V0),
+            EntryValue::RpcDefinition(RpcDefinition::V1(definition.clone())),
         ),
-        |parameter| {
-            let mut sum = 0;
-            for i in parameter.clone() {
-                sum += i;
-            }
-            println!("{:?}", parameter);
+        move |parameter| {
+            let params = definition.decode_parameters(&parameter).unwrap();
+            println!("{:?}", params);
+
+            let sum = params
+                .iter()
+                .map(|param| match param {
+                    EntryValue::Double(d) => *d,
+                    _ => 0.0,
+                })
+                .sum();
             thread::sleep(Duration::from_millis(600));
-            vec![sum]
+            definition
+                .encode_results(&[EntryValue::Double(sum)])
+                .unwrap()
         },
-    );
+    )?;
 
-    loop {}
+    loop {
+        tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+    }
 }
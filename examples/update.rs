@@ -1,4 +1,4 @@
-use nt::{EntryData, EntryValue, NetworkTables};
+use nt::{EntryData, NetworkTables};
 use std::thread;
 use std::time::Duration;
 
@@ -9,21 +9,17 @@ async fn main() -> anyhow::Result<()> {
     let client = NetworkTables::connect("127.0.0.1:1735", "nt-rs").await?;
     println!("Creating entry");
     let entry_id = client
-        .create_entry(EntryData::new(
-            "update1".to_string(),
-            0,
-            EntryValue::String("Hello!".to_string()),
-        ))
+        .create_entry(EntryData::new("update1".to_string(), 0, "Hello!".into()))
         .await?;
     println!("Entry created");
 
     {
-        let mut entry = client.get_entry(entry_id);
-        println!("{} ==> {:?}", entry.id(), entry.value());
+        let mut entry = client.get_typed_entry::<String>(entry_id);
+        println!("{} ==> {:?}", entry.id(), entry.get());
 
         println!("Changing value");
-        entry.set_value(EntryValue::String("World!".to_string()));
-        println!("2 {} ==> {:?}", entry.id(), entry.value());
+        entry.set("World!".to_string())?;
+        println!("2 {} ==> {:?}", entry.id(), entry.get());
     }
 
     thread::sleep(Duration::from_millis(250));
@@ -22,7 +22,9 @@ async fn main() {
         .await
         .expect("Client is not connected to the server.");
     println!("Entry should have been created: {}", id);
-    loop {}
+    loop {
+        tokio::time::delay_for(std::time::Duration::from_secs(1)).await;
+    }
 }
 
 #[tokio::main]
@@ -1,12 +1,13 @@
+use crate::error::{DecodeError, EncodeError};
 use crate::ext::*;
+use crate::limits::DecodeLimits;
 use crate::{
     ClearAllEntries, ClientHello, EntryAssignment, EntryDelete, EntryFlagsUpdate, EntryUpdate,
-    Packet, ProtocolVersionUnsupported, Result, RpcExecute, RpcResponse, ServerHello,
+    Packet, ProtocolVersionUnsupported, RpcExecute, RpcResponse, ServerHello,
 };
-use anyhow::anyhow;
 use bytes::{Buf, BytesMut};
-use std::io;
 use tokio_util::codec::{Decoder, Encoder};
+use tracing::{debug, trace};
 
 #[derive(Clone, Debug)]
 pub enum ReceivedPacket {
@@ -25,108 +26,247 @@ pub enum ReceivedPacket {
     RpcResponse(RpcResponse),
 }
 
-pub struct NTCodec;
+impl ReceivedPacket {
+    /// The name of the type of this packet, for logging
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ReceivedPacket::KeepAlive => "KeepAlive",
+            ReceivedPacket::ClientHello(_) => "ClientHello",
+            ReceivedPacket::ProtocolVersionUnsupported(_) => "ProtocolVersionUnsupported",
+            ReceivedPacket::ServerHelloComplete => "ServerHelloComplete",
+            ReceivedPacket::ServerHello(_) => "ServerHello",
+            ReceivedPacket::ClientHelloComplete => "ClientHelloComplete",
+            ReceivedPacket::EntryAssignment(_) => "EntryAssignment",
+            ReceivedPacket::EntryUpdate(_) => "EntryUpdate",
+            ReceivedPacket::EntryFlagsUpdate(_) => "EntryFlagsUpdate",
+            ReceivedPacket::EntryDelete(_) => "EntryDelete",
+            ReceivedPacket::ClearAllEntries(_) => "ClearAllEntries",
+            ReceivedPacket::RpcExecute(_) => "RpcExecute",
+            ReceivedPacket::RpcResponse(_) => "RpcResponse",
+        }
+    }
+
+    /// The id of the entry this packet is about, if it is about one
+    pub fn entry_id(&self) -> Option<u16> {
+        match self {
+            ReceivedPacket::EntryAssignment(ea) => Some(ea.entry_id),
+            ReceivedPacket::EntryUpdate(eu) => Some(eu.entry_id),
+            ReceivedPacket::EntryFlagsUpdate(efu) => Some(efu.entry_id),
+            ReceivedPacket::EntryDelete(ed) => Some(ed.entry_id),
+            ReceivedPacket::RpcExecute(rpc) => Some(rpc.entry_id),
+            ReceivedPacket::RpcResponse(rpc) => Some(rpc.entry_id),
+            _ => None,
+        }
+    }
+}
+
+/// Formats bytes as space separated hex, for packet dumps
+#[cfg(feature = "packet-dump")]
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Codec for NT3 packets
+#[derive(Default)]
+pub struct NTCodec {
+    limits: DecodeLimits,
+}
+
+impl NTCodec {
+    /// A codec that fails to decode packets larger than `limits` allow
+    pub fn with_limits(limits: DecodeLimits) -> NTCodec {
+        NTCodec { limits }
+    }
+}
 
 impl Encoder for NTCodec {
     type Item = Box<dyn Packet>;
-    type Error = anyhow::Error;
+    type Error = EncodeError;
 
-    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<()> {
-        dst.put_serializable(&*item);
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), EncodeError> {
+        let start = dst.len();
+        if let Err(e) = item.serialize(dst) {
+            // Don't leave part of the packet behind to be sent
+            dst.truncate(start);
+            return Err(e);
+        }
+
+        let packet = item.to_received();
+        trace!(
+            packet = packet.as_ref().map_or("Unknown", ReceivedPacket::kind),
+            entry_id = ?packet.as_ref().and_then(ReceivedPacket::entry_id),
+            len = dst.len() - start,
+            "Encoded packet"
+        );
+        #[cfg(feature = "packet-dump")]
+        trace!(bytes = %hex(&dst[start..]), "Sent");
         Ok(())
     }
 }
 
 impl Decoder for NTCodec {
     type Item = ReceivedPacket;
-    type Error = anyhow::Error;
+    type Error = DecodeError;
 
-    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<ReceivedPacket>> {
-        let mut buf = src.clone().freeze();
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<ReceivedPacket>, DecodeError> {
+        // Packets are parsed in place, and only consumed once they are complete
+        let mut buf = &src[..];
 
         if buf.remaining() < 1 {
             return Ok(None);
         }
 
-        let (packet, bytes) = match try_decode(&mut buf) {
+        let decoded = try_decode(&mut buf).and_then(|(packet, bytes)| {
+            self.limits.check_frame(bytes)?;
+            self.limits.check_packet(&packet)?;
+            Ok((packet, bytes))
+        });
+        let (packet, bytes) = match decoded {
             Ok(t) => t,
-            Err(e) => match e.downcast_ref::<io::Error>() {
-                Some(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
-                    return Ok(None);
-                }
-                _ => return Err(e),
-            },
+            // A packet that is still incomplete once the frame limit has been buffered never will be
+            Err(DecodeError::Incomplete) => {
+                return self.limits.check_frame(src.len()).map(|_| None)
+            }
+            Err(e) => {
+                debug!(error = %e, "Failed to decode packet");
+                #[cfg(feature = "packet-dump")]
+                trace!(bytes = %hex(&src[..]), "Undecodable");
+                return Err(e);
+            }
         };
 
+        trace!(
+            packet = packet.kind(),
+            entry_id = ?packet.entry_id(),
+            len = bytes,
+            "Decoded packet"
+        );
+        #[cfg(feature = "packet-dump")]
+        trace!(bytes = %hex(&src[..bytes]), "Received");
+
         src.advance(bytes);
         Ok(Some(packet))
     }
 }
 
-fn try_decode(mut buf: &mut dyn Buf) -> Result<(ReceivedPacket, usize)> {
+fn try_decode(mut buf: &mut dyn Buf) -> Result<(ReceivedPacket, usize), DecodeError> {
     let id = buf.read_u8()?;
 
     let mut bytes = 1;
 
     let packet = match id {
-        0x00 => Some(ReceivedPacket::KeepAlive),
+        0x00 => ReceivedPacket::KeepAlive,
         0x01 => {
             let (packet, read) = ClientHello::deserialize(buf)?;
             bytes += read;
-            Some(ReceivedPacket::ClientHello(packet))
+            ReceivedPacket::ClientHello(packet)
         }
         0x02 => {
             let (packet, read) = ProtocolVersionUnsupported::deserialize(buf)?;
             bytes += read;
-            Some(ReceivedPacket::ProtocolVersionUnsupported(packet))
+            ReceivedPacket::ProtocolVersionUnsupported(packet)
         }
-        0x03 => Some(ReceivedPacket::ServerHelloComplete),
+        0x03 => ReceivedPacket::ServerHelloComplete,
         0x04 => {
             let (packet, read) = ServerHello::deserialize(buf)?;
             bytes += read;
-            Some(ReceivedPacket::ServerHello(packet))
+            ReceivedPacket::ServerHello(packet)
         }
-        0x05 => Some(ReceivedPacket::ClientHelloComplete),
+        0x05 => ReceivedPacket::ClientHelloComplete,
         0x10 => {
             let (packet, read) = EntryAssignment::deserialize(buf)?;
             bytes += read;
-            Some(ReceivedPacket::EntryAssignment(packet))
+            ReceivedPacket::EntryAssignment(packet)
         }
         0x11 => {
             let (packet, read) = EntryUpdate::deserialize(buf)?;
             bytes += read;
-            Some(ReceivedPacket::EntryUpdate(packet))
+            ReceivedPacket::EntryUpdate(packet)
         }
         0x12 => {
             let (packet, read) = EntryFlagsUpdate::deserialize(buf)?;
             bytes += read;
-            Some(ReceivedPacket::EntryFlagsUpdate(packet))
+            ReceivedPacket::EntryFlagsUpdate(packet)
         }
         0x13 => {
             let (packet, read) = EntryDelete::deserialize(buf)?;
             bytes += read;
-            Some(ReceivedPacket::EntryDelete(packet))
+            ReceivedPacket::EntryDelete(packet)
         }
         0x14 => {
             let (packet, read) = ClearAllEntries::deserialize(buf)?;
             bytes += read;
-            Some(ReceivedPacket::ClearAllEntries(packet))
+            ReceivedPacket::ClearAllEntries(packet)
         }
         0x20 => {
             let (packet, read) = RpcExecute::deserialize(buf)?;
             bytes += read;
-            Some(ReceivedPacket::RpcExecute(packet))
+            ReceivedPacket::RpcExecute(packet)
         }
         0x21 => {
             let (packet, read) = RpcResponse::deserialize(buf)?;
             bytes += read;
-            Some(ReceivedPacket::RpcResponse(packet))
+            ReceivedPacket::RpcResponse(packet)
         }
-        _ => None,
+        id => return Err(DecodeError::UnknownPacketId(id)),
     };
 
-    match packet {
-        Some(packet) => Ok((packet, bytes)),
-        None => Err(anyhow!("Failed to decode packet")),
+    Ok((packet, bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{EntryType, EntryValue};
+
+    fn encoded(packets: Vec<Box<dyn Packet>>) -> BytesMut {
+        let mut buf = BytesMut::new();
+        for packet in packets {
+            NTCodec::default().encode(packet, &mut buf).unwrap();
+        }
+        buf
+    }
+
+    #[test]
+    fn incomplete_packets_are_left_in_the_buffer() {
+        let update = EntryUpdate::new(7, 1, EntryType::Double, EntryValue::Double(2.5));
+        let full = encoded(vec![Box::new(update)]);
+
+        let mut codec = NTCodec::default();
+        let mut src = BytesMut::from(&full[..full.len() - 1]);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        assert_eq!(src.len(), full.len() - 1);
+
+        src.extend_from_slice(&full[full.len() - 1..]);
+        match codec.decode(&mut src).unwrap() {
+            Some(ReceivedPacket::EntryUpdate(eu)) => {
+                assert_eq!(eu.entry_id, 7);
+                assert_eq!(eu.entry_value, EntryValue::Double(2.5));
+            }
+            packet => panic!("expected an entry update, got {:?}", packet),
+        }
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn packets_are_consumed_one_at_a_time() {
+        let mut src = encoded(vec![
+            Box::new(ClearAllEntries::new()),
+            Box::new(EntryDelete::new(3)),
+        ]);
+        let mut codec = NTCodec::default();
+        assert!(matches!(
+            codec.decode(&mut src).unwrap(),
+            Some(ReceivedPacket::ClearAllEntries(_))
+        ));
+        assert!(matches!(
+            codec.decode(&mut src).unwrap(),
+            Some(ReceivedPacket::EntryDelete(ed)) if ed.entry_id == 3
+        ));
+        assert!(codec.decode(&mut src).unwrap().is_none());
     }
 }
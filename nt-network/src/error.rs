@@ -0,0 +1,82 @@
+//! Errors produced when encoding and decoding packets
+
+use crate::types::{EntryType, RpcError};
+use std::io;
+use std::string::FromUtf8Error;
+use thiserror::Error;
+
+/// The largest length accepted for a string, array or RPC definition. Larger lengths can only come
+/// from a corrupt stream.
+pub const MAX_LENGTH: u64 = u32::MAX as u64;
+
+/// An error decoding a packet
+#[derive(Debug, Error)]
+pub enum DecodeError {
+    /// The buffer ends part way through the packet. Codecs wait for more bytes when they see this,
+    /// rather than failing.
+    #[error("Not enough bytes to decode the packet")]
+    Incomplete,
+    #[error("Unknown packet id {0:#04x}")]
+    UnknownPacketId(u8),
+    #[error("Invalid entry type {0:#04x}")]
+    InvalidEntryType(u8),
+    #[error("{0:?} values can't be sent over this protocol")]
+    UnsupportedEntryType(EntryType),
+    #[error("Invalid protocol version {0:#06x}")]
+    InvalidVersion(u16),
+    #[error("String is not valid UTF-8")]
+    InvalidUtf8(#[from] FromUtf8Error),
+    #[error("Invalid ClearAllEntries magic {0:#010x}")]
+    BadClearAllMagic(u32),
+    #[error("Invalid RPC definition: {0}")]
+    InvalidRpcDefinition(String),
+    #[error("LEB128 value doesn't fit in 64 bits")]
+    Leb128Overflow,
+    #[error("Length {len} is over the limit of {limit}")]
+    LengthLimitExceeded { len: u64, limit: u64 },
+    /// A packet that is larger than the `DecodeLimits` of the codec allow
+    #[error("{what} of {len} is over the limit of {limit}")]
+    LimitExceeded {
+        what: &'static str,
+        len: usize,
+        limit: usize,
+    },
+    /// RPC parameters or results with more bytes than the definition declares values for
+    #[error("{0} bytes left over after the last value")]
+    TrailingBytes(usize),
+    #[error("Update for unassigned entry {0}")]
+    UnassignedEntry(u16),
+    /// An NT4 message that isn't valid JSON or MessagePack, or doesn't have the expected shape
+    #[error("Invalid NT4 message: {0}")]
+    InvalidMessage(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl From<nt_leb128::read::Error> for DecodeError {
+    fn from(err: nt_leb128::read::Error) -> Self {
+        match err {
+            // Reading from a buffer only fails when it runs out
+            nt_leb128::read::Error::IoError(_) => DecodeError::Incomplete,
+            nt_leb128::read::Error::Overflow => DecodeError::Leb128Overflow,
+        }
+    }
+}
+
+/// An error encoding a packet
+#[derive(Debug, Error)]
+pub enum EncodeError {
+    #[error("{0} packets can't be sent over this protocol")]
+    UnsupportedPacket(&'static str),
+    #[error("{0:?} values can't be sent over this protocol")]
+    UnsupportedEntryType(EntryType),
+    #[error("Length {len} is over the limit of {limit}")]
+    LengthLimitExceeded { len: u64, limit: u64 },
+    #[error(transparent)]
+    Rpc(#[from] RpcError),
+    /// An NT4 message that couldn't be serialized to JSON or MessagePack
+    #[error("Invalid NT4 message: {0}")]
+    InvalidMessage(String),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
@@ -1,13 +1,17 @@
 pub mod codec;
+pub mod error;
 mod ext;
+pub mod limits;
+pub mod nt2;
+pub mod nt4;
 mod packets;
-
-use anyhow::anyhow;
-
-pub type Result<T> = std::result::Result<T, anyhow::Error>;
+mod seqnum;
 
 pub use self::codec::ReceivedPacket;
+pub use self::error::{DecodeError, EncodeError};
+pub use self::limits::DecodeLimits;
 pub use self::packets::*;
+pub use self::seqnum::SequenceNumber;
 
 #[repr(u16)]
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
@@ -17,11 +21,11 @@ pub enum NTVersion {
 }
 
 impl NTVersion {
-    pub fn from_u16(v: u16) -> Result<NTVersion> {
+    pub fn from_u16(v: u16) -> Result<NTVersion, DecodeError> {
         match v {
             0x0200 => Ok(NTVersion::V2),
             0x0300 => Ok(NTVersion::V3),
-            _ => Err(anyhow!("Invalid version passed in packet. {:#x}", v)),
+            _ => Err(DecodeError::InvalidVersion(v)),
         }
     }
 }
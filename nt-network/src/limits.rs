@@ -0,0 +1,109 @@
+//! Limits on the size of decoded packets, for peers that can't be trusted to send sensible ones
+
+use crate::error::DecodeError;
+use crate::nt4::{ControlMessage, ValueFrame};
+use crate::types::EntryValue;
+use crate::ReceivedPacket;
+
+/// The largest strings, arrays and frames a codec will decode. Anything larger fails with
+/// `DecodeError::LimitExceeded`.
+///
+/// Raw data and RPC parameters count as strings. The default places no limits.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// The longest string, in bytes
+    pub max_string_len: usize,
+    /// The most elements in an array value
+    pub max_array_len: usize,
+    /// The most bytes buffered for a single packet or websocket message
+    pub max_frame_size: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_string_len: usize::MAX,
+            max_array_len: usize::MAX,
+            max_frame_size: usize::MAX,
+        }
+    }
+}
+
+fn check(what: &'static str, len: usize, limit: usize) -> Result<(), DecodeError> {
+    if len > limit {
+        Err(DecodeError::LimitExceeded { what, len, limit })
+    } else {
+        Ok(())
+    }
+}
+
+impl DecodeLimits {
+    pub fn check_frame(&self, len: usize) -> Result<(), DecodeError> {
+        check("Frame size", len, self.max_frame_size)
+    }
+
+    pub fn check_str(&self, s: &str) -> Result<(), DecodeError> {
+        check("String length", s.len(), self.max_string_len)
+    }
+
+    pub fn check_bytes(&self, bytes: &[u8]) -> Result<(), DecodeError> {
+        check("Raw data length", bytes.len(), self.max_string_len)
+    }
+
+    pub fn check_array<T>(&self, array: &[T]) -> Result<(), DecodeError> {
+        check("Array length", array.len(), self.max_array_len)
+    }
+
+    pub fn check_value(&self, value: &EntryValue) -> Result<(), DecodeError> {
+        match value {
+            EntryValue::String(s) => self.check_str(s),
+            EntryValue::RawData(data) => self.check_bytes(data),
+            EntryValue::BooleanArray(v) => self.check_array(v),
+            EntryValue::DoubleArray(v) => self.check_array(v),
+            EntryValue::IntegerArray(v) => self.check_array(v),
+            EntryValue::FloatArray(v) => self.check_array(v),
+            EntryValue::StringArray(v) => {
+                self.check_array(v)?;
+                v.iter().try_for_each(|s| self.check_str(s))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the names, values and RPC data of a decoded NT2 or NT3 packet
+    pub fn check_packet(&self, packet: &ReceivedPacket) -> Result<(), DecodeError> {
+        match packet {
+            ReceivedPacket::ClientHello(hello) => self.check_str(&hello.name),
+            ReceivedPacket::ServerHello(hello) => self.check_str(&hello.name),
+            ReceivedPacket::EntryAssignment(ea) => {
+                self.check_str(&ea.entry_name)?;
+                self.check_value(&ea.entry_value)
+            }
+            ReceivedPacket::EntryUpdate(eu) => self.check_value(&eu.entry_value),
+            ReceivedPacket::RpcExecute(rpc) => self.check_bytes(&rpc.parameter),
+            ReceivedPacket::RpcResponse(rpc) => self.check_bytes(&rpc.result),
+            _ => Ok(()),
+        }
+    }
+
+    /// Checks the topic names of a decoded NT4 control message
+    pub fn check_control(&self, msg: &ControlMessage) -> Result<(), DecodeError> {
+        match msg {
+            ControlMessage::Publish(publish) => self.check_str(&publish.name),
+            ControlMessage::SetProperties(set) => self.check_str(&set.name),
+            ControlMessage::Subscribe(subscribe) => {
+                self.check_array(&subscribe.topics)?;
+                subscribe.topics.iter().try_for_each(|t| self.check_str(t))
+            }
+            ControlMessage::Announce(announce) => self.check_str(&announce.name),
+            ControlMessage::Unannounce(unannounce) => self.check_str(&unannounce.name),
+            ControlMessage::Properties(update) => self.check_str(&update.name),
+            ControlMessage::Unpublish(_) | ControlMessage::Unsubscribe(_) => Ok(()),
+        }
+    }
+
+    /// Checks the value of a decoded NT4 value frame
+    pub fn check_value_frame(&self, frame: &ValueFrame) -> Result<(), DecodeError> {
+        self.check_value(&frame.entry_value)
+    }
+}
@@ -0,0 +1,406 @@
+//! Codec for NetworkTables 2 traffic
+//!
+//! NT2 is a subset of NT3, with 16-bit string lengths, 8-bit array lengths, and no entry flags,
+//! deletion, or RPC. [`NT2Codec`](struct.NT2Codec.html) translates between the NT2 wire format and
+//! the NT3 packet types, so that NT2 peers can be handled like any other connection.
+
+use crate::error::{DecodeError, EncodeError};
+use crate::ext::*;
+use crate::limits::DecodeLimits;
+use crate::packets::types::{EntryType, EntryValue};
+use crate::{
+    ClientHello, EntryAssignment, EntryUpdate, NTVersion, Packet, ProtocolVersionUnsupported,
+    ReceivedPacket,
+};
+use bytes::{Buf, BufMut, BytesMut};
+use std::collections::HashMap;
+use tokio_util::codec::{Decoder, Encoder};
+
+/// Codec translating NT3 packets to and from the NT2 wire format
+///
+/// NT2 entry updates don't carry the type of the entry, so the codec remembers the type of every
+/// entry assigned over the connection. Packets with no NT2 equivalent, and values that can't be
+/// represented in NT2, are dropped when encoding. Strings and arrays too long for NT2 fail to encode.
+#[derive(Default)]
+pub struct NT2Codec {
+    types: HashMap<u16, EntryType>,
+    limits: DecodeLimits,
+}
+
+impl Encoder for NT2Codec {
+    type Item = Box<dyn Packet>;
+    type Error = EncodeError;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), EncodeError> {
+        let packet = match item.to_received() {
+            Some(packet) => packet,
+            None => return Ok(()),
+        };
+
+        let mut buf = BytesMut::new();
+        match self.encode_packet(packet, &mut buf) {
+            Ok(()) => dst.extend_from_slice(&buf[..]),
+            // NT2 peers never see what it has no equivalent for
+            Err(EncodeError::UnsupportedPacket(_)) | Err(EncodeError::UnsupportedEntryType(_)) => {}
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+}
+
+impl Decoder for NT2Codec {
+    type Item = ReceivedPacket;
+    type Error = DecodeError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<ReceivedPacket>, DecodeError> {
+        // Packets are parsed in place, and only consumed once they are complete
+        let mut buf = &src[..];
+
+        if buf.remaining() < 1 {
+            return Ok(None);
+        }
+
+        let len = buf.remaining();
+        let decoded = self.try_decode(&mut buf).and_then(|packet| {
+            self.limits.check_frame(len - buf.remaining())?;
+            self.limits.check_packet(&packet)?;
+            Ok(packet)
+        });
+        let packet = match decoded {
+            Ok(packet) => packet,
+            Err(DecodeError::Incomplete) => return self.limits.check_frame(len).map(|_| None),
+            Err(e) => return Err(e),
+        };
+
+        if let ReceivedPacket::EntryAssignment(ref ea) = packet {
+            self.types.insert(ea.entry_id, ea.entry_type);
+        }
+
+        src.advance(len - buf.remaining());
+        Ok(Some(packet))
+    }
+}
+
+impl NT2Codec {
+    /// A codec that fails to decode packets larger than `limits` allow
+    pub fn with_limits(limits: DecodeLimits) -> NT2Codec {
+        NT2Codec {
+            types: HashMap::new(),
+            limits,
+        }
+    }
+
+    fn encode_packet(
+        &mut self,
+        packet: ReceivedPacket,
+        buf: &mut BytesMut,
+    ) -> Result<(), EncodeError> {
+        match packet {
+            ReceivedPacket::KeepAlive => buf.put_u8(0x00),
+            ReceivedPacket::ClientHello(_) => {
+                buf.put_u8(0x01);
+                buf.put_u16(NTVersion::V2 as u16);
+            }
+            ReceivedPacket::ProtocolVersionUnsupported(pvu) => {
+                buf.put_u8(0x02);
+                buf.put_u16(pvu.supported_version);
+            }
+            ReceivedPacket::ServerHelloComplete => buf.put_u8(0x03),
+            ReceivedPacket::EntryAssignment(ea) => {
+                buf.put_u8(0x10);
+                write_string(&ea.entry_name, buf)?;
+                write_type(ea.entry_type, buf)?;
+                buf.put_u16(ea.entry_id);
+                buf.put_u16(ea.entry_seqnum);
+                write_value(&ea.entry_value, buf)?;
+
+                // Entries created by a client don't have an id until the server assigns one
+                if ea.entry_id != 0xFFFF {
+                    self.types.insert(ea.entry_id, ea.entry_type);
+                }
+            }
+            ReceivedPacket::EntryUpdate(eu) => {
+                buf.put_u8(0x11);
+                buf.put_u16(eu.entry_id);
+                buf.put_u16(eu.entry_seqnum);
+                write_value(&eu.entry_value, buf)?;
+            }
+            packet => return Err(EncodeError::UnsupportedPacket(packet.kind())),
+        }
+        Ok(())
+    }
+
+    fn try_decode(&self, mut buf: &mut dyn Buf) -> Result<ReceivedPacket, DecodeError> {
+        let packet = match buf.read_u8()? {
+            0x00 => ReceivedPacket::KeepAlive,
+            0x01 => {
+                let version = NTVersion::from_u16(buf.read_u16_be()?)?;
+                ReceivedPacket::ClientHello(ClientHello::new(version, String::new()))
+            }
+            0x02 => ReceivedPacket::ProtocolVersionUnsupported(ProtocolVersionUnsupported {
+                supported_version: buf.read_u16_be()?,
+            }),
+            0x03 => ReceivedPacket::ServerHelloComplete,
+            0x10 => {
+                let name = read_string(buf)?;
+                let (entry_type, _) = EntryType::deserialize(buf)?;
+                let entry_id = buf.read_u16_be()?;
+                let entry_seqnum = buf.read_u16_be()?;
+                let entry_value = read_value(entry_type, buf)?;
+                ReceivedPacket::EntryAssignment(EntryAssignment::new(
+                    name,
+                    entry_type,
+                    entry_id,
+                    entry_seqnum,
+                    0,
+                    entry_value,
+                ))
+            }
+            0x11 => {
+                let entry_id = buf.read_u16_be()?;
+                let entry_seqnum = buf.read_u16_be()?;
+                let entry_type = *self
+                    .types
+                    .get(&entry_id)
+                    .ok_or(DecodeError::UnassignedEntry(entry_id))?;
+                let entry_value = read_value(entry_type, buf)?;
+                ReceivedPacket::EntryUpdate(EntryUpdate::new(
+                    entry_id,
+                    entry_seqnum,
+                    entry_type,
+                    entry_value,
+                ))
+            }
+            id => return Err(DecodeError::UnknownPacketId(id)),
+        };
+        Ok(packet)
+    }
+}
+
+fn write_type(ty: EntryType, buf: &mut BytesMut) -> Result<(), EncodeError> {
+    match ty {
+        EntryType::RawData | EntryType::RpcDefinition => Err(EncodeError::UnsupportedEntryType(ty)),
+        ty => ty.serialize(buf),
+    }
+}
+
+/// Writes a value in the NT2 encoding. As in NT3, NT4 only types are widened to doubles.
+fn write_value(value: &EntryValue, buf: &mut BytesMut) -> Result<(), EncodeError> {
+    match value {
+        EntryValue::Boolean(b) => buf.put_u8(*b as u8),
+        EntryValue::Double(d) => buf.put_f64(*d),
+        EntryValue::Integer(i) => buf.put_f64(*i as f64),
+        EntryValue::Float(f) => buf.put_f64(*f as f64),
+        EntryValue::String(s) => write_string(s, buf)?,
+        EntryValue::BooleanArray(v) => {
+            write_array_len(v.len(), buf)?;
+            v.iter().for_each(|b| buf.put_u8(*b as u8));
+        }
+        EntryValue::DoubleArray(v) => {
+            write_array_len(v.len(), buf)?;
+            v.iter().for_each(|d| buf.put_f64(*d));
+        }
+        EntryValue::IntegerArray(v) => {
+            write_array_len(v.len(), buf)?;
+            v.iter().for_each(|i| buf.put_f64(*i as f64));
+        }
+        EntryValue::FloatArray(v) => {
+            write_array_len(v.len(), buf)?;
+            v.iter().for_each(|f| buf.put_f64(*f as f64));
+        }
+        EntryValue::StringArray(v) => {
+            write_array_len(v.len(), buf)?;
+            for s in v {
+                write_string(s, buf)?;
+            }
+        }
+        EntryValue::RawData(_) | EntryValue::RpcDefinition(_) => {
+            return Err(EncodeError::UnsupportedEntryType(value.entry_type()))
+        }
+    }
+    Ok(())
+}
+
+fn read_value(ty: EntryType, mut buf: &mut dyn Buf) -> Result<EntryValue, DecodeError> {
+    let value = match ty {
+        EntryType::Boolean => EntryValue::Boolean(buf.read_u8()? == 1),
+        EntryType::Double => EntryValue::Double(buf.read_f64_be()?),
+        EntryType::Integer => EntryValue::Integer(buf.read_f64_be()? as i64),
+        EntryType::Float => EntryValue::Float(buf.read_f64_be()? as f32),
+        EntryType::String => EntryValue::String(read_string(buf)?),
+        EntryType::BooleanArray => {
+            let len = buf.read_u8()?;
+            EntryValue::BooleanArray(
+                (0..len)
+                    .map(|_| Ok(buf.read_u8()? == 1))
+                    .collect::<Result<_, DecodeError>>()?,
+            )
+        }
+        EntryType::DoubleArray => EntryValue::DoubleArray(read_doubles(buf)?),
+        EntryType::IntegerArray => {
+            EntryValue::IntegerArray(read_doubles(buf)?.into_iter().map(|d| d as i64).collect())
+        }
+        EntryType::FloatArray => {
+            EntryValue::FloatArray(read_doubles(buf)?.into_iter().map(|d| d as f32).collect())
+        }
+        EntryType::StringArray => {
+            let len = buf.read_u8()?;
+            EntryValue::StringArray(
+                (0..len)
+                    .map(|_| read_string(buf))
+                    .collect::<Result<_, _>>()?,
+            )
+        }
+        EntryType::RawData | EntryType::RpcDefinition => {
+            return Err(DecodeError::UnsupportedEntryType(ty))
+        }
+    };
+    Ok(value)
+}
+
+fn read_doubles(mut buf: &mut dyn Buf) -> Result<Vec<f64>, DecodeError> {
+    let len = buf.read_u8()?;
+    (0..len).map(|_| buf.read_f64_be()).collect()
+}
+
+fn write_array_len(len: usize, buf: &mut BytesMut) -> Result<(), EncodeError> {
+    if len > u8::MAX as usize {
+        return Err(EncodeError::LengthLimitExceeded {
+            len: len as u64,
+            limit: u8::MAX as u64,
+        });
+    }
+    buf.put_u8(len as u8);
+    Ok(())
+}
+
+fn write_string(s: &str, buf: &mut BytesMut) -> Result<(), EncodeError> {
+    if s.len() > u16::MAX as usize {
+        return Err(EncodeError::LengthLimitExceeded {
+            len: s.len() as u64,
+            limit: u16::MAX as u64,
+        });
+    }
+    buf.put_u16(s.len() as u16);
+    buf.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+fn read_string(mut buf: &mut dyn Buf) -> Result<String, DecodeError> {
+    let len = buf.read_u16_be()? as usize;
+    if buf.remaining() < len {
+        return Err(DecodeError::Incomplete);
+    }
+    let mut bytes = vec![0u8; len];
+    buf.copy_to_slice(&mut bytes[..]);
+    Ok(String::from_utf8(bytes)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(codec: &mut NT2Codec, packet: Box<dyn Packet>) -> Result<BytesMut, EncodeError> {
+        let mut buf = BytesMut::new();
+        codec.encode(packet, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn assignment(id: u16, value: EntryValue) -> Box<EntryAssignment> {
+        Box::new(EntryAssignment::new(
+            "/value".to_string(),
+            value.entry_type(),
+            id,
+            1,
+            0,
+            value,
+        ))
+    }
+
+    #[test]
+    fn assignments_and_updates_round_trip() {
+        let value = EntryValue::StringArray(vec!["a".to_string(), "b".to_string()]);
+        let mut src = encode(&mut NT2Codec::default(), assignment(4, value.clone())).unwrap();
+        let update = EntryUpdate::new(
+            4,
+            2,
+            EntryType::StringArray,
+            EntryValue::StringArray(vec!["c".to_string()]),
+        );
+        src.extend_from_slice(&encode(&mut NT2Codec::default(), Box::new(update)).unwrap());
+
+        let mut codec = NT2Codec::default();
+        match codec.decode(&mut src).unwrap() {
+            Some(ReceivedPacket::EntryAssignment(ea)) => {
+                assert_eq!(ea.entry_name, "/value");
+                assert_eq!(ea.entry_id, 4);
+                assert_eq!(ea.entry_seqnum, 1);
+                assert_eq!(ea.entry_value, value);
+            }
+            packet => panic!("expected an entry assignment, got {:?}", packet),
+        }
+        match codec.decode(&mut src).unwrap() {
+            Some(ReceivedPacket::EntryUpdate(eu)) => {
+                assert_eq!(eu.entry_id, 4);
+                assert_eq!(eu.entry_seqnum, 2);
+                assert_eq!(
+                    eu.entry_value,
+                    EntryValue::StringArray(vec!["c".to_string()])
+                );
+            }
+            packet => panic!("expected an entry update, got {:?}", packet),
+        }
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn updates_of_unassigned_entries_are_rejected() {
+        let update = EntryUpdate::new(9, 1, EntryType::Double, EntryValue::Double(1.0));
+        let mut src = encode(&mut NT2Codec::default(), Box::new(update)).unwrap();
+        match NT2Codec::default().decode(&mut src) {
+            Err(DecodeError::UnassignedEntry(9)) => {}
+            res => panic!("expected an unassigned entry, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn arrays_are_limited_to_255_elements() {
+        let mut codec = NT2Codec::default();
+        encode(
+            &mut codec,
+            assignment(1, EntryValue::DoubleArray(vec![0.0; 255])),
+        )
+        .unwrap();
+        match encode(
+            &mut codec,
+            assignment(2, EntryValue::DoubleArray(vec![0.0; 256])),
+        ) {
+            Err(EncodeError::LengthLimitExceeded {
+                len: 256,
+                limit: 255,
+            }) => {}
+            res => panic!("expected the array to be too long, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn strings_are_limited_to_65535_bytes() {
+        let mut codec = NT2Codec::default();
+        let value = |len| EntryValue::String("a".repeat(len));
+        encode(&mut codec, assignment(1, value(65535))).unwrap();
+        match encode(&mut codec, assignment(2, value(65536))) {
+            Err(EncodeError::LengthLimitExceeded {
+                len: 65536,
+                limit: 65535,
+            }) => {}
+            res => panic!("expected the string to be too long, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn values_nt2_cant_represent_are_dropped() {
+        let value = EntryValue::RawData(vec![1, 2, 3]);
+        let buf = encode(&mut NT2Codec::default(), assignment(1, value)).unwrap();
+        assert!(buf.is_empty());
+    }
+}
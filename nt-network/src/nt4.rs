@@ -0,0 +1,425 @@
+//! Codec for NetworkTables 4 traffic
+//!
+//! NT4 is carried over websockets. Text frames hold a JSON array of control messages (see
+//! [`ControlMessage`](messages/enum.ControlMessage.html)), and binary frames hold one or more
+//! MessagePack arrays of the form `[topic id, timestamp, type, value]`.
+
+use crate::error::{DecodeError, EncodeError};
+use crate::types::{EntryType, EntryValue};
+use rmpv::Value;
+
+pub mod messages;
+
+pub use self::messages::*;
+
+/// The websocket subprotocol negotiated by NT4 clients and servers
+pub const NT4_SUBPROTOCOL: &str = "networktables.first.wpi.edu";
+
+/// The default port used by NT4 servers
+pub const NT4_PORT: u16 = 5810;
+
+/// The topic id used by value frames carrying round trip time measurements
+pub const RTT_TOPIC_ID: i64 = -1;
+
+/// A single value carried in an NT4 binary frame
+#[derive(Clone, Debug, PartialEq)]
+pub struct ValueFrame {
+    /// The topic id assigned by the server, or the publisher id if sent by a client
+    pub topic_id: i64,
+    /// The time the value was produced, in microseconds
+    pub timestamp: u64,
+    pub entry_value: EntryValue,
+}
+
+impl ValueFrame {
+    pub fn new(topic_id: i64, timestamp: u64, entry_value: EntryValue) -> ValueFrame {
+        ValueFrame {
+            topic_id,
+            timestamp,
+            entry_value,
+        }
+    }
+}
+
+/// A decoded NT4 websocket frame
+#[derive(Clone, Debug)]
+pub enum NT4Frame {
+    Control(Vec<ControlMessage>),
+    Values(Vec<ValueFrame>),
+}
+
+impl EntryType {
+    /// The type string used to describe topics of this type in NT4 control messages
+    ///
+    /// RPC definitions have no structured representation in NT4, and are announced as `rpc`
+    /// topics holding the NT3 encoding of the definition.
+    pub fn nt4_type_str(self) -> &'static str {
+        match self {
+            EntryType::Boolean => "boolean",
+            EntryType::Double => "double",
+            EntryType::Integer => "int",
+            EntryType::Float => "float",
+            EntryType::String => "string",
+            EntryType::RawData => "raw",
+            EntryType::RpcDefinition => "rpc",
+            EntryType::BooleanArray => "boolean[]",
+            EntryType::DoubleArray => "double[]",
+            EntryType::IntegerArray => "int[]",
+            EntryType::FloatArray => "float[]",
+            EntryType::StringArray => "string[]",
+        }
+    }
+
+    /// Parses an NT4 type string
+    ///
+    /// Types without a dedicated representation are mapped onto the value they are carried as,
+    /// `json` onto `String`, and `rpc`, `msgpack` and `protobuf` onto `RawData`.
+    pub fn from_nt4_type_str(s: &str) -> Option<EntryType> {
+        let ty = match s {
+            "boolean" => EntryType::Boolean,
+            "double" => EntryType::Double,
+            "int" => EntryType::Integer,
+            "float" => EntryType::Float,
+            "string" | "json" => EntryType::String,
+            "raw" | "rpc" | "msgpack" | "protobuf" => EntryType::RawData,
+            "boolean[]" => EntryType::BooleanArray,
+            "double[]" => EntryType::DoubleArray,
+            "int[]" => EntryType::IntegerArray,
+            "float[]" => EntryType::FloatArray,
+            "string[]" => EntryType::StringArray,
+            _ => return None,
+        };
+        Some(ty)
+    }
+
+    /// The numeric type id used to tag values of this type in NT4 binary frames
+    pub fn nt4_type_id(self) -> u8 {
+        match self {
+            EntryType::Boolean => 0,
+            EntryType::Double => 1,
+            EntryType::Integer => 2,
+            EntryType::Float => 3,
+            EntryType::String => 4,
+            EntryType::RawData | EntryType::RpcDefinition => 5,
+            EntryType::BooleanArray => 16,
+            EntryType::DoubleArray => 17,
+            EntryType::IntegerArray => 18,
+            EntryType::FloatArray => 19,
+            EntryType::StringArray => 20,
+        }
+    }
+
+    /// Parses an NT4 binary frame type id
+    pub fn from_nt4_type_id(id: u64) -> Option<EntryType> {
+        let ty = match id {
+            0 => EntryType::Boolean,
+            1 => EntryType::Double,
+            2 => EntryType::Integer,
+            3 => EntryType::Float,
+            4 => EntryType::String,
+            5 => EntryType::RawData,
+            16 => EntryType::BooleanArray,
+            17 => EntryType::DoubleArray,
+            18 => EntryType::IntegerArray,
+            19 => EntryType::FloatArray,
+            20 => EntryType::StringArray,
+            _ => return None,
+        };
+        Some(ty)
+    }
+}
+
+/// Decodes the contents of an NT4 text frame
+///
+/// Messages with a method that isn't recognized are skipped, as required by the spec.
+pub fn decode_text(text: &str) -> Result<Vec<ControlMessage>, DecodeError> {
+    let values: Vec<serde_json::Value> = serde_json::from_str(text).map_err(invalid)?;
+    let mut messages = Vec::with_capacity(values.len());
+
+    for value in values {
+        let known = match value.get("method").and_then(|m| m.as_str()) {
+            Some(method) => ControlMessage::METHODS.contains(&method),
+            None => return Err(invalid("Control message is missing a method")),
+        };
+        if known {
+            messages.push(serde_json::from_value(value).map_err(invalid)?);
+        }
+    }
+
+    Ok(messages)
+}
+
+/// Encodes control messages into the contents of an NT4 text frame
+pub fn encode_text(messages: &[ControlMessage]) -> Result<String, EncodeError> {
+    serde_json::to_string(messages).map_err(|e| EncodeError::InvalidMessage(e.to_string()))
+}
+
+/// Decodes the contents of an NT4 binary frame
+pub fn decode_binary(mut buf: &[u8]) -> Result<Vec<ValueFrame>, DecodeError> {
+    let mut frames = Vec::new();
+
+    while !buf.is_empty() {
+        let value = rmpv::decode::read_value(&mut buf).map_err(invalid)?;
+        frames.push(decode_value_frame(value)?);
+    }
+
+    Ok(frames)
+}
+
+/// Encodes values into the contents of an NT4 binary frame
+pub fn encode_binary(frames: &[ValueFrame]) -> Result<Vec<u8>, EncodeError> {
+    let mut buf = Vec::new();
+
+    for frame in frames {
+        let ty = frame.entry_value.entry_type();
+        let value = Value::Array(vec![
+            Value::from(frame.topic_id),
+            Value::from(frame.timestamp),
+            Value::from(ty.nt4_type_id()),
+            encode_value(&frame.entry_value)?,
+        ]);
+        rmpv::encode::write_value(&mut buf, &value)
+            .map_err(|e| EncodeError::InvalidMessage(e.to_string()))?;
+    }
+
+    Ok(buf)
+}
+
+fn decode_value_frame(value: Value) -> Result<ValueFrame, DecodeError> {
+    let mut fields = match value {
+        Value::Array(fields) if fields.len() == 4 => fields.into_iter(),
+        _ => return Err(invalid("Value frame must be an array of 4 elements")),
+    };
+
+    // Length was checked above
+    let topic_id = fields.next().unwrap();
+    let timestamp = fields.next().unwrap();
+    let ty = fields.next().unwrap();
+    let value = fields.next().unwrap();
+
+    let topic_id = topic_id
+        .as_i64()
+        .ok_or_else(|| invalid(format!("Invalid topic id {}", topic_id)))?;
+    let timestamp = timestamp
+        .as_u64()
+        .ok_or_else(|| invalid(format!("Invalid timestamp {}", timestamp)))?;
+    let ty = ty
+        .as_u64()
+        .and_then(EntryType::from_nt4_type_id)
+        .ok_or_else(|| invalid(format!("Invalid entry type {}", ty)))?;
+
+    Ok(ValueFrame::new(
+        topic_id,
+        timestamp,
+        decode_value(ty, value)?,
+    ))
+}
+
+fn encode_value(value: &EntryValue) -> Result<Value, EncodeError> {
+    let value = match value {
+        EntryValue::Boolean(b) => Value::from(*b),
+        EntryValue::Double(d) => Value::from(*d),
+        EntryValue::Integer(i) => Value::from(*i),
+        EntryValue::Float(f) => Value::from(*f),
+        EntryValue::String(s) => Value::from(s.as_str()),
+        EntryValue::RawData(v) => Value::from(v.as_slice()),
+        EntryValue::RpcDefinition(def) => {
+            let mut buf = bytes::BytesMut::new();
+            crate::Packet::serialize(def, &mut buf)?;
+            Value::from(&buf[..])
+        }
+        EntryValue::BooleanArray(v) => Value::Array(v.iter().map(|b| Value::from(*b)).collect()),
+        EntryValue::DoubleArray(v) => Value::Array(v.iter().map(|d| Value::from(*d)).collect()),
+        EntryValue::IntegerArray(v) => Value::Array(v.iter().map(|i| Value::from(*i)).collect()),
+        EntryValue::FloatArray(v) => Value::Array(v.iter().map(|f| Value::from(*f)).collect()),
+        EntryValue::StringArray(v) => {
+            Value::Array(v.iter().map(|s| Value::from(s.as_str())).collect())
+        }
+    };
+    Ok(value)
+}
+
+fn decode_value(ty: EntryType, value: Value) -> Result<EntryValue, DecodeError> {
+    let value = match ty {
+        EntryType::Boolean => EntryValue::Boolean(as_bool(&value)?),
+        EntryType::Double => EntryValue::Double(as_f64(&value)?),
+        EntryType::Integer => EntryValue::Integer(as_i64(&value)?),
+        EntryType::Float => EntryValue::Float(as_f64(&value)? as f32),
+        EntryType::String => EntryValue::String(as_string(value)?),
+        EntryType::RawData | EntryType::RpcDefinition => match value {
+            Value::Binary(v) => EntryValue::RawData(v),
+            value => return Err(invalid(format!("Expected binary data, got {}", value))),
+        },
+        EntryType::BooleanArray => EntryValue::BooleanArray(
+            as_array(value)?
+                .iter()
+                .map(as_bool)
+                .collect::<Result<_, _>>()?,
+        ),
+        EntryType::DoubleArray => EntryValue::DoubleArray(
+            as_array(value)?
+                .iter()
+                .map(as_f64)
+                .collect::<Result<_, _>>()?,
+        ),
+        EntryType::IntegerArray => EntryValue::IntegerArray(
+            as_array(value)?
+                .iter()
+                .map(as_i64)
+                .collect::<Result<_, _>>()?,
+        ),
+        EntryType::FloatArray => EntryValue::FloatArray(
+            as_array(value)?
+                .iter()
+                .map(|v| as_f64(v).map(|f| f as f32))
+                .collect::<Result<_, _>>()?,
+        ),
+        EntryType::StringArray => EntryValue::StringArray(
+            as_array(value)?
+                .into_iter()
+                .map(as_string)
+                .collect::<Result<_, _>>()?,
+        ),
+    };
+    Ok(value)
+}
+
+fn as_bool(value: &Value) -> Result<bool, DecodeError> {
+    value
+        .as_bool()
+        .ok_or_else(|| invalid(format!("Expected boolean, got {}", value)))
+}
+
+/// MessagePack encoders are free to pick the smallest representation, so doubles may arrive as
+/// floats or integers
+fn as_f64(value: &Value) -> Result<f64, DecodeError> {
+    match value {
+        Value::F32(f) => Ok(*f as f64),
+        Value::F64(d) => Ok(*d),
+        Value::Integer(i) => i
+            .as_f64()
+            .ok_or_else(|| invalid(format!("Expected number, got {}", value))),
+        _ => Err(invalid(format!("Expected number, got {}", value))),
+    }
+}
+
+fn as_i64(value: &Value) -> Result<i64, DecodeError> {
+    value
+        .as_i64()
+        .ok_or_else(|| invalid(format!("Expected integer, got {}", value)))
+}
+
+fn as_string(value: Value) -> Result<String, DecodeError> {
+    match value {
+        Value::String(s) => s
+            .into_str()
+            .ok_or_else(|| invalid("String is not valid UTF-8")),
+        value => Err(invalid(format!("Expected string, got {}", value))),
+    }
+}
+
+fn as_array(value: Value) -> Result<Vec<Value>, DecodeError> {
+    match value {
+        Value::Array(v) => Ok(v),
+        value => Err(invalid(format!("Expected array, got {}", value))),
+    }
+}
+
+/// Describes why an NT4 message is invalid
+fn invalid(reason: impl ToString) -> DecodeError {
+    DecodeError::InvalidMessage(reason.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn control_messages_round_trip() {
+        let mut properties = Properties::new();
+        properties.insert("persistent".to_string(), true.into());
+        let messages = vec![
+            ControlMessage::Publish(Publish {
+                name: "/SmartDashboard/kP".to_string(),
+                pubuid: 3,
+                type_str: "double".to_string(),
+                properties: properties.clone(),
+            }),
+            ControlMessage::Subscribe(Subscribe {
+                topics: vec!["/SmartDashboard/".to_string()],
+                subuid: 7,
+                options: SubscriptionOptions {
+                    prefix: true,
+                    ..Default::default()
+                },
+            }),
+            ControlMessage::Announce(Announce {
+                name: "/SmartDashboard/kP".to_string(),
+                id: 12,
+                type_str: "double".to_string(),
+                pubuid: Some(3),
+                properties,
+            }),
+            ControlMessage::Unannounce(Unannounce {
+                name: "/SmartDashboard/kP".to_string(),
+                id: 12,
+            }),
+        ];
+
+        let text = encode_text(&messages).unwrap();
+        assert_eq!(decode_text(&text).unwrap(), messages);
+    }
+
+    #[test]
+    fn unknown_methods_are_skipped() {
+        let text =
+            r#"[{"method":"bogus","params":{}},{"method":"unpublish","params":{"pubuid":1}}]"#;
+        assert_eq!(
+            decode_text(text).unwrap(),
+            vec![ControlMessage::Unpublish(Unpublish { pubuid: 1 })]
+        );
+    }
+
+    #[test]
+    fn value_frames_round_trip() {
+        let frames = vec![
+            ValueFrame::new(1, 100, EntryValue::Boolean(true)),
+            ValueFrame::new(2, 200, EntryValue::Double(0.5)),
+            ValueFrame::new(3, 300, EntryValue::Integer(-42)),
+            ValueFrame::new(4, 400, EntryValue::Float(1.5)),
+            ValueFrame::new(5, 500, EntryValue::String("hello".to_string())),
+            ValueFrame::new(6, 600, EntryValue::RawData(vec![0, 1, 2])),
+            ValueFrame::new(7, 700, EntryValue::BooleanArray(vec![true, false])),
+            ValueFrame::new(8, 800, EntryValue::DoubleArray(vec![1.0, 2.0])),
+            ValueFrame::new(9, 900, EntryValue::IntegerArray(vec![1, -1])),
+            ValueFrame::new(10, 1000, EntryValue::FloatArray(vec![0.25])),
+            ValueFrame::new(
+                11,
+                1100,
+                EntryValue::StringArray(vec!["a".to_string(), "b".to_string()]),
+            ),
+            ValueFrame::new(RTT_TOPIC_ID, 0, EntryValue::Integer(1234)),
+        ];
+
+        let bytes = encode_binary(&frames).unwrap();
+        assert_eq!(decode_binary(&bytes).unwrap(), frames);
+    }
+
+    #[test]
+    fn out_of_range_type_ids_are_rejected() {
+        // 256 would be read as boolean if the id were truncated to a byte
+        let value = Value::Array(vec![
+            Value::from(1),
+            Value::from(0),
+            Value::from(256),
+            Value::from(true),
+        ]);
+        let mut buf = Vec::new();
+        rmpv::encode::write_value(&mut buf, &value).unwrap();
+
+        match decode_binary(&buf) {
+            Err(DecodeError::InvalidMessage(_)) => {}
+            res => panic!("Expected an invalid message, got {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// A topic property map, as carried by `publish`, `announce`, `setproperties` and `properties`
+pub type Properties = Map<String, Value>;
+
+/// A control message carried in an NT4 text frame
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "method", content = "params", rename_all = "lowercase")]
+pub enum ControlMessage {
+    Publish(Publish),
+    Unpublish(Unpublish),
+    SetProperties(SetProperties),
+    Subscribe(Subscribe),
+    Unsubscribe(Unsubscribe),
+    Announce(Announce),
+    Unannounce(Unannounce),
+    Properties(PropertiesUpdate),
+}
+
+impl ControlMessage {
+    /// The method names of every message understood by this codec
+    pub const METHODS: [&'static str; 8] = [
+        "publish",
+        "unpublish",
+        "setproperties",
+        "subscribe",
+        "unsubscribe",
+        "announce",
+        "unannounce",
+        "properties",
+    ];
+}
+
+/// Sent by a client to start publishing to a topic
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Publish {
+    pub name: String,
+    pub pubuid: i32,
+    #[serde(rename = "type")]
+    pub type_str: String,
+    #[serde(default)]
+    pub properties: Properties,
+}
+
+/// Sent by a client to stop publishing to a topic
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Unpublish {
+    pub pubuid: i32,
+}
+
+/// Sent by a client to change the properties of a topic
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SetProperties {
+    pub name: String,
+    pub update: Properties,
+}
+
+/// Sent by a client to request announcements and values for a set of topics
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Subscribe {
+    pub topics: Vec<String>,
+    pub subuid: i32,
+    #[serde(default)]
+    pub options: SubscriptionOptions,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SubscriptionOptions {
+    /// How often, in seconds, the server should send changes to the subscriber
+    #[serde(default = "SubscriptionOptions::default_periodic")]
+    pub periodic: f64,
+    /// Whether every value change should be sent, rather than only the most recent one
+    #[serde(default)]
+    pub all: bool,
+    /// Whether only announcements should be sent, without any values
+    #[serde(default, rename = "topicsonly")]
+    pub topics_only: bool,
+    /// Whether `topics` should be treated as name prefixes rather than exact names
+    #[serde(default)]
+    pub prefix: bool,
+}
+
+impl SubscriptionOptions {
+    fn default_periodic() -> f64 {
+        0.1
+    }
+}
+
+impl Default for SubscriptionOptions {
+    fn default() -> Self {
+        SubscriptionOptions {
+            periodic: SubscriptionOptions::default_periodic(),
+            all: false,
+            topics_only: false,
+            prefix: false,
+        }
+    }
+}
+
+/// Sent by a client to cancel a subscription
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Unsubscribe {
+    pub subuid: i32,
+}
+
+/// Sent by the server when a topic is published, or when a client subscribes to it
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Announce {
+    pub name: String,
+    pub id: i32,
+    #[serde(rename = "type")]
+    pub type_str: String,
+    /// Set when the announcement is in response to a `publish` from the receiving client
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pubuid: Option<i32>,
+    #[serde(default)]
+    pub properties: Properties,
+}
+
+/// Sent by the server when a topic is removed
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Unannounce {
+    pub name: String,
+    pub id: i32,
+}
+
+/// Sent by the server when the properties of a topic change
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PropertiesUpdate {
+    pub name: String,
+    /// Set when the update is in response to a `setproperties` from the receiving client
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub ack: bool,
+    pub update: Properties,
+}
@@ -1,49 +1,64 @@
+use crate::codec::ReceivedPacket;
 use crate::ext::*;
 use crate::packets::types::{EntryType, EntryValue};
-use crate::{NTVersion, Result};
+use crate::{DecodeError, EncodeError, NTVersion};
 use bytes::{Buf, BufMut, BytesMut};
 
 pub mod types;
 
 pub trait Packet: Send + Sync {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()>;
-    fn deserialize(buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError>;
+    fn deserialize(buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized;
+
+    /// Returns a copy of this packet as a `ReceivedPacket`, if it is a top level packet.
+    ///
+    /// This allows boxed packets to be inspected and translated for connections that don't speak NT3.
+    fn to_received(&self) -> Option<ReceivedPacket> {
+        None
+    }
 }
 
 #[derive(Clone, Debug)]
 pub struct ClientHello {
     pub version: NTVersion,
+    /// The identity of the client. NT2 hellos have no name, so it is empty for `NTVersion::V2`.
     pub name: String,
 }
 
 impl ClientHello {
     pub fn new(version: NTVersion, name: String) -> ClientHello {
-        if version == NTVersion::V2 {
-            panic!("V2 is not supported");
-        }
-
         ClientHello { version, name }
     }
 }
 
 impl Packet for ClientHello {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
         buf.put_u8(0x01);
         buf.put_u16(self.version as u16);
-        self.name.serialize(buf)?; // cant use the method on buf because dum
+        if self.version == NTVersion::V3 {
+            self.name.serialize(buf)?; // cant use the method on buf because dum
+        }
         Ok(())
     }
 
-    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
         let version = NTVersion::from_u16(buf.read_u16_be()?)?;
+        if version == NTVersion::V2 {
+            return Ok((ClientHello::new(version, String::new()), 2));
+        }
+
         let (name, name_bytes) = String::deserialize(buf)?;
         Ok((ClientHello { version, name }, 2 + name_bytes))
     }
+
+    fn to_received(&self) -> Option<ReceivedPacket> {
+        Some(ReceivedPacket::ClientHello(self.clone()))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -59,14 +74,14 @@ impl ServerHello {
 }
 
 impl Packet for ServerHello {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
         buf.put_u8(0x04);
         buf.put_u8(self.flags);
         self.name.serialize(buf)?;
         Ok(())
     }
 
-    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
@@ -74,6 +89,10 @@ impl Packet for ServerHello {
         let (name, bytes) = String::deserialize(buf)?;
         Ok((ServerHello::new(flags, name), 1 + bytes))
     }
+
+    fn to_received(&self) -> Option<ReceivedPacket> {
+        Some(ReceivedPacket::ServerHello(self.clone()))
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -107,7 +126,7 @@ impl EntryAssignment {
 }
 
 impl Packet for EntryAssignment {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
         buf.put_u8(0x10);
         self.entry_name.serialize(buf)?;
         self.entry_type.serialize(buf)?;
@@ -118,7 +137,7 @@ impl Packet for EntryAssignment {
         Ok(())
     }
 
-    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
@@ -135,56 +154,72 @@ impl Packet for EntryAssignment {
             5 + read,
         ))
     }
+
+    fn to_received(&self) -> Option<ReceivedPacket> {
+        Some(ReceivedPacket::EntryAssignment(self.clone()))
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
 pub struct ClientHelloComplete;
 
 impl Packet for ClientHelloComplete {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
         buf.put_u8(0x05);
         Ok(())
     }
 
-    fn deserialize(_buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(_buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
         Ok((ClientHelloComplete, 0))
     }
+
+    fn to_received(&self) -> Option<ReceivedPacket> {
+        Some(ReceivedPacket::ClientHelloComplete)
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
 pub struct ServerHelloComplete;
 
 impl Packet for ServerHelloComplete {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
         buf.put_u8(0x03);
         Ok(())
     }
 
-    fn deserialize(_buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(_buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
         Ok((ServerHelloComplete, 0))
     }
+
+    fn to_received(&self) -> Option<ReceivedPacket> {
+        Some(ReceivedPacket::ServerHelloComplete)
+    }
 }
 
 pub struct KeepAlive;
 
 impl Packet for KeepAlive {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
         buf.put_u8(0x00);
         Ok(())
     }
 
-    fn deserialize(_buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(_buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
         Ok((KeepAlive, 0))
     }
+
+    fn to_received(&self) -> Option<ReceivedPacket> {
+        Some(ReceivedPacket::KeepAlive)
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -201,19 +236,23 @@ impl ProtocolVersionUnsupported {
 }
 
 impl Packet for ProtocolVersionUnsupported {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
         buf.put_u8(0x02);
         buf.put_u16(self.supported_version);
         Ok(())
     }
 
-    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
         let supported_version = buf.read_u16_be()?;
         Ok((ProtocolVersionUnsupported { supported_version }, 2))
     }
+
+    fn to_received(&self) -> Option<ReceivedPacket> {
+        Some(ReceivedPacket::ProtocolVersionUnsupported(self.clone()))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -241,7 +280,7 @@ impl EntryUpdate {
 }
 
 impl Packet for EntryUpdate {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
         buf.put_u8(0x11);
         buf.put_u16(self.entry_id);
         buf.put_u16(self.entry_seqnum);
@@ -250,7 +289,7 @@ impl Packet for EntryUpdate {
         Ok(())
     }
 
-    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
@@ -264,6 +303,10 @@ impl Packet for EntryUpdate {
             2 + 2 + type_bytes + value_bytes,
         ))
     }
+
+    fn to_received(&self) -> Option<ReceivedPacket> {
+        Some(ReceivedPacket::EntryUpdate(self.clone()))
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -282,19 +325,19 @@ impl EntryFlagsUpdate {
 }
 
 impl Packet for EntryFlagsUpdate {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
         buf.put_u8(0x12);
         buf.put_u16(self.entry_id);
         buf.put_u8(self.entry_flags);
         Ok(())
     }
 
-    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
         let entry_id = buf.read_u16_be()?;
-        let entry_flags = buf.get_u8();
+        let entry_flags = buf.read_u8()?;
         Ok((
             EntryFlagsUpdate {
                 entry_id,
@@ -303,6 +346,10 @@ impl Packet for EntryFlagsUpdate {
             3,
         ))
     }
+
+    fn to_received(&self) -> Option<ReceivedPacket> {
+        Some(ReceivedPacket::EntryFlagsUpdate(*self))
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -317,19 +364,23 @@ impl EntryDelete {
 }
 
 impl Packet for EntryDelete {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
         buf.put_u8(0x13);
         buf.put_u16(self.entry_id);
         Ok(())
     }
 
-    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
         let entry_id = buf.read_u16_be()?;
         Ok((EntryDelete { entry_id }, 2))
     }
+
+    fn to_received(&self) -> Option<ReceivedPacket> {
+        Some(ReceivedPacket::EntryDelete(*self))
+    }
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -349,19 +400,33 @@ impl ClearAllEntries {
     }
 }
 
+impl Default for ClearAllEntries {
+    fn default() -> Self {
+        ClearAllEntries::new()
+    }
+}
+
 impl Packet for ClearAllEntries {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
         buf.put_u8(0x14);
         buf.put_u32(self.magic);
         Ok(())
     }
 
-    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
         let magic = buf.read_u32_be()?;
-        Ok((ClearAllEntries { magic }, 4))
+        let packet = ClearAllEntries { magic };
+        if !packet.is_valid() {
+            return Err(DecodeError::BadClearAllMagic(magic));
+        }
+        Ok((packet, 4))
+    }
+
+    fn to_received(&self) -> Option<ReceivedPacket> {
+        Some(ReceivedPacket::ClearAllEntries(*self))
     }
 }
 
@@ -383,14 +448,14 @@ impl RpcExecute {
 }
 
 impl Packet for RpcExecute {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
         buf.put_u8(0x20);
         buf.put_u16(self.entry_id);
         buf.put_u16(self.unique_id);
         Packet::serialize(&self.parameter, buf)
     }
 
-    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
@@ -406,6 +471,10 @@ impl Packet for RpcExecute {
             4 + len,
         ))
     }
+
+    fn to_received(&self) -> Option<ReceivedPacket> {
+        Some(ReceivedPacket::RpcExecute(self.clone()))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -426,14 +495,14 @@ impl RpcResponse {
 }
 
 impl Packet for RpcResponse {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
         buf.put_u8(0x21);
         buf.put_u16(self.entry_id);
         buf.put_u16(self.unique_id);
         Packet::serialize(&self.result, buf)
     }
 
-    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
@@ -449,4 +518,8 @@ impl Packet for RpcResponse {
             4 + len,
         ))
     }
+
+    fn to_received(&self) -> Option<ReceivedPacket> {
+        Some(ReceivedPacket::RpcResponse(self.clone()))
+    }
 }
@@ -1,99 +1,97 @@
+use crate::error::{DecodeError, EncodeError, MAX_LENGTH};
 use crate::ext::BufExt;
 use crate::packets::Packet;
-use crate::Result;
 use bytes::{Buf, BufMut, BytesMut};
-use anyhow::anyhow;
-use thiserror::Error;
 use nt_leb128::*;
 #[cfg(feature = "wasm-bindgen")]
 use wasm_bindgen::prelude::*;
 
+mod rpc;
+mod value;
+
+pub use self::rpc::*;
+pub use self::value::*;
+
 impl Packet for String {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
-        buf.write_unsigned(self.len() as u64).unwrap();
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
+        buf.write_unsigned(self.len() as u64)?;
         buf.extend_from_slice(self.as_bytes());
         Ok(())
     }
 
-    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
-        let (len, read) = {
-            let (len, read) = buf.read_unsigned()?;
-            (len as usize, read)
-        };
+        let (len, read) = read_length(buf)?;
         if buf.remaining() < len {
-            return Err(std::io::Error::new(
-                std::io::ErrorKind::UnexpectedEof,
-                "String aint there",
-            )
-            .into());
+            return Err(DecodeError::Incomplete);
         }
         let mut this = vec![0u8; len];
         buf.copy_to_slice(&mut this[..]);
 
-        Ok((String::from_utf8(this).unwrap(), read + len))
+        Ok((String::from_utf8(this)?, read + len))
     }
 }
 
 impl Packet for u8 {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
         buf.put_u8(*self);
         Ok(())
     }
 
-    fn deserialize(buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
-        Ok((buf.get_u8(), 1))
+        Ok((buf.read_u8()?, 1))
     }
 }
 
 impl Packet for bool {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
         buf.put_u8(if *self { 1 } else { 0 });
         Ok(())
     }
 
-    fn deserialize(buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
-        Ok((buf.get_u8() == 1, 1))
+        Ok((buf.read_u8()? == 1, 1))
     }
 }
 
 impl Packet for f64 {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
         buf.put_f64(*self);
         Ok(())
     }
 
-    fn deserialize(buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
-        Ok((buf.get_f64(), 8))
+        Ok((buf.read_f64_be()?, 8))
     }
 }
 
 impl<T: Packet> Packet for Vec<T> {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
         buf.write_unsigned(self.len() as u64)?;
-        self.iter().for_each(|value| {
-            value.serialize(buf).unwrap();
-        });
+        for value in self {
+            value.serialize(buf)?;
+        }
         Ok(())
     }
 
-    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
-        let (len, mut read) = buf.read_unsigned()?;
-        let mut v = Vec::with_capacity(len as usize);
+        let (len, mut read) = read_length(buf)?;
+        // Every element takes at least a byte, so a length the buffer can't hold is never allocated
+        let mut v = Vec::with_capacity(len.min(buf.remaining()));
 
         for _ in 0..len {
             let (value, b) = T::deserialize(buf)?;
@@ -105,44 +103,25 @@ impl<T: Packet> Packet for Vec<T> {
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
-pub enum RpcDefinition {
-    V0,
-}
-
-#[derive(Debug, Error)]
-pub enum RpcError {
-    #[error("Invalid RPC Definition: {version}")]
-    InvalidVersion { version: u8 },
-}
-
-impl Packet for RpcDefinition {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
-        match *self {
-            RpcDefinition::V0 => {
-                buf.write_unsigned(1)?;
-                buf.put_u8(0);
-            }
-        }
-        Ok(())
-    }
-
-    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize)>
-    where
-        Self: Sized,
-    {
-        let (len, read) = buf.read_unsigned()?;
-        let ver = buf.get_u8();
-        if len == 1 && ver == 0 {
-            Ok((RpcDefinition::V0, len as usize + read))
-        } else {
-            Err(RpcError::InvalidVersion { version: ver }.into())
-        }
+/// Reads a LEB128 length prefix, returning it along with the number of bytes it took up
+pub(crate) fn read_length(mut buf: &mut dyn Buf) -> Result<(usize, usize), DecodeError> {
+    let (len, read) = buf.read_unsigned()?;
+    if len > MAX_LENGTH {
+        return Err(DecodeError::LengthLimitExceeded {
+            len,
+            limit: MAX_LENGTH,
+        });
     }
+    Ok((len as usize, read))
 }
 
+/// The type of an entry.
+///
+/// `Integer`, `Float`, `IntegerArray` and `FloatArray` only exist in NetworkTables 4. When one of
+/// these is sent over an NT3 connection it is widened to `Double` or `DoubleArray` on the wire.
 #[cfg_attr(feature = "wasm-bindgen", wasm_bindgen)]
 #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum EntryType {
     Boolean,
     Double,
@@ -152,9 +131,14 @@ pub enum EntryType {
     DoubleArray,
     StringArray,
     RpcDefinition,
+    Integer,
+    Float,
+    IntegerArray,
+    FloatArray,
 }
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum EntryValue {
     Boolean(bool),
     Double(f64),
@@ -164,6 +148,10 @@ pub enum EntryValue {
     DoubleArray(Vec<f64>),
     StringArray(Vec<String>),
     RpcDefinition(RpcDefinition),
+    Integer(i64),
+    Float(f32),
+    IntegerArray(Vec<i64>),
+    FloatArray(Vec<f32>),
 }
 
 impl EntryValue {
@@ -177,12 +165,32 @@ impl EntryValue {
             EntryValue::DoubleArray(_) => EntryType::DoubleArray,
             EntryValue::StringArray(_) => EntryType::StringArray,
             EntryValue::RpcDefinition(_) => EntryType::RpcDefinition,
+            EntryValue::Integer(_) => EntryType::Integer,
+            EntryValue::Float(_) => EntryType::Float,
+            EntryValue::IntegerArray(_) => EntryType::IntegerArray,
+            EntryValue::FloatArray(_) => EntryType::FloatArray,
+        }
+    }
+
+    /// Converts a value received over NT3 back to `ty`, the type of the entry it is for. Doubles
+    /// are narrowed to the NT4 only type they were widened from, other values are kept as they are.
+    pub fn narrow_to(self, ty: EntryType) -> EntryValue {
+        match (self, ty) {
+            (EntryValue::Double(d), EntryType::Integer) => EntryValue::Integer(d as i64),
+            (EntryValue::Double(d), EntryType::Float) => EntryValue::Float(d as f32),
+            (EntryValue::DoubleArray(v), EntryType::IntegerArray) => {
+                EntryValue::IntegerArray(v.into_iter().map(|d| d as i64).collect())
+            }
+            (EntryValue::DoubleArray(v), EntryType::FloatArray) => {
+                EntryValue::FloatArray(v.into_iter().map(|d| d as f32).collect())
+            }
+            (value, _) => value,
         }
     }
 }
 
 impl Packet for EntryType {
-    fn serialize(&self, buf: &mut BytesMut) -> Result<()> {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
         match *self {
             EntryType::Boolean => buf.put_u8(0x00),
             EntryType::Double => buf.put_u8(0x01),
@@ -192,11 +200,13 @@ impl Packet for EntryType {
             EntryType::DoubleArray => buf.put_u8(0x11),
             EntryType::StringArray => buf.put_u8(0x12),
             EntryType::RpcDefinition => buf.put_u8(0x20),
+            EntryType::Integer | EntryType::Float => buf.put_u8(0x01),
+            EntryType::IntegerArray | EntryType::FloatArray => buf.put_u8(0x11),
         }
         Ok(())
     }
 
-    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize)>
+    fn deserialize(mut buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
     where
         Self: Sized,
     {
@@ -210,7 +220,7 @@ impl Packet for EntryType {
             0x11 => EntryType::DoubleArray,
             0x12 => EntryType::StringArray,
             0x20 => EntryType::RpcDefinition,
-            _ => return Err(anyhow!("Invalid entry type")),
+            ty => return Err(DecodeError::InvalidEntryType(ty)),
         };
 
         Ok((entry, 1))
@@ -218,7 +228,16 @@ impl Packet for EntryType {
 }
 
 impl EntryType {
-    pub fn write_value(self, value: &EntryValue, buf: &mut BytesMut) -> Result<()> {
+    /// The type values of this type have on the NT3 wire, where NT4 only types are widened
+    pub fn nt3_type(self) -> EntryType {
+        match self {
+            EntryType::Integer | EntryType::Float => EntryType::Double,
+            EntryType::IntegerArray | EntryType::FloatArray => EntryType::DoubleArray,
+            ty => ty,
+        }
+    }
+
+    pub fn write_value(self, value: &EntryValue, buf: &mut BytesMut) -> Result<(), EncodeError> {
         match value {
             EntryValue::Boolean(ref b) => b.serialize(buf)?,
             EntryValue::Double(ref d) => d.serialize(buf)?,
@@ -228,11 +247,23 @@ impl EntryType {
             EntryValue::DoubleArray(ref v) => v.serialize(buf)?,
             EntryValue::StringArray(ref v) => v.serialize(buf)?,
             EntryValue::RpcDefinition(ref v) => v.serialize(buf)?,
+            EntryValue::Integer(i) => (*i as f64).serialize(buf)?,
+            EntryValue::Float(f) => (*f as f64).serialize(buf)?,
+            EntryValue::IntegerArray(ref v) => v
+                .iter()
+                .map(|i| *i as f64)
+                .collect::<Vec<f64>>()
+                .serialize(buf)?,
+            EntryValue::FloatArray(ref v) => v
+                .iter()
+                .map(|f| *f as f64)
+                .collect::<Vec<f64>>()
+                .serialize(buf)?,
         }
         Ok(())
     }
 
-    pub fn read_value(self, mut buf: &mut dyn Buf) -> Result<(EntryValue, usize)> {
+    pub fn read_value(self, mut buf: &mut dyn Buf) -> Result<(EntryValue, usize), DecodeError> {
         let mut read = 0;
 
         let value = match self {
@@ -274,6 +305,25 @@ impl EntryType {
                 read += len;
                 EntryValue::RpcDefinition(v)
             }
+            // NT3 has no integer or float types, these are widened to doubles on the wire
+            EntryType::Integer => {
+                read += 8;
+                EntryValue::Integer(buf.read_f64_be()? as i64)
+            }
+            EntryType::Float => {
+                read += 8;
+                EntryValue::Float(buf.read_f64_be()? as f32)
+            }
+            EntryType::IntegerArray => {
+                let (v, len) = Vec::<f64>::deserialize(buf)?;
+                read += len;
+                EntryValue::IntegerArray(v.into_iter().map(|d| d as i64).collect())
+            }
+            EntryType::FloatArray => {
+                let (v, len) = Vec::<f64>::deserialize(buf)?;
+                read += len;
+                EntryValue::FloatArray(v.into_iter().map(|d| d as f32).collect())
+            }
         };
         Ok((value, read))
     }
@@ -0,0 +1,375 @@
+use crate::error::{DecodeError, EncodeError};
+use crate::ext::BufExt;
+use crate::packets::types::{read_length, EntryType, EntryValue};
+use crate::packets::Packet;
+use bytes::{Buf, BufMut, BytesMut};
+use nt_leb128::*;
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum RpcDefinition {
+    V0,
+    V1(RpcDefinitionV1),
+}
+
+/// A version 1 RPC definition, describing the parameters and results of a procedure
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RpcDefinitionV1 {
+    pub name: String,
+    pub params: Vec<RpcParameter>,
+    pub results: Vec<RpcResult>,
+}
+
+/// A parameter of a version 1 RPC. The type of the parameter is the type of its default value.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RpcParameter {
+    pub name: String,
+    pub default: EntryValue,
+}
+
+/// A result of a version 1 RPC
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RpcResult {
+    pub entry_type: EntryType,
+    pub name: String,
+}
+
+#[derive(Debug, Error)]
+pub enum RpcError {
+    #[error("Invalid RPC Definition: {version}")]
+    InvalidVersion { version: u8 },
+    #[error("Expected {expected} values, got {found}")]
+    WrongValueCount { expected: usize, found: usize },
+    #[error("Expected {expected:?} for {name}, got {found:?}")]
+    TypeMismatch {
+        name: String,
+        expected: EntryType,
+        found: EntryType,
+    },
+}
+
+impl RpcParameter {
+    pub fn new(name: String, default: EntryValue) -> RpcParameter {
+        RpcParameter { name, default }
+    }
+
+    pub fn entry_type(&self) -> EntryType {
+        self.default.entry_type()
+    }
+}
+
+impl RpcResult {
+    pub fn new(entry_type: EntryType, name: String) -> RpcResult {
+        RpcResult { entry_type, name }
+    }
+}
+
+impl RpcDefinitionV1 {
+    pub fn new(name: String, params: Vec<RpcParameter>, results: Vec<RpcResult>) -> Self {
+        RpcDefinitionV1 {
+            name,
+            params,
+            results,
+        }
+    }
+
+    /// Encodes the arguments of a call, for use as `RpcExecute::parameter`.
+    ///
+    /// Trailing parameters that aren't given are sent with their default value.
+    pub fn encode_parameters(&self, values: &[EntryValue]) -> Result<Vec<u8>, EncodeError> {
+        if values.len() > self.params.len() {
+            return Err(RpcError::WrongValueCount {
+                expected: self.params.len(),
+                found: values.len(),
+            }
+            .into());
+        }
+
+        let mut buf = BytesMut::new();
+        for (i, param) in self.params.iter().enumerate() {
+            let value = values.get(i).unwrap_or(&param.default);
+            write_checked(&param.name, param.entry_type(), value, &mut buf)?;
+        }
+        Ok(buf.to_vec())
+    }
+
+    /// Decodes the arguments of a call from `RpcExecute::parameter`, failing if bytes are left over
+    pub fn decode_parameters(&self, mut bytes: &[u8]) -> Result<Vec<EntryValue>, DecodeError> {
+        let values = self
+            .params
+            .iter()
+            .map(|param| Ok(param.entry_type().read_value(&mut bytes)?.0))
+            .collect::<Result<_, DecodeError>>()?;
+        expect_end(bytes)?;
+        Ok(values)
+    }
+
+    /// Encodes the results of a call, for use as `RpcResponse::result`
+    pub fn encode_results(&self, values: &[EntryValue]) -> Result<Vec<u8>, EncodeError> {
+        if values.len() != self.results.len() {
+            return Err(RpcError::WrongValueCount {
+                expected: self.results.len(),
+                found: values.len(),
+            }
+            .into());
+        }
+
+        let mut buf = BytesMut::new();
+        for (result, value) in self.results.iter().zip(values) {
+            write_checked(&result.name, result.entry_type, value, &mut buf)?;
+        }
+        Ok(buf.to_vec())
+    }
+
+    /// Decodes the results of a call from `RpcResponse::result`, failing if bytes are left over
+    pub fn decode_results(&self, mut bytes: &[u8]) -> Result<Vec<EntryValue>, DecodeError> {
+        let values = self
+            .results
+            .iter()
+            .map(|result| Ok(result.entry_type.read_value(&mut bytes)?.0))
+            .collect::<Result<_, DecodeError>>()?;
+        expect_end(bytes)?;
+        Ok(values)
+    }
+
+    fn deserialize_body(mut buf: &mut dyn Buf) -> Result<RpcDefinitionV1, DecodeError> {
+        let (name, _) = String::deserialize(buf)?;
+
+        let param_count = buf.read_u8()?;
+        let mut params = Vec::new();
+        for _ in 0..param_count {
+            let (ty, _) = EntryType::deserialize(buf)?;
+            let (name, _) = String::deserialize(buf)?;
+            let (default, _) = ty.read_value(buf)?;
+            params.push(RpcParameter::new(name, default));
+        }
+
+        let result_count = buf.read_u8()?;
+        let mut results = Vec::new();
+        for _ in 0..result_count {
+            let (ty, _) = EntryType::deserialize(buf)?;
+            let (name, _) = String::deserialize(buf)?;
+            results.push(RpcResult::new(ty, name));
+        }
+
+        Ok(RpcDefinitionV1::new(name, params, results))
+    }
+
+    fn serialize_body(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
+        buf.put_u8(0x01);
+        self.name.serialize(buf)?;
+
+        buf.put_u8(count(self.params.len())?);
+        for param in &self.params {
+            param.entry_type().serialize(buf)?;
+            param.name.serialize(buf)?;
+            param.entry_type().write_value(&param.default, buf)?;
+        }
+
+        buf.put_u8(count(self.results.len())?);
+        for result in &self.results {
+            result.entry_type.serialize(buf)?;
+            result.name.serialize(buf)?;
+        }
+        Ok(())
+    }
+}
+
+fn write_checked(
+    name: &str,
+    ty: EntryType,
+    value: &EntryValue,
+    buf: &mut BytesMut,
+) -> Result<(), EncodeError> {
+    if value.entry_type() != ty {
+        return Err(RpcError::TypeMismatch {
+            name: name.to_string(),
+            expected: ty,
+            found: value.entry_type(),
+        }
+        .into());
+    }
+    ty.write_value(value, buf)
+}
+
+/// Fails if anything is left over after the last declared value
+fn expect_end(bytes: &[u8]) -> Result<(), DecodeError> {
+    if bytes.is_empty() {
+        Ok(())
+    } else {
+        Err(DecodeError::TrailingBytes(bytes.len()))
+    }
+}
+
+/// Checks that a number of parameters or results fits in the byte that holds it
+fn count(len: usize) -> Result<u8, EncodeError> {
+    if len > u8::MAX as usize {
+        return Err(EncodeError::LengthLimitExceeded {
+            len: len as u64,
+            limit: u8::MAX as u64,
+        });
+    }
+    Ok(len as u8)
+}
+
+impl Packet for RpcDefinition {
+    fn serialize(&self, buf: &mut BytesMut) -> Result<(), EncodeError> {
+        match *self {
+            RpcDefinition::V0 => {
+                buf.write_unsigned(1)?;
+                buf.put_u8(0);
+            }
+            RpcDefinition::V1(ref def) => {
+                let mut body = BytesMut::new();
+                def.serialize_body(&mut body)?;
+                buf.write_unsigned(body.len() as u64)?;
+                buf.extend_from_slice(&body[..]);
+            }
+        }
+        Ok(())
+    }
+
+    fn deserialize(buf: &mut dyn Buf) -> Result<(Self, usize), DecodeError>
+    where
+        Self: Sized,
+    {
+        let (len, read) = read_length(buf)?;
+        if buf.remaining() < len {
+            return Err(DecodeError::Incomplete);
+        }
+
+        // The definition is length prefixed, so it is parsed out of its own buffer
+        let mut body = vec![0u8; len];
+        buf.copy_to_slice(&mut body[..]);
+        let mut body = &body[..];
+
+        // The whole definition has been read, so running out of bytes means it is malformed
+        // rather than incomplete
+        let malformed = |e: DecodeError| DecodeError::InvalidRpcDefinition(e.to_string());
+        let def = match body.read_u8().map_err(malformed)? {
+            0 if len == 1 => RpcDefinition::V0,
+            1 => {
+                RpcDefinition::V1(RpcDefinitionV1::deserialize_body(&mut body).map_err(malformed)?)
+            }
+            version => {
+                return Err(DecodeError::InvalidRpcDefinition(
+                    RpcError::InvalidVersion { version }.to_string(),
+                ))
+            }
+        };
+        Ok((def, len + read))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sum() -> RpcDefinitionV1 {
+        RpcDefinitionV1::new(
+            "sum".to_string(),
+            vec![
+                RpcParameter::new("a".to_string(), EntryValue::Double(0.0)),
+                RpcParameter::new("b".to_string(), EntryValue::Double(1.0)),
+            ],
+            vec![RpcResult::new(EntryType::Double, "sum".to_string())],
+        )
+    }
+
+    #[test]
+    fn definition_round_trips() {
+        let def = RpcDefinition::V1(sum());
+        let mut buf = BytesMut::new();
+        def.serialize(&mut buf).unwrap();
+        let len = buf.len();
+
+        let (decoded, read) = RpcDefinition::deserialize(&mut &buf[..]).unwrap();
+        assert_eq!(decoded, def);
+        assert_eq!(read, len);
+    }
+
+    #[test]
+    fn parameters_round_trip() {
+        let def = sum();
+        let values = [EntryValue::Double(2.0), EntryValue::Double(3.0)];
+        let bytes = def.encode_parameters(&values).unwrap();
+        assert_eq!(def.decode_parameters(&bytes).unwrap(), values);
+    }
+
+    #[test]
+    fn missing_parameters_take_their_default() {
+        let def = sum();
+        let bytes = def.encode_parameters(&[EntryValue::Double(2.0)]).unwrap();
+        assert_eq!(
+            def.decode_parameters(&bytes).unwrap(),
+            vec![EntryValue::Double(2.0), EntryValue::Double(1.0)]
+        );
+    }
+
+    #[test]
+    fn results_round_trip() {
+        let def = sum();
+        let values = [EntryValue::Double(5.0)];
+        let bytes = def.encode_results(&values).unwrap();
+        assert_eq!(def.decode_results(&bytes).unwrap(), values);
+    }
+
+    #[test]
+    fn wrong_value_counts_are_rejected() {
+        let def = sum();
+        let too_many = [
+            EntryValue::Double(1.0),
+            EntryValue::Double(2.0),
+            EntryValue::Double(3.0),
+        ];
+        match def.encode_parameters(&too_many) {
+            Err(EncodeError::Rpc(RpcError::WrongValueCount {
+                expected: 2,
+                found: 3,
+            })) => {}
+            res => panic!("Expected WrongValueCount, got {:?}", res),
+        }
+        match def.encode_results(&[]) {
+            Err(EncodeError::Rpc(RpcError::WrongValueCount {
+                expected: 1,
+                found: 0,
+            })) => {}
+            res => panic!("Expected WrongValueCount, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn mistyped_values_are_rejected() {
+        let def = sum();
+        match def.encode_parameters(&[EntryValue::Boolean(true)]) {
+            Err(EncodeError::Rpc(RpcError::TypeMismatch {
+                expected: EntryType::Double,
+                found: EntryType::Boolean,
+                ..
+            })) => {}
+            res => panic!("Expected TypeMismatch, got {:?}", res),
+        }
+    }
+
+    #[test]
+    fn trailing_bytes_are_rejected() {
+        let def = sum();
+        let mut bytes = def.encode_results(&[EntryValue::Double(5.0)]).unwrap();
+        bytes.push(0);
+        match def.decode_results(&bytes) {
+            Err(DecodeError::TrailingBytes(1)) => {}
+            res => panic!("Expected TrailingBytes, got {:?}", res),
+        }
+
+        let mut bytes = def.encode_parameters(&[]).unwrap();
+        bytes.extend_from_slice(&[1, 2]);
+        match def.decode_parameters(&bytes) {
+            Err(DecodeError::TrailingBytes(2)) => {}
+            res => panic!("Expected TrailingBytes, got {:?}", res),
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use crate::packets::types::{EntryType, EntryValue};
+use std::convert::TryFrom;
+use thiserror::Error;
+
+/// The error returned when converting an `EntryValue` into a Rust type that doesn't match it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+#[error("Expected a value of type {expected:?}, got {found:?}")]
+pub struct TypeMismatch {
+    pub expected: EntryType,
+    pub found: EntryType,
+}
+
+/// A Rust type that corresponds to exactly one `EntryType`
+pub trait EntryValueType: Into<EntryValue> + TryFrom<EntryValue, Error = TypeMismatch> {
+    const ENTRY_TYPE: EntryType;
+}
+
+macro_rules! value_type {
+    ($ty:ty, $variant:ident) => {
+        impl From<$ty> for EntryValue {
+            fn from(value: $ty) -> Self {
+                EntryValue::$variant(value)
+            }
+        }
+
+        impl TryFrom<EntryValue> for $ty {
+            type Error = TypeMismatch;
+
+            fn try_from(value: EntryValue) -> Result<Self, TypeMismatch> {
+                match value {
+                    EntryValue::$variant(value) => Ok(value),
+                    value => Err(TypeMismatch {
+                        expected: EntryType::$variant,
+                        found: value.entry_type(),
+                    }),
+                }
+            }
+        }
+
+        impl EntryValueType for $ty {
+            const ENTRY_TYPE: EntryType = EntryType::$variant;
+        }
+    };
+}
+
+value_type!(bool, Boolean);
+value_type!(f64, Double);
+value_type!(String, String);
+value_type!(Vec<u8>, RawData);
+value_type!(Vec<bool>, BooleanArray);
+value_type!(Vec<f64>, DoubleArray);
+value_type!(Vec<String>, StringArray);
+value_type!(i64, Integer);
+value_type!(f32, Float);
+value_type!(Vec<i64>, IntegerArray);
+value_type!(Vec<f32>, FloatArray);
+
+impl From<&str> for EntryValue {
+    fn from(value: &str) -> Self {
+        EntryValue::String(value.to_string())
+    }
+}
@@ -0,0 +1,78 @@
+/// An entry sequence number, which wraps around after 65535
+///
+/// As defined in the NT3 spec, a sequence number is newer than another if it is less than 32768
+/// ahead of it, wrapping around. Numbers exactly 32768 apart are neither newer than the other.
+/// This isn't a total order, so sequence numbers are compared with `is_newer_than` rather than
+/// with the comparison operators.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SequenceNumber(pub u16);
+
+impl SequenceNumber {
+    pub fn new(seqnum: u16) -> SequenceNumber {
+        SequenceNumber(seqnum)
+    }
+
+    /// The sequence number following this one
+    pub fn next(self) -> SequenceNumber {
+        SequenceNumber(self.0.wrapping_add(1))
+    }
+
+    /// Whether this sequence number is newer than `other`
+    pub fn is_newer_than(self, other: SequenceNumber) -> bool {
+        let diff = self.0.wrapping_sub(other.0);
+        diff != 0 && diff < 32768
+    }
+}
+
+impl From<u16> for SequenceNumber {
+    fn from(seqnum: u16) -> Self {
+        SequenceNumber(seqnum)
+    }
+}
+
+impl From<SequenceNumber> for u16 {
+    fn from(seqnum: SequenceNumber) -> Self {
+        seqnum.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SequenceNumber;
+
+    fn newer(a: u16, b: u16) -> bool {
+        SequenceNumber(a).is_newer_than(SequenceNumber(b))
+    }
+
+    #[test]
+    fn equal_numbers_are_not_newer() {
+        assert!(!newer(0, 0));
+        assert!(!newer(32768, 32768));
+        assert!(!newer(65535, 65535));
+    }
+
+    #[test]
+    fn numbers_ahead_are_newer() {
+        assert!(newer(1, 0));
+        assert!(!newer(0, 1));
+        assert!(newer(32767, 0));
+        assert!(!newer(0, 32767));
+    }
+
+    #[test]
+    fn numbers_wrap_around() {
+        assert!(newer(0, 65535));
+        assert!(!newer(65535, 0));
+        assert!(newer(32766, 65535));
+        assert!(newer(SequenceNumber(65535).next().0, 65535));
+    }
+
+    #[test]
+    fn numbers_half_way_apart_are_unordered() {
+        assert!(!newer(32768, 0));
+        assert!(!newer(0, 32768));
+        assert!(!newer(65535, 32767));
+        assert!(!newer(32767, 65535));
+    }
+}
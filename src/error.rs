@@ -1,4 +1,5 @@
-use nt_network::NTVersion;
+use nt_network::types::EntryType;
+use nt_network::{DecodeError, EncodeError, NTVersion};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
@@ -7,17 +8,39 @@ pub enum Error {
     ConnectionAborted,
     #[error("Connected closed unexpectedly.")]
     BrokenPipe,
+    #[error("Not connected to the remote end.")]
+    Disconnected,
+    #[error("The server closed the connection.")]
+    ConnectionClosed,
     #[error("Server does not support the desired protocol version. Supported version: {supported_version:?}")]
     UnsupportedProtocolVersion { supported_version: NTVersion },
+    #[error("Timed out waiting for the server.")]
+    Timeout,
+    #[error("No entry with id {id} exists.")]
+    EntryNotFound { id: u16 },
+    #[error("Expected a value of type {expected:?}, got {found:?}")]
+    TypeMismatch {
+        expected: EntryType,
+        found: EntryType,
+    },
+    #[error("Invalid URL {url}: {reason}")]
+    InvalidUrl { url: String, reason: String },
+    #[error("RPC failed: {reason}")]
+    RpcFailed { reason: String },
+    #[error("{what} is over the limit of {limit}")]
+    LimitExceeded { what: &'static str, limit: usize },
+    #[error("Failed to decode a packet: {0}")]
+    Decode(#[from] DecodeError),
+    #[error("Failed to encode a packet: {0}")]
+    Encode(#[from] EncodeError),
     #[error(transparent)]
     IO(#[from] std::io::Error),
+    #[cfg(feature = "websocket")]
+    #[error("Websocket error: {0}")]
+    WebSocket(#[from] tokio_tungstenite::tungstenite::error::Error),
+    #[cfg(feature = "serde")]
+    #[error("Failed to convert between a struct and entries: {0}")]
+    Serde(#[from] crate::StructError),
     #[error(transparent)]
     Other(#[from] anyhow::Error),
 }
-
-#[cfg(feature = "websocket")]
-impl From<tokio_tungstenite::tungstenite::error::Error> for Error {
-    fn from(err: tokio_tungstenite::tungstenite::error::Error) -> Self {
-        Error::Other(err.into())
-    }
-}
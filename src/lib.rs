@@ -10,13 +10,24 @@ extern crate tokio;
 
 pub mod error;
 mod nt;
+pub mod persist;
 mod proto;
 
 /// Base result type for nt-rs
 pub type Result<T> = std::result::Result<T, error::Error>;
 
+pub use self::nt::blocking;
 pub use self::nt::callback::*;
-pub use self::nt::entry::EntryData;
+pub use self::nt::connection::{ConnectionInfo, Protocol};
+pub use self::nt::entry::{Entry, EntryData, TypedEntry, PERSISTENT_FLAG};
+pub use self::nt::subscription::*;
+pub use self::nt::table::NetworkTable;
+#[cfg(feature = "serde")]
+pub use self::nt::table_serde::StructError;
 pub use self::nt::NetworkTables;
+pub use self::proto::client::supervisor::Backoff;
+pub use self::proto::server::limits::Limits;
 pub use self::proto::{Client, NTBackend, Server, State};
+pub use nt_network::nt4::Properties;
 pub use nt_network::types::*;
+pub use nt_network::SequenceNumber;
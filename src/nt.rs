@@ -1,22 +1,39 @@
+pub mod blocking;
 pub mod callback;
+pub mod connection;
 pub mod entry;
+pub mod subscription;
+pub mod table;
+#[cfg(feature = "serde")]
+pub mod table_serde;
 
+use crate::error::Error;
 use crate::Result;
 
 pub use self::entry::*;
 use crate::nt::callback::*;
+use crate::nt::connection::ConnectionInfo;
+use crate::nt::subscription::{ConnectionEvents, Subscription};
+use crate::nt::table::NetworkTable;
+use crate::proto::client::supervisor::Backoff;
+use crate::proto::client::{self, ClientState};
+use crate::proto::server::limits::Limits;
 use crate::proto::server::ServerState;
-use crate::proto::{client::ClientState, Client, NTBackend, Server, State};
-use futures_channel::mpsc::{channel, unbounded, Sender};
+use crate::proto::{
+    spawn_thread, spawn_thread_on, Client, ConnectionTask, NTBackend, Server, State,
+};
+use futures_channel::mpsc::{channel, Receiver, Sender, UnboundedReceiver, UnboundedSender};
 use futures_util::StreamExt;
-use nt_network::types::EntryValue;
+use nt_network::types::{EntryValue, EntryValueType};
 use nt_network::Packet;
 use std::collections::HashMap;
+use std::future::Future;
 use std::net::SocketAddr;
 use std::panic::RefUnwindSafe;
+use std::path::{Path, PathBuf};
 use std::sync::{Arc, Mutex};
-use std::thread;
-use tokio::runtime::Runtime;
+use tokio::runtime::{Handle, Runtime};
+use tokio::task::JoinHandle;
 
 /// Core struct representing a connection to a NetworkTables server
 pub struct NetworkTables<T: NTBackend> {
@@ -29,39 +46,108 @@ impl NetworkTables<Client> {
     ///
     /// This call will block the thread until the client has completed the handshake with the server,
     /// at which point the connection will be valid to send and receive data over
+    ///
+    /// The connection runs on a thread of its own. Use `connect_on` to run it on an existing runtime.
     pub async fn connect(ip: &str, client_name: &str) -> Result<NetworkTables<Client>> {
+        let rt = Runtime::new()?;
         let (close_tx, close_rx) = channel::<()>(1);
-        let state = ClientState::new(ip.to_string(), client_name.to_string(), close_rx).await?;
+        let (state, _) =
+            ClientState::new(ip.to_string(), client_name.to_string(), close_rx, |task| {
+                spawn_thread_on(rt, task)
+            })
+            .await?;
         Ok(NetworkTables { state, close_tx })
     }
 
+    /// Like `connect`, but runs the connection on the runtime of `handle`, such as
+    /// `Handle::current()`. The returned `JoinHandle` completes once the connection has closed.
+    pub async fn connect_on(
+        handle: &Handle,
+        ip: &str,
+        client_name: &str,
+    ) -> Result<(NetworkTables<Client>, JoinHandle<Result<()>>)> {
+        let (close_tx, close_rx) = channel::<()>(1);
+        let (state, task) =
+            ClientState::new(ip.to_string(), client_name.to_string(), close_rx, |task| {
+                handle.spawn(task)
+            })
+            .await?;
+        Ok((NetworkTables { state, close_tx }, task))
+    }
+
     /// Attempts to reconnect to the NetworkTables server if the connection had been terminated.
     ///
+    /// Entries are kept across the reconnect. Any the server no longer has are created again, and
+    /// values updated while disconnected are sent to the server once the handshake completes.
+    ///
     /// This function should _only_ be called if you are certain that the previous connection is dead.
     /// Connection status can be determined using callbacks specified with `add_connection_callback`.
+    /// Clients created with `connect_supervised` reconnect on their own.
     pub async fn reconnect(&mut self) {
-        let rt_state = Arc::clone(&self.state);
+        // The connection thread logs how the new connection ends
+        drop(
+            self.restart(
+                ClientState::reset_connection,
+                spawn_thread,
+                client::conn::connection,
+            )
+            .await,
+        );
+    }
 
-        let (close_tx, close_rx) = channel::<()>(1);
-        let (packet_tx, packet_rx) = unbounded::<Box<dyn Packet>>();
-        let (ready_tx, mut ready_rx) = unbounded();
+    /// Like `reconnect`, but runs the new connection on the runtime of `handle`
+    pub async fn reconnect_on(&mut self, handle: &Handle) -> JoinHandle<Result<()>> {
+        self.restart(
+            ClientState::reset_connection,
+            |task| handle.spawn(task),
+            client::conn::connection,
+        )
+        .await
+    }
 
-        self.close_tx = close_tx;
-        {
-            let mut state = self.state.lock().unwrap();
-            state.packet_tx = packet_tx;
-            state.entries_mut().clear();
-            state.pending_entries.clear();
-        }
-        thread::spawn(move || {
-            let mut rt = Runtime::new().unwrap();
-            rt.block_on(crate::proto::client::conn::connection(
-                rt_state, packet_rx, ready_tx, close_rx,
-            ))
-            .unwrap();
-        });
+    /// Connects over TCP to the given ip like `connect`, but keeps the client connected, reconnecting
+    /// after `backoff` whenever the connection is lost or an attempt fails.
+    ///
+    /// This returns right away, before the first connection has been made. Until then, entries can
+    /// be set with `set_default` but not created with `create_entry`. Use `connection_events` to
+    /// follow the connection.
+    pub fn connect_supervised(
+        ip: &str,
+        client_name: &str,
+        backoff: Backoff,
+    ) -> NetworkTables<Client> {
+        let (close_tx, close_rx) = channel::<()>(1);
+        let (state, _) = ClientState::supervised(
+            ip.to_string(),
+            client_name.to_string(),
+            backoff,
+            close_rx,
+            ClientState::reset_connection,
+            spawn_thread,
+            client::conn::connection,
+        );
+        NetworkTables { state, close_tx }
+    }
 
-        let _ = ready_rx.next().await;
+    /// Like `connect_supervised`, but runs the supervisor on the runtime of `handle`. The returned
+    /// `JoinHandle` completes once the client is dropped.
+    pub fn connect_supervised_on(
+        handle: &Handle,
+        ip: &str,
+        client_name: &str,
+        backoff: Backoff,
+    ) -> (NetworkTables<Client>, JoinHandle<Result<()>>) {
+        let (close_tx, close_rx) = channel::<()>(1);
+        let (state, task) = ClientState::supervised(
+            ip.to_string(),
+            client_name.to_string(),
+            backoff,
+            close_rx,
+            ClientState::reset_connection,
+            |task| handle.spawn(task),
+            client::conn::connection,
+        );
+        (NetworkTables { state, close_tx }, task)
     }
 
     /// Connects over websockets to the given ip, with the given client name
@@ -70,40 +156,240 @@ impl NetworkTables<Client> {
     /// at which point the connection will be valid to send and receive data over
     #[cfg(feature = "websocket")]
     pub async fn connect_ws(ip: &str, client_name: &str) -> Result<NetworkTables<Client>> {
+        let rt = Runtime::new()?;
         let (close_tx, close_rx) = channel::<()>(1);
-        let state = ClientState::new_ws(ip.to_string(), client_name.to_string(), close_rx).await?;
+        let (state, _) =
+            ClientState::new_ws(ip.to_string(), client_name.to_string(), close_rx, |task| {
+                spawn_thread_on(rt, task)
+            })
+            .await?;
 
         Ok(NetworkTables { state, close_tx })
     }
 
+    /// Like `connect_ws`, but runs the connection on the runtime of `handle`
+    #[cfg(feature = "websocket")]
+    pub async fn connect_ws_on(
+        handle: &Handle,
+        ip: &str,
+        client_name: &str,
+    ) -> Result<(NetworkTables<Client>, JoinHandle<Result<()>>)> {
+        let (close_tx, close_rx) = channel::<()>(1);
+        let (state, task) =
+            ClientState::new_ws(ip.to_string(), client_name.to_string(), close_rx, |task| {
+                handle.spawn(task)
+            })
+            .await?;
+        Ok((NetworkTables { state, close_tx }, task))
+    }
+
     /// Attempts to reconnect over websockets to the NetworkTables instance.
     ///
+    /// Entries are kept and merged with the server's as in `reconnect`.
+    ///
     /// This function should _only_ be called if you are certain that the previous connection is dead.
     /// Connection status can be determined using callbacks specified with `add_connection_callback`.
     #[cfg(feature = "websocket")]
     pub async fn reconnect_ws(&mut self) {
-        let rt_state = Arc::clone(&self.state);
+        // The connection thread logs how the new connection ends
+        drop(
+            self.restart(
+                ClientState::reset_connection,
+                spawn_thread,
+                client::conn::connection_ws,
+            )
+            .await,
+        );
+    }
+
+    /// Like `reconnect_ws`, but runs the new connection on the runtime of `handle`
+    #[cfg(feature = "websocket")]
+    pub async fn reconnect_ws_on(&mut self, handle: &Handle) -> JoinHandle<Result<()>> {
+        self.restart(
+            ClientState::reset_connection,
+            |task| handle.spawn(task),
+            client::conn::connection_ws,
+        )
+        .await
+    }
 
+    /// Connects over websockets like `connect_ws`, but keeps the client connected as in
+    /// `connect_supervised`
+    #[cfg(feature = "websocket")]
+    pub fn connect_ws_supervised(
+        ip: &str,
+        client_name: &str,
+        backoff: Backoff,
+    ) -> NetworkTables<Client> {
         let (close_tx, close_rx) = channel::<()>(1);
-        let (packet_tx, packet_rx) = unbounded::<Box<dyn Packet>>();
-        let (ready_tx, mut ready_rx) = unbounded();
+        let (state, _) = ClientState::supervised(
+            ip.to_string(),
+            client_name.to_string(),
+            backoff,
+            close_rx,
+            ClientState::reset_connection,
+            spawn_thread,
+            client::conn::connection_ws,
+        );
+        NetworkTables { state, close_tx }
+    }
 
-        self.close_tx = close_tx;
-        {
-            let mut state = self.state.lock().unwrap();
-            state.packet_tx = packet_tx;
-            state.entries_mut().clear();
-            state.pending_entries.clear();
-        }
-        thread::spawn(move || {
-            let mut rt = Runtime::new().unwrap();
-            rt.block_on(crate::proto::client::conn::connection_ws(
-                rt_state, packet_rx, ready_tx, close_rx,
-            ))
-            .unwrap();
-        });
+    /// Like `connect_ws_supervised`, but runs the supervisor on the runtime of `handle`
+    #[cfg(feature = "websocket")]
+    pub fn connect_ws_supervised_on(
+        handle: &Handle,
+        ip: &str,
+        client_name: &str,
+        backoff: Backoff,
+    ) -> (NetworkTables<Client>, JoinHandle<Result<()>>) {
+        let (close_tx, close_rx) = channel::<()>(1);
+        let (state, task) = ClientState::supervised(
+            ip.to_string(),
+            client_name.to_string(),
+            backoff,
+            close_rx,
+            ClientState::reset_connection,
+            |task| handle.spawn(task),
+            client::conn::connection_ws,
+        );
+        (NetworkTables { state, close_tx }, task)
+    }
 
-        let _ = ready_rx.next().await;
+    /// Connects over websockets to the NT4 server on the given host, with the given client name
+    ///
+    /// The host should not include a port, NT4 servers are always reached on port 5810. If the server
+    /// rejects the upgrade to NT4, refuses connections on port 5810 or doesn't answer the upgrade
+    /// within 5 seconds, this falls back to connecting over NT3 on port 1735. Other failures, such as
+    /// the host being unreachable, are returned without falling back.
+    ///
+    /// The connection is ready once the server has answered the first clock synchronization ping, so
+    /// `server_time` is available as soon as this returns.
+    ///
+    /// Topics announced by the server are presented as entries, with entry ids allocated by the client.
+    /// NT4 has no RPCs, so `call_rpc` is not supported on connections that negotiated NT4.
+    #[cfg(feature = "websocket")]
+    pub async fn connect_nt4(host: &str, client_name: &str) -> Result<NetworkTables<Client>> {
+        let rt = Runtime::new()?;
+        let (close_tx, close_rx) = channel::<()>(1);
+        let (state, _) = ClientState::new_nt4(
+            host.to_string(),
+            client_name.to_string(),
+            close_rx,
+            |task| spawn_thread_on(rt, task),
+        )
+        .await?;
+
+        Ok(NetworkTables { state, close_tx })
+    }
+
+    /// Like `connect_nt4`, but runs the connection on the runtime of `handle`
+    #[cfg(feature = "websocket")]
+    pub async fn connect_nt4_on(
+        handle: &Handle,
+        host: &str,
+        client_name: &str,
+    ) -> Result<(NetworkTables<Client>, JoinHandle<Result<()>>)> {
+        let (close_tx, close_rx) = channel::<()>(1);
+        let (state, task) = ClientState::new_nt4(
+            host.to_string(),
+            client_name.to_string(),
+            close_rx,
+            |task| handle.spawn(task),
+        )
+        .await?;
+        Ok((NetworkTables { state, close_tx }, task))
+    }
+
+    /// Attempts to reconnect to the NT4 server, falling back to NT3 as in `connect_nt4`.
+    ///
+    /// This function should _only_ be called if you are certain that the previous connection is dead.
+    /// Connection status can be determined using callbacks specified with `add_connection_callback`.
+    #[cfg(feature = "websocket")]
+    pub async fn reconnect_nt4(&mut self) {
+        // The connection thread logs how the new connection ends
+        drop(
+            self.restart(
+                ClientState::reset_nt4_connection,
+                spawn_thread,
+                client::nt4::connection,
+            )
+            .await,
+        );
+    }
+
+    /// Like `reconnect_nt4`, but runs the new connection on the runtime of `handle`
+    #[cfg(feature = "websocket")]
+    pub async fn reconnect_nt4_on(&mut self, handle: &Handle) -> JoinHandle<Result<()>> {
+        self.restart(
+            ClientState::reset_nt4_connection,
+            |task| handle.spawn(task),
+            client::nt4::connection,
+        )
+        .await
+    }
+
+    /// Connects to the NT4 server on the given host like `connect_nt4`, but keeps the client
+    /// connected as in `connect_supervised`.
+    ///
+    /// Entries this client published are published again on every new connection, unless the
+    /// server already has them.
+    #[cfg(feature = "websocket")]
+    pub fn connect_nt4_supervised(
+        host: &str,
+        client_name: &str,
+        backoff: Backoff,
+    ) -> NetworkTables<Client> {
+        let (close_tx, close_rx) = channel::<()>(1);
+        let (state, _) = ClientState::supervised(
+            host.to_string(),
+            client_name.to_string(),
+            backoff,
+            close_rx,
+            ClientState::reset_nt4_connection,
+            spawn_thread,
+            client::nt4::connection,
+        );
+        NetworkTables { state, close_tx }
+    }
+
+    /// Like `connect_nt4_supervised`, but runs the supervisor on the runtime of `handle`
+    #[cfg(feature = "websocket")]
+    pub fn connect_nt4_supervised_on(
+        handle: &Handle,
+        host: &str,
+        client_name: &str,
+        backoff: Backoff,
+    ) -> (NetworkTables<Client>, JoinHandle<Result<()>>) {
+        let (close_tx, close_rx) = channel::<()>(1);
+        let (state, task) = ClientState::supervised(
+            host.to_string(),
+            client_name.to_string(),
+            backoff,
+            close_rx,
+            ClientState::reset_nt4_connection,
+            |task| handle.spawn(task),
+            client::nt4::connection,
+        );
+        (NetworkTables { state, close_tx }, task)
+    }
+
+    async fn restart<R, F>(
+        &mut self,
+        reset: fn(&mut ClientState, UnboundedSender<Box<dyn Packet>>),
+        spawn: impl FnOnce(ConnectionTask) -> R,
+        connection: impl FnOnce(
+            Arc<Mutex<ClientState>>,
+            UnboundedReceiver<Box<dyn Packet>>,
+            UnboundedSender<Result<()>>,
+            Receiver<()>,
+        ) -> F,
+    ) -> R
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        let (close_tx, close_rx) = channel::<()>(1);
+        self.close_tx = close_tx;
+        ClientState::restart(&self.state, close_rx, reset, spawn, connection).await
     }
 
     pub fn add_connection_callback(
@@ -117,22 +403,135 @@ impl NetworkTables<Client> {
             .add_connection_callback(callback_type, action);
     }
 
+    /// Returns whether the handshake with the server has completed, and the connection hasn't since
+    /// been lost
+    pub fn is_connected(&self) -> bool {
+        self.state.lock().unwrap().connected
+    }
+
+    /// Returns information about the server, or `None` while not connected
+    pub fn connection_info(&self) -> Option<ConnectionInfo> {
+        self.state.lock().unwrap().connection_info().cloned()
+    }
+
+    /// Returns a `Stream` of the changes to the connection, such as it being lost or reestablished
+    pub fn connection_events(&self) -> ConnectionEvents {
+        self.state.lock().unwrap().connection_events()
+    }
+
+    /// Returns the current server time in microseconds.
+    ///
+    /// This is only available once an NT4 connection has synchronized its clock with the server,
+    /// NT3 has no notion of server time.
+    pub fn server_time(&self) -> Option<u64> {
+        self.state.lock().unwrap().server_time()
+    }
+
+    /// Calls the RPC with the given id, passing its result to `callback` once the server returns it.
+    ///
+    /// Fails if the entry isn't an RPC definition, or the connection doesn't support RPCs.
     pub fn call_rpc(
         &self,
         id: u16,
         parameter: Vec<u8>,
         callback: impl Fn(Vec<u8>) + Send + 'static,
-    ) {
-        self.state.lock().unwrap().call_rpc(id, parameter, callback);
+    ) -> Result<()> {
+        self.state.lock().unwrap().call_rpc(id, parameter, callback)
     }
 }
 
 impl NetworkTables<Server> {
     /// Initializes an NT server over TCP and binds it to the given ip, with the given server name.
-    pub fn bind(ip: &str, server_name: &str) -> NetworkTables<Server> {
+    ///
+    /// This completes once the server is listening, failing if it can't bind to `ip`.
+    ///
+    /// With the `websocket` feature enabled, the server also accepts NT4 clients. These share the
+    /// same entries as NT3 clients, with entry ids used as NT4 topic ids. As standard NT4 clients
+    /// connect to port 5810, the server also listens on that port of the host in `ip`, and fails to
+    /// bind if it can't.
+    ///
+    /// Persistent entries are kept in memory only, use `bind_with_persist_file` to store them.
+    pub async fn bind(ip: &str, server_name: &str) -> Result<NetworkTables<Server>> {
+        Self::bind_with_persist_path(ip, server_name, None).await
+    }
+
+    /// Like `bind`, but with persistent entries loaded from the file at `path` in the format used by
+    /// WPILib, and saved there whenever they change
+    pub async fn bind_with_persist_file(
+        ip: &str,
+        server_name: &str,
+        path: impl AsRef<Path>,
+    ) -> Result<NetworkTables<Server>> {
+        let path = path.as_ref().to_path_buf();
+        Self::bind_with_persist_path(ip, server_name, Some(path)).await
+    }
+
+    async fn bind_with_persist_path(
+        ip: &str,
+        server_name: &str,
+        persist_path: Option<PathBuf>,
+    ) -> Result<NetworkTables<Server>> {
+        let rt = Runtime::new()?;
         let (close_tx, close_rx) = channel::<()>(1);
-        let state = ServerState::new(ip.to_string(), server_name.to_string(), close_rx);
-        NetworkTables { state, close_tx }
+        let (state, _) = ServerState::new(
+            ip.to_string(),
+            server_name.to_string(),
+            persist_path,
+            close_rx,
+            |task| spawn_thread_on(rt, task),
+        )
+        .await?;
+        Ok(NetworkTables { state, close_tx })
+    }
+
+    /// Like `bind`, but runs the server on the runtime of `handle`, such as `Handle::current()`.
+    /// The returned `JoinHandle` completes once the server has stopped.
+    pub async fn bind_on(
+        handle: &Handle,
+        ip: &str,
+        server_name: &str,
+    ) -> Result<(NetworkTables<Server>, JoinHandle<Result<()>>)> {
+        Self::bind_with_persist_path_on(handle, ip, server_name, None).await
+    }
+
+    /// Like `bind_with_persist_file`, but runs the server on the runtime of `handle`
+    pub async fn bind_with_persist_file_on(
+        handle: &Handle,
+        ip: &str,
+        server_name: &str,
+        path: impl AsRef<Path>,
+    ) -> Result<(NetworkTables<Server>, JoinHandle<Result<()>>)> {
+        let path = path.as_ref().to_path_buf();
+        Self::bind_with_persist_path_on(handle, ip, server_name, Some(path)).await
+    }
+
+    async fn bind_with_persist_path_on(
+        handle: &Handle,
+        ip: &str,
+        server_name: &str,
+        persist_path: Option<PathBuf>,
+    ) -> Result<(NetworkTables<Server>, JoinHandle<Result<()>>)> {
+        let (close_tx, close_rx) = channel::<()>(1);
+        let (state, task) = ServerState::new(
+            ip.to_string(),
+            server_name.to_string(),
+            persist_path,
+            close_rx,
+            |task| handle.spawn(task),
+        )
+        .await?;
+        Ok((NetworkTables { state, close_tx }, task))
+    }
+
+    /// Stops the server, completing once it has stopped.
+    ///
+    /// The server stops accepting connections, and sends each client whatever is still queued for
+    /// it before closing the connection. Unsaved persistent entries are then saved. Dropping the
+    /// server stops it the same way, without waiting.
+    pub async fn shutdown(mut self) {
+        let stopped = self.state.lock().unwrap().stopped();
+        let _ = self.close_tx.try_send(());
+        stopped.await;
     }
 
     /// Adds a callback for connection state updates regarding clients.
@@ -150,12 +549,42 @@ impl NetworkTables<Server> {
             .add_server_callback(callback_type, action);
     }
 
+    /// Returns a `Stream` of clients connecting to and disconnecting from the server, along with
+    /// the reason each one disconnected
+    pub fn connection_events(&self) -> ConnectionEvents {
+        self.state.lock().unwrap().connection_events()
+    }
+
+    /// Returns the limits placed on what clients can send
+    pub fn limits(&self) -> Limits {
+        self.state.lock().unwrap().limits()
+    }
+
+    /// Sets the limits placed on what clients can send. Clients that are already connected keep
+    /// the limits they connected with, except for the entry limit.
+    pub fn set_limits(&self, limits: Limits) {
+        self.state.lock().unwrap().set_limits(limits);
+    }
+
+    /// Returns information about each client that has completed its handshake with the server
+    pub fn connected_clients(&self) -> Vec<ConnectionInfo> {
+        self.state.lock().unwrap().connected_clients()
+    }
+
+    /// Returns the current server time in microseconds, the time base used by entry timestamps
+    pub fn server_time(&self) -> u64 {
+        self.state.lock().unwrap().server_time()
+    }
+
+    /// Creates an RPC entry whose calls are answered by `callback`, returning its id.
+    ///
+    /// Fails if `data` doesn't hold an RPC definition, or an entry of another type has the same name.
     pub fn create_rpc(
         &mut self,
         data: EntryData,
         callback: impl Fn(Vec<u8>) -> Vec<u8> + Send + Sync + RefUnwindSafe + 'static,
-    ) {
-        self.state.lock().unwrap().create_rpc(data, callback);
+    ) -> Result<u16> {
+        self.state.lock().unwrap().create_rpc(data, callback)
     }
 }
 
@@ -166,30 +595,195 @@ impl<T: NTBackend> NetworkTables<T> {
     }
 
     /// Gets the entry with the given id, returning an `Entry` for the specified data
-    pub fn get_entry(&self, id: u16) -> Entry<T> {
+    pub fn get_entry(&self, id: u16) -> Entry<'_, T> {
         Entry::new(self, id)
     }
 
+    /// Gets the entry with the given name, if it exists
+    pub fn get_entry_by_name(&self, name: &str) -> Option<Entry<'_, T>> {
+        let id = *self.state.lock().unwrap().entry_ids().get(name)?;
+        Some(Entry::new(self, id))
+    }
+
+    /// Gets the entry with the given name, creating it with the value `default` if it doesn't exist
+    pub async fn get_or_create_entry(
+        &self,
+        name: &str,
+        default: EntryValue,
+    ) -> crate::Result<Entry<'_, T>> {
+        let id = self
+            .create_entry(EntryData::new(name.to_string(), 0, default))
+            .await?;
+        Ok(Entry::new(self, id))
+    }
+
+    /// Creates the entry with the value `default`, unless the server already has an entry with the
+    /// given name, whose value is then left untouched.
+    ///
+    /// This is safe to call while a client is still connecting, the entry is only created once the
+    /// handshake has told it which entries the server has. The returned entry may hold a value of
+    /// a type other than that of `default`.
+    pub async fn set_default(
+        &self,
+        name: &str,
+        default: EntryValue,
+    ) -> crate::Result<Entry<'_, T>> {
+        let mut rx =
+            self.state
+                .lock()
+                .unwrap()
+                .set_default(EntryData::new(name.to_string(), 0, default))?;
+        let id = rx.next().await.ok_or(Error::BrokenPipe)?;
+        Ok(Entry::new(self, id))
+    }
+
+    /// Like `set_default`, but returns a `TypedEntry` for the given type.
+    ///
+    /// Fails if the server already has an entry with the given name, and it holds another type.
+    pub async fn set_default_typed<V: EntryValueType>(
+        &self,
+        name: &str,
+        default: V,
+    ) -> crate::Result<TypedEntry<'_, T, V>> {
+        let id = *self.set_default(name, default.into()).await?.id();
+        let found = self
+            .state
+            .lock()
+            .unwrap()
+            .entries()
+            .get(&id)
+            .map(EntryData::entry_type);
+        match found {
+            Some(expected) if expected != V::ENTRY_TYPE => Err(Error::TypeMismatch {
+                expected,
+                found: V::ENTRY_TYPE,
+            }),
+            _ => Ok(TypedEntry::new(self, id)),
+        }
+    }
+
+    /// Gets the table at the given path, such as `/SmartDashboard`. The root table has an empty path.
+    pub fn get_table(&self, path: &str) -> NetworkTable<'_, T> {
+        NetworkTable::new(self, path)
+    }
+
+    /// Gets the entry with the given id, returning a `TypedEntry` that reads and writes values of type `V`
+    pub fn get_typed_entry<V: EntryValueType>(&self, id: u16) -> TypedEntry<'_, T, V> {
+        TypedEntry::new(self, id)
+    }
+
+    /// Publishes the fields of `value` as entries of the table at `prefix`, with nested structs
+    /// stored in sub-tables.
+    ///
+    /// Missing entries are created and existing ones updated. The whole struct is serialized and
+    /// checked against the existing entries before any of them change. If an existing entry holds a
+    /// value of another type nothing is published, and a `TypeMismatch` is returned.
+    #[cfg(feature = "serde")]
+    pub async fn publish_struct<S: serde::Serialize + ?Sized>(
+        &self,
+        prefix: &str,
+        value: &S,
+    ) -> crate::Result<()> {
+        let path = self.get_table(prefix).path().to_string();
+        let values = table_serde::to_entries(&path, value)?;
+
+        let mut created = Vec::new();
+        {
+            let mut state = self.state.lock().unwrap();
+            let mut updates = Vec::new();
+            let mut creates = Vec::new();
+            for (name, value) in values {
+                let existing = state
+                    .entry_ids()
+                    .get(&name)
+                    .and_then(|id| Some((*id, state.entries().get(id)?)));
+                match existing {
+                    Some((id, entry)) => {
+                        let expected = entry.entry_type();
+                        // An empty `Vec` can't tell which array type it is, so it takes that of the entry
+                        let value = if value == EntryValue::DoubleArray(Vec::new()) {
+                            table_serde::empty_array(expected).unwrap_or(value)
+                        } else {
+                            value
+                        };
+                        if value.entry_type() != expected {
+                            return Err(Error::TypeMismatch {
+                                expected,
+                                found: value.entry_type(),
+                            });
+                        }
+                        if entry.value != value {
+                            updates.push((id, value));
+                        }
+                    }
+                    None => creates.push(EntryData::new(name, 0, value)),
+                }
+            }
+
+            // Once the values are checked only creating entries can fail, such as when a client is
+            // disconnected, so entries are created before any are updated
+            for data in creates {
+                created.push(state.create_entry(data)?);
+            }
+            for (id, value) in updates {
+                state.update_entry(id, value)?;
+            }
+        }
+
+        for mut rx in created {
+            rx.next().await.ok_or(Error::BrokenPipe)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a `D` from the entries of the table at `prefix`, with nested structs read from sub-tables.
+    ///
+    /// Fields without an entry are treated as missing, so `Option` and `#[serde(default)]` fields may be left out.
+    #[cfg(feature = "serde")]
+    pub fn read_struct<D: serde::de::DeserializeOwned>(&self, prefix: &str) -> crate::Result<D> {
+        let path = self.get_table(prefix).path().to_string();
+        let entries = {
+            let children = format!("{}/", path);
+            let state = self.state.lock().unwrap();
+            let entries = state.entries();
+            state
+                .entry_ids()
+                .range(children.clone()..)
+                .take_while(|(name, _)| name.starts_with(&children))
+                .filter_map(|(name, id)| Some((name.clone(), entries.get(id)?.value.clone())))
+                .collect()
+        };
+        Ok(table_serde::from_entries(&path, &entries)?)
+    }
+
     /// Creates a new entry with the specified data, returning the id assigned to it by the server
     /// This call may block if this connection is acting as a client, while it waits for the id to be assigned by the remote server
+    ///
+    /// If an entry with the same name already exists, its id is returned and its value is left unchanged.
     pub async fn create_entry(&self, data: EntryData) -> crate::Result<u16> {
         let mut rx = self.state.lock().unwrap().create_entry(data)?;
-        Ok(rx.next().await.unwrap())
+        rx.next().await.ok_or(Error::BrokenPipe)
     }
 
     /// Deletes the entry with the given id
-    pub fn delete_entry(&self, id: u16) {
-        self.state.lock().unwrap().delete_entry(id);
+    ///
+    /// Fails with `EntryNotFound` if there is no such entry, or `Disconnected` if a client has lost
+    /// its connection.
+    pub fn delete_entry(&self, id: u16) -> Result<()> {
+        self.state.lock().unwrap().delete_entry(id)
     }
 
     /// Clears all the entries associated with this connection
-    pub fn clear_entries(&self) {
-        self.state.lock().unwrap().clear_entries();
+    pub fn clear_entries(&self) -> Result<()> {
+        self.state.lock().unwrap().clear_entries()
     }
 
-    /// Updates the entry of the given id, with the new value
-    pub fn update_entry(&self, id: u16, new_value: EntryValue) {
-        self.state.lock().unwrap().update_entry(id, new_value);
+    /// Updates the entry of the given id, with the new value. Fails with `TypeMismatch` if the value
+    /// isn't of the entry's type.
+    ///
+    /// Clients keep updates made while disconnected, and send them once the connection is reestablished.
+    pub fn update_entry(&self, id: u16, new_value: EntryValue) -> Result<()> {
+        self.state.lock().unwrap().update_entry(id, new_value)
     }
 
     /// Adds an entry callback of the given type.
@@ -204,9 +798,20 @@ impl<T: NTBackend> NetworkTables<T> {
         state.add_callback(action, cb);
     }
 
+    /// Returns a `Stream` of every change made to the entries, whether it was made locally or by a
+    /// remote connection
+    pub fn subscribe(&self) -> Subscription {
+        self.subscribe_prefix("")
+    }
+
+    /// Returns a `Stream` of the changes made to entries whose name starts with `prefix`
+    pub fn subscribe_prefix(&self, prefix: &str) -> Subscription {
+        self.state.lock().unwrap().subscribe(prefix.to_string())
+    }
+
     /// Updates the flags associated with the entry of the given id
-    pub fn update_entry_flags(&self, id: u16, new_flags: u8) {
-        self.state.lock().unwrap().update_entry_flags(id, new_flags);
+    pub fn update_entry_flags(&self, id: u16, new_flags: u8) -> Result<()> {
+        self.state.lock().unwrap().update_entry_flags(id, new_flags)
     }
 }
 
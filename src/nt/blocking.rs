@@ -0,0 +1,219 @@
+//! A blocking API over `NetworkTables`, for programs that don't run a tokio runtime.
+//!
+//! Calls that wait on the remote end take a timeout, and return `Error::Timeout` once it has passed.
+//! Everything that doesn't wait, such as reading and updating entries, is available through `Deref`.
+
+use crate::error::Error;
+use crate::nt::EntryData;
+use crate::proto::client::ClientState;
+use crate::proto::{spawn_thread_on, Client, NTBackend, Server};
+use crate::Result;
+use futures_channel::mpsc::{channel, unbounded, Receiver};
+use futures_util::StreamExt;
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::runtime::{Builder, Runtime};
+
+/// A `NetworkTables` connection whose waiting calls block the current thread
+pub struct NetworkTables<T: NTBackend> {
+    inner: crate::NetworkTables<T>,
+    rt: Mutex<Runtime>,
+}
+
+/// Builds the runtime that blocking calls wait on. The connection itself runs on its own thread.
+fn runtime() -> Result<Runtime> {
+    Ok(Builder::new().basic_scheduler().enable_all().build()?)
+}
+
+fn block_on<F: Future>(rt: &mut Runtime, timeout: Duration, fut: F) -> Result<F::Output> {
+    rt.block_on(async { tokio::time::timeout(timeout, fut).await })
+        .map_err(|_| Error::Timeout)
+}
+
+impl<T: NTBackend> NetworkTables<T> {
+    fn block_on<F: Future>(&self, timeout: Duration, fut: F) -> Result<F::Output> {
+        block_on(&mut self.rt.lock().unwrap(), timeout, fut)
+    }
+
+    /// Returns the underlying async `NetworkTables`
+    pub fn into_inner(self) -> crate::NetworkTables<T> {
+        self.inner
+    }
+
+    /// Creates a new entry with the specified data, returning the id assigned to it by the server.
+    ///
+    /// If this is a client, this blocks until the server has assigned the id, or `timeout` has passed.
+    pub fn create_entry(&self, data: EntryData, timeout: Duration) -> Result<u16> {
+        self.block_on(timeout, self.inner.create_entry(data))?
+    }
+}
+
+impl NetworkTables<Client> {
+    /// Connects over TCP to the given ip, with the given client name, blocking until the handshake
+    /// has completed or `timeout` has passed
+    pub fn connect(ip: &str, client_name: &str, timeout: Duration) -> Result<Self> {
+        let conn_rt = Runtime::new()?;
+        Self::connect_with(timeout, |close_rx| {
+            ClientState::new(ip.to_string(), client_name.to_string(), close_rx, |task| {
+                spawn_thread_on(conn_rt, task)
+            })
+        })
+    }
+
+    /// Connects over websockets to the given ip, with the given client name, blocking until the
+    /// handshake has completed or `timeout` has passed
+    #[cfg(feature = "websocket")]
+    pub fn connect_ws(ip: &str, client_name: &str, timeout: Duration) -> Result<Self> {
+        let conn_rt = Runtime::new()?;
+        Self::connect_with(timeout, |close_rx| {
+            ClientState::new_ws(ip.to_string(), client_name.to_string(), close_rx, |task| {
+                spawn_thread_on(conn_rt, task)
+            })
+        })
+    }
+
+    /// Connects to the NT4 server on the given host as in `NetworkTables::connect_nt4`, blocking
+    /// until the connection is ready or `timeout` has passed
+    #[cfg(feature = "websocket")]
+    pub fn connect_nt4(host: &str, client_name: &str, timeout: Duration) -> Result<Self> {
+        let conn_rt = Runtime::new()?;
+        Self::connect_with(timeout, |close_rx| {
+            ClientState::new_nt4(
+                host.to_string(),
+                client_name.to_string(),
+                close_rx,
+                |task| spawn_thread_on(conn_rt, task),
+            )
+        })
+    }
+
+    fn connect_with<F, R>(
+        timeout: Duration,
+        connect: impl FnOnce(Receiver<()>) -> F,
+    ) -> Result<Self>
+    where
+        F: Future<Output = Result<(Arc<Mutex<ClientState>>, R)>>,
+    {
+        let mut rt = runtime()?;
+        let (mut close_tx, close_rx) = channel::<()>(1);
+        match block_on(&mut rt, timeout, connect(close_rx)) {
+            Ok(state) => Ok(NetworkTables {
+                inner: crate::NetworkTables {
+                    state: state?.0,
+                    close_tx,
+                },
+                rt: Mutex::new(rt),
+            }),
+            Err(e) => {
+                // The handshake may still complete, in which case the connection closes right away
+                let _ = close_tx.try_send(());
+                Err(e)
+            }
+        }
+    }
+
+    /// Reconnects to the server over TCP as in `NetworkTables::reconnect`, blocking until the
+    /// handshake has completed or `timeout` has passed
+    pub fn reconnect(&mut self, timeout: Duration) -> Result<()> {
+        let mut rt = self.rt.lock().unwrap();
+        let res = block_on(&mut rt, timeout, self.inner.reconnect());
+        self.finish_reconnect(res)
+    }
+
+    /// Reconnects to the server over websockets as in `NetworkTables::reconnect_ws`, blocking until
+    /// the handshake has completed or `timeout` has passed
+    #[cfg(feature = "websocket")]
+    pub fn reconnect_ws(&mut self, timeout: Duration) -> Result<()> {
+        let mut rt = self.rt.lock().unwrap();
+        let res = block_on(&mut rt, timeout, self.inner.reconnect_ws());
+        self.finish_reconnect(res)
+    }
+
+    /// Reconnects to the NT4 server as in `NetworkTables::reconnect_nt4`, blocking until the
+    /// connection is ready or `timeout` has passed
+    #[cfg(feature = "websocket")]
+    pub fn reconnect_nt4(&mut self, timeout: Duration) -> Result<()> {
+        let mut rt = self.rt.lock().unwrap();
+        let res = block_on(&mut rt, timeout, self.inner.reconnect_nt4());
+        self.finish_reconnect(res)
+    }
+
+    fn finish_reconnect(&self, res: Result<()>) -> Result<()> {
+        if let Err(e) = res {
+            let _ = self.inner.close_tx.clone().try_send(());
+            return Err(e);
+        }
+        if self.inner.state.lock().unwrap().connected {
+            Ok(())
+        } else {
+            Err(Error::ConnectionAborted)
+        }
+    }
+
+    /// Calls the RPC with the given id, blocking until the server has returned its result or
+    /// `timeout` has passed
+    pub fn call_rpc(&self, id: u16, parameter: Vec<u8>, timeout: Duration) -> Result<Vec<u8>> {
+        let (result_tx, mut result_rx) = unbounded();
+        self.inner.call_rpc(id, parameter, move |result| {
+            let _ = result_tx.unbounded_send(result);
+        })?;
+        self.block_on(timeout, result_rx.next())?
+            .ok_or(Error::BrokenPipe)
+    }
+}
+
+impl NetworkTables<Server> {
+    /// Initializes an NT server over TCP and binds it to the given ip, as in `NetworkTables::bind`,
+    /// blocking until it is listening
+    pub fn bind(ip: &str, server_name: &str) -> Result<Self> {
+        let mut rt = runtime()?;
+        let inner = rt.block_on(crate::NetworkTables::bind(ip, server_name))?;
+        Ok(NetworkTables {
+            inner,
+            rt: Mutex::new(rt),
+        })
+    }
+
+    /// Like `bind`, but with persistent entries stored in the file at `path`
+    pub fn bind_with_persist_file(
+        ip: &str,
+        server_name: &str,
+        path: impl AsRef<Path>,
+    ) -> Result<Self> {
+        let mut rt = runtime()?;
+        let inner = rt.block_on(crate::NetworkTables::bind_with_persist_file(
+            ip,
+            server_name,
+            path,
+        ))?;
+        Ok(NetworkTables {
+            inner,
+            rt: Mutex::new(rt),
+        })
+    }
+
+    /// Stops the server as in `NetworkTables::shutdown`, blocking until it has stopped or `timeout`
+    /// has passed
+    pub fn shutdown(self, timeout: Duration) -> Result<()> {
+        let NetworkTables { inner, rt } = self;
+        let mut rt = rt.into_inner().unwrap();
+        block_on(&mut rt, timeout, inner.shutdown())
+    }
+}
+
+impl<T: NTBackend> Deref for NetworkTables<T> {
+    type Target = crate::NetworkTables<T>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl<T: NTBackend> DerefMut for NetworkTables<T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
@@ -0,0 +1,39 @@
+use std::net::SocketAddr;
+use std::time::Instant;
+
+/// The version of the NetworkTables protocol spoken over a connection
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Protocol {
+    NT2,
+    NT3,
+    NT4,
+}
+
+/// Information about the remote end of an established connection
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    /// The address of the remote end
+    pub addr: SocketAddr,
+    /// The name the remote end identified itself with. For a client this is the name of the server
+    /// from its hello, and for a server the name of the client. NT2 peers and NT4 servers don't send
+    /// one, so it is empty.
+    pub remote_name: String,
+    pub protocol: Protocol,
+    /// When the handshake completed
+    pub connected_at: Instant,
+    /// When a packet was last received from the remote end
+    pub last_received: Instant,
+}
+
+impl ConnectionInfo {
+    pub(crate) fn new(addr: SocketAddr, remote_name: String, protocol: Protocol) -> ConnectionInfo {
+        let now = Instant::now();
+        ConnectionInfo {
+            addr,
+            remote_name,
+            protocol,
+            connected_at: now,
+            last_received: now,
+        }
+    }
+}
@@ -1,37 +1,114 @@
-use crate::proto::NTBackend;
+use crate::error::Error;
+use crate::proto::{NTBackend, State};
 use crate::NetworkTables;
-use nt_network::types::{EntryType, EntryValue};
+use nt_network::nt4::Properties;
+use nt_network::types::{EntryType, EntryValue, EntryValueType};
+use nt_network::SequenceNumber;
+use std::marker::PhantomData;
+
+/// The bit of `EntryData::flags` marking an entry as persistent
+pub const PERSISTENT_FLAG: u8 = 0x01;
 
 #[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct EntryData {
     pub name: String,
     pub flags: u8,
     pub value: EntryValue,
-    pub seqnum: u16,
+    pub seqnum: SequenceNumber,
+    /// The server time, in microseconds, at which the current value was produced.
+    ///
+    /// This is `None` when it isn't known, such as for values received by NT3 clients, which have
+    /// no way of synchronizing their clock with the server.
+    pub timestamp: Option<u64>,
+    /// The NT4 properties of the entry.
+    ///
+    /// The `persistent` property is kept in sync with the persistent bit of `flags`.
+    pub properties: Properties,
 }
 
 impl EntryData {
     pub fn new(name: String, flags: u8, value: EntryValue) -> EntryData {
-        Self::new_with_seqnum(name, flags, value, 1)
+        Self::new_with_seqnum(name, flags, value, SequenceNumber::new(1))
     }
 
     pub fn entry_type(&self) -> EntryType {
         self.value.entry_type()
     }
 
+    /// Whether the entry should be saved by the server, and survive restarts
+    pub fn is_persistent(&self) -> bool {
+        self.flags & PERSISTENT_FLAG != 0
+    }
+
+    /// Whether the server keeps the entry once the last NT4 client publishing it goes away
+    pub fn is_retained(&self) -> bool {
+        self.properties
+            .get("retained")
+            .and_then(|retained| retained.as_bool())
+            .unwrap_or(false)
+    }
+
+    /// Whether the server sends the last value of the entry to new NT4 subscribers
+    pub fn is_cached(&self) -> bool {
+        self.properties
+            .get("cached")
+            .and_then(|cached| cached.as_bool())
+            .unwrap_or(true)
+    }
+
+    /// Sets the flags of the entry, updating the `persistent` property to match
+    pub fn set_flags(&mut self, flags: u8) {
+        self.flags = flags;
+        if self.is_persistent() {
+            self.properties
+                .insert("persistent".to_string(), true.into());
+        } else {
+            self.properties.remove("persistent");
+        }
+    }
+
+    /// Merges an NT4 property update into the properties of the entry, updating `flags` to match.
+    ///
+    /// As in NT4, properties set to `null` are removed.
+    pub fn update_properties(&mut self, update: &Properties) {
+        for (key, value) in update {
+            if value.is_null() {
+                self.properties.remove(key);
+            } else {
+                self.properties.insert(key.clone(), value.clone());
+            }
+        }
+
+        let persistent = self
+            .properties
+            .get("persistent")
+            .and_then(|persistent| persistent.as_bool())
+            .unwrap_or(false);
+        if persistent {
+            self.flags |= PERSISTENT_FLAG;
+        } else {
+            self.flags &= !PERSISTENT_FLAG;
+        }
+    }
+
     #[doc(hidden)]
     pub(crate) fn new_with_seqnum(
         name: String,
         flags: u8,
         value: EntryValue,
-        seqnum: u16,
+        seqnum: SequenceNumber,
     ) -> EntryData {
-        EntryData {
+        let mut data = EntryData {
             name,
-            flags,
+            flags: 0,
             value,
             seqnum,
-        }
+            timestamp: None,
+            properties: Properties::new(),
+        };
+        data.set_flags(flags);
+        data
     }
 }
 
@@ -49,19 +126,89 @@ impl<'a, T: NTBackend> Entry<'a, T> {
         &self.id
     }
 
-    pub fn value(&self) -> EntryData {
-        self.nt.entries()[&self.id].clone()
+    /// Gets the current data of the entry, failing with `EntryNotFound` if it no longer exists
+    pub fn value(&self) -> crate::Result<EntryData> {
+        let state = self.nt.state.lock().unwrap();
+        state
+            .entries()
+            .get(&self.id)
+            .cloned()
+            .ok_or(Error::EntryNotFound { id: self.id })
+    }
+
+    pub fn set_persistent(&mut self, persistent: bool) -> crate::Result<()> {
+        let flags = if persistent { PERSISTENT_FLAG } else { 0 };
+        self.nt.update_entry_flags(self.id, flags)
+    }
+
+    pub fn set_value(&mut self, new_value: EntryValue) -> crate::Result<()> {
+        self.nt.update_entry(self.id, new_value)
+    }
+
+    pub fn delete(self) -> crate::Result<()> {
+        self.nt.delete_entry(self.id)
+    }
+
+    /// Converts this into a handle that reads and writes values of type `V`
+    pub fn typed<V: EntryValueType>(self) -> TypedEntry<'a, T, V> {
+        TypedEntry::new(self.nt, self.id)
+    }
+}
+
+/// A handle to an entry holding values of type `V`, such as `f64`, `bool`, `String` or `Vec<f64>`
+///
+/// Unlike `Entry`, values of the wrong type are never sent to the remote end.
+pub struct TypedEntry<'a, T: NTBackend, V: EntryValueType> {
+    nt: &'a NetworkTables<T>,
+    id: u16,
+    _marker: PhantomData<V>,
+}
+
+impl<'a, T: NTBackend, V: EntryValueType> TypedEntry<'a, T, V> {
+    pub fn new(nt: &'a NetworkTables<T>, id: u16) -> TypedEntry<'a, T, V> {
+        TypedEntry {
+            nt,
+            id,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn id(&self) -> &u16 {
+        &self.id
+    }
+
+    /// Gets the current value, or `None` if the entry doesn't exist or holds a value of another type
+    pub fn get(&self) -> Option<V> {
+        let state = self.nt.state.lock().unwrap();
+        let value = state.entries().get(&self.id)?.value.clone();
+        V::try_from(value).ok()
     }
 
-    pub fn set_persistent(&mut self, persistent: bool) {
-        self.nt.update_entry_flags(self.id, persistent as u8);
+    /// Gets the current value, or `default` if it isn't available as a `V`
+    pub fn get_or(&self, default: V) -> V {
+        self.get().unwrap_or(default)
     }
 
-    pub fn set_value(&mut self, new_value: EntryValue) {
-        self.nt.update_entry(self.id, new_value);
+    /// Sets the value of the entry.
+    ///
+    /// Fails without sending anything if the entry doesn't exist, or has a type other than `V`.
+    pub fn set(&mut self, value: V) -> crate::Result<()> {
+        let expected = match self.nt.state.lock().unwrap().entries().get(&self.id) {
+            Some(entry) => entry.entry_type(),
+            None => return Err(Error::EntryNotFound { id: self.id }),
+        };
+        if expected != V::ENTRY_TYPE {
+            return Err(Error::TypeMismatch {
+                expected,
+                found: V::ENTRY_TYPE,
+            });
+        }
+
+        self.nt.update_entry(self.id, value.into())
     }
 
-    pub fn delete(self) {
-        self.nt.delete_entry(self.id);
+    /// Converts this back into an untyped `Entry`
+    pub fn untyped(self) -> Entry<'a, T> {
+        Entry::new(self.nt, self.id)
     }
 }
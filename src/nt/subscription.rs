@@ -0,0 +1,169 @@
+use crate::EntryData;
+use futures_channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+use futures_util::stream::Stream;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+/// The kind of change described by an `EntryEvent`
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum EntryEventKind {
+    Added,
+    Updated,
+    /// The flags or NT4 properties of the entry changed
+    FlagsChanged,
+    Deleted,
+    /// The entry was removed by clearing all entries
+    Cleared,
+}
+
+/// Where the change described by an `EntryEvent` came from
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
+pub enum EventSource {
+    /// The change was made through this `NetworkTables` instance
+    Local,
+    /// The change was received from a remote connection
+    Remote,
+}
+
+/// A change to an entry, as yielded by a `Subscription`
+#[derive(Clone, Debug)]
+pub struct EntryEvent {
+    pub kind: EntryEventKind,
+    pub id: u16,
+    /// The entry before the change, `None` for `Added` events
+    pub old: Option<EntryData>,
+    /// The entry after the change, `None` for `Deleted` and `Cleared` events
+    pub new: Option<EntryData>,
+    pub source: EventSource,
+}
+
+impl EntryEvent {
+    /// The name of the entry that changed
+    pub fn name(&self) -> &str {
+        // Every event has at least one of the two
+        &self.new.as_ref().or(self.old.as_ref()).unwrap().name
+    }
+}
+
+/// A stream of `EntryEvent`s, created by `NetworkTables::subscribe`
+///
+/// Events are buffered until they are read, and the subscription is removed when this is dropped.
+pub struct Subscription {
+    rx: UnboundedReceiver<EntryEvent>,
+}
+
+impl Stream for Subscription {
+    type Item = EntryEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<EntryEvent>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+/// A change to the connection of a client, or to the clients of a server, as yielded by
+/// `NetworkTables::connection_events`
+#[derive(Clone, Debug)]
+pub enum ConnectionEvent {
+    /// The handshake with the server at `addr` has completed
+    Connected { addr: SocketAddr },
+    /// An established connection was lost
+    Disconnected { reason: String },
+    /// An attempt to connect failed before the handshake completed
+    ConnectFailed { reason: String },
+    /// The connection will be attempted again after `delay`. Attempts are counted from 1, and
+    /// start over once a connection succeeds.
+    Reconnecting { attempt: u32, delay: Duration },
+    /// The handshake of the client at `addr` with this server has completed
+    ClientConnected { addr: SocketAddr },
+    /// The client at `addr` disconnected from this server, or was disconnected by it for
+    /// misbehaving. Clients that never completed their handshake aren't reported.
+    ClientDisconnected { addr: SocketAddr, reason: String },
+}
+
+/// A stream of `ConnectionEvent`s, created by `NetworkTables::connection_events`
+pub struct ConnectionEvents {
+    rx: UnboundedReceiver<ConnectionEvent>,
+}
+
+impl Stream for ConnectionEvents {
+    type Item = ConnectionEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<ConnectionEvent>> {
+        Pin::new(&mut self.rx).poll_next(cx)
+    }
+}
+
+/// The `ConnectionEvents` streams registered with a client or server
+#[derive(Default)]
+pub(crate) struct ConnectionSubscribers {
+    subscribers: Vec<UnboundedSender<ConnectionEvent>>,
+}
+
+impl ConnectionSubscribers {
+    pub fn subscribe(&mut self) -> ConnectionEvents {
+        let (tx, rx) = unbounded();
+        self.subscribers.push(tx);
+        ConnectionEvents { rx }
+    }
+
+    /// Sends an event to every subscriber, dropping closed ones
+    pub fn notify(&mut self, event: ConnectionEvent) {
+        self.subscribers.retain(|tx| !tx.is_closed());
+        for tx in &self.subscribers {
+            let _ = tx.unbounded_send(event.clone());
+        }
+    }
+}
+
+struct Subscriber {
+    prefix: String,
+    tx: UnboundedSender<EntryEvent>,
+}
+
+/// The subscriptions registered with a connection state
+#[derive(Default)]
+pub(crate) struct Subscribers {
+    subscribers: Vec<Subscriber>,
+}
+
+impl Subscribers {
+    pub fn subscribe(&mut self, prefix: String) -> Subscription {
+        let (tx, rx) = unbounded();
+        self.subscribers.push(Subscriber { prefix, tx });
+        Subscription { rx }
+    }
+
+    /// Sends an event to every subscriber interested in the entry, dropping closed subscriptions.
+    ///
+    /// The entry data is only cloned if there is someone to send it to.
+    pub fn notify(
+        &mut self,
+        kind: EntryEventKind,
+        id: u16,
+        old: Option<&EntryData>,
+        new: Option<&EntryData>,
+        source: EventSource,
+    ) {
+        self.subscribers.retain(|sub| !sub.tx.is_closed());
+
+        let name = match new.or(old) {
+            Some(data) => &data.name,
+            None => return,
+        };
+        for sub in self
+            .subscribers
+            .iter()
+            .filter(|sub| name.starts_with(&sub.prefix))
+        {
+            let _ = sub.tx.unbounded_send(EntryEvent {
+                kind,
+                id,
+                old: old.cloned(),
+                new: new.cloned(),
+                source,
+            });
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use crate::nt::entry::Entry;
+use crate::proto::{NTBackend, State};
+use crate::NetworkTables;
+
+/// A view of the entries under a path, such as `/SmartDashboard/Drive`
+///
+/// Entry names are treated as `/` separated paths. Keys are relative to the path of the table, so
+/// the entry `/SmartDashboard/Drive/kP` is the key `kP` of the table `/SmartDashboard/Drive`.
+pub struct NetworkTable<'a, T: NTBackend> {
+    nt: &'a NetworkTables<T>,
+    path: String,
+}
+
+impl<'a, T: NTBackend> NetworkTable<'a, T> {
+    pub(crate) fn new(nt: &'a NetworkTables<T>, path: &str) -> NetworkTable<'a, T> {
+        let path = path.trim_end_matches('/');
+        let path = if path.is_empty() || path.starts_with('/') {
+            path.to_string()
+        } else {
+            format!("/{}", path)
+        };
+        NetworkTable { nt, path }
+    }
+
+    /// The path of this table, which is empty for the root table
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The full name of the entry with the given key
+    pub fn entry_name(&self, key: &str) -> String {
+        format!("{}/{}", self.path, key)
+    }
+
+    /// Gets the table with the given key, which may itself contain `/`
+    pub fn get_sub_table(&self, key: &str) -> NetworkTable<'a, T> {
+        NetworkTable::new(self.nt, &self.entry_name(key.trim_matches('/')))
+    }
+
+    /// Gets the keys of the entries directly in this table, in sorted order
+    pub fn get_keys(&self) -> Vec<String> {
+        let mut keys = Vec::new();
+        self.for_each_child(|key, is_table| {
+            if !is_table {
+                keys.push(key.to_string());
+            }
+        });
+        keys
+    }
+
+    /// Gets the keys of the tables directly in this table, in sorted order
+    pub fn get_sub_tables(&self) -> Vec<String> {
+        let mut tables = Vec::new();
+        self.for_each_child(|key, is_table| {
+            if is_table {
+                tables.push(key.to_string());
+            }
+        });
+        // The index orders `Drive-2/x` before `Drive/x`, so the tables are not sorted by key
+        tables.sort();
+        tables.dedup();
+        tables
+    }
+
+    /// Whether this table has an entry with the given key
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get_id(key).is_some()
+    }
+
+    /// Gets the id of the entry with the given key
+    pub fn get_id(&self, key: &str) -> Option<u16> {
+        let state = self.nt.state.lock().unwrap();
+        state.entry_ids().get(&self.entry_name(key)).copied()
+    }
+
+    /// Gets the entry with the given key, if it exists
+    pub fn get_entry(&self, key: &str) -> Option<Entry<'a, T>> {
+        self.get_id(key).map(|id| Entry::new(self.nt, id))
+    }
+
+    /// Calls `f` with the first path segment of every entry under this table, and whether more
+    /// segments follow it
+    fn for_each_child(&self, mut f: impl FnMut(&str, bool)) {
+        let prefix = format!("{}/", self.path);
+        let state = self.nt.state.lock().unwrap();
+        for name in state
+            .entry_ids()
+            .range(prefix.clone()..)
+            .map(|(name, _)| name)
+            .take_while(|name| name.starts_with(&prefix))
+        {
+            let rest = &name[prefix.len()..];
+            match rest.find('/') {
+                Some(end) => f(&rest[..end], true),
+                None => f(rest, false),
+            }
+        }
+    }
+}
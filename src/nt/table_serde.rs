@@ -0,0 +1,1098 @@
+//! Serde support for binding the fields of Rust structs to the entries of a table
+//!
+//! Numbers are stored as doubles, and strings, bools and arrays of them as the matching entry
+//! types. Nested structs and maps are stored as sub-tables, and fields that are `None` are skipped.
+
+use crate::{EntryType, EntryValue};
+use serde::de::{self, DeserializeSeed, IntoDeserializer, MapAccess, SeqAccess, Visitor};
+use serde::ser::{self, Impossible, Serialize};
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fmt::Display;
+use thiserror::Error;
+
+/// An error converting between a Rust value and the entries of a table
+#[derive(Debug, Error)]
+#[error("{0}")]
+pub struct StructError(String);
+
+impl ser::Error for StructError {
+    fn custom<T: Display>(msg: T) -> Self {
+        StructError(msg.to_string())
+    }
+}
+
+impl de::Error for StructError {
+    fn custom<T: Display>(msg: T) -> Self {
+        StructError(msg.to_string())
+    }
+}
+
+fn unsupported<T>(what: &str) -> Result<T, StructError> {
+    Err(StructError(format!("{} can't be stored in entries", what)))
+}
+
+/// Flattens `value` into the entries under `prefix`
+pub(crate) fn to_entries<T: Serialize + ?Sized>(
+    prefix: &str,
+    value: &T,
+) -> Result<Vec<(String, EntryValue)>, StructError> {
+    let mut entries = Vec::new();
+    value.serialize(TableSerializer {
+        prefix: prefix.to_string(),
+        entries: &mut entries,
+        key: None,
+    })?;
+    Ok(entries)
+}
+
+/// Builds a `T` from `entries`, which hold the entries under `prefix` by name
+pub(crate) fn from_entries<T: de::DeserializeOwned>(
+    prefix: &str,
+    entries: &BTreeMap<String, EntryValue>,
+) -> Result<T, StructError> {
+    T::deserialize(TableDeserializer {
+        prefix: prefix.to_string(),
+        entries,
+    })
+}
+
+/// An empty array of the given type, which is what an empty `Vec` becomes in an existing entry
+pub(crate) fn empty_array(entry_type: EntryType) -> Option<EntryValue> {
+    match entry_type {
+        EntryType::BooleanArray => Some(EntryValue::BooleanArray(Vec::new())),
+        EntryType::DoubleArray => Some(EntryValue::DoubleArray(Vec::new())),
+        EntryType::StringArray => Some(EntryValue::StringArray(Vec::new())),
+        EntryType::IntegerArray => Some(EntryValue::IntegerArray(Vec::new())),
+        EntryType::FloatArray => Some(EntryValue::FloatArray(Vec::new())),
+        _ => None,
+    }
+}
+
+/// Combines the elements of a sequence into an array value.
+///
+/// The type of an empty sequence can't be known, so it becomes a `DoubleArray`.
+fn array_value(values: Vec<EntryValue>) -> Result<EntryValue, StructError> {
+    let mixed = |_| StructError("Arrays must hold values of a single type".to_string());
+    Ok(match values.first() {
+        Some(EntryValue::Boolean(_)) => EntryValue::BooleanArray(
+            values
+                .into_iter()
+                .map(bool::try_from)
+                .collect::<Result<_, _>>()
+                .map_err(mixed)?,
+        ),
+        Some(EntryValue::String(_)) => EntryValue::StringArray(
+            values
+                .into_iter()
+                .map(String::try_from)
+                .collect::<Result<_, _>>()
+                .map_err(mixed)?,
+        ),
+        _ => EntryValue::DoubleArray(
+            values
+                .into_iter()
+                .map(f64::try_from)
+                .collect::<Result<_, _>>()
+                .map_err(mixed)?,
+        ),
+    })
+}
+
+macro_rules! reject_scalars {
+    ($what:expr; $($method:ident($ty:ty),)*) => {
+        $(
+            fn $method(self, _v: $ty) -> Result<Self::Ok, StructError> {
+                unsupported($what)
+            }
+        )*
+    };
+}
+
+/// Serializes a struct or map into the entries of a table
+struct TableSerializer<'a> {
+    prefix: String,
+    entries: &'a mut Vec<(String, EntryValue)>,
+    /// The key of the map entry being serialized
+    key: Option<String>,
+}
+
+impl<'a> TableSerializer<'a> {
+    fn field(&mut self, key: &str) -> FieldSerializer<'_> {
+        FieldSerializer {
+            name: format!("{}/{}", self.prefix, key),
+            entries: self.entries,
+        }
+    }
+}
+
+impl<'a> ser::Serializer for TableSerializer<'a> {
+    type Ok = ();
+    type Error = StructError;
+    type SerializeSeq = Impossible<(), StructError>;
+    type SerializeTuple = Impossible<(), StructError>;
+    type SerializeTupleStruct = Impossible<(), StructError>;
+    type SerializeTupleVariant = Impossible<(), StructError>;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Impossible<(), StructError>;
+
+    reject_scalars! {
+        "A value outside of a struct";
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+        serialize_bytes(&[u8]),
+    }
+
+    fn serialize_none(self) -> Result<(), StructError> {
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), StructError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), StructError> {
+        unsupported("A unit value")
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), StructError> {
+        unsupported("A unit struct")
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+    ) -> Result<(), StructError> {
+        unsupported("An enum outside of a struct")
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), StructError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<(), StructError> {
+        unsupported("An enum with data")
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, StructError> {
+        unsupported("A sequence outside of a struct")
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, StructError> {
+        unsupported("A tuple outside of a struct")
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, StructError> {
+        unsupported("A tuple struct outside of a struct")
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, StructError> {
+        unsupported("An enum with data")
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self, StructError> {
+        Ok(self)
+    }
+
+    fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self, StructError> {
+        Ok(self)
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, StructError> {
+        unsupported("An enum with data")
+    }
+}
+
+impl<'a> ser::SerializeStruct for TableSerializer<'a> {
+    type Ok = ();
+    type Error = StructError;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), StructError> {
+        value.serialize(self.field(key))
+    }
+
+    fn end(self) -> Result<(), StructError> {
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeMap for TableSerializer<'a> {
+    type Ok = ();
+    type Error = StructError;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), StructError> {
+        match key.serialize(LeafSerializer)? {
+            EntryValue::String(key) => {
+                self.key = Some(key);
+                Ok(())
+            }
+            _ => unsupported("A map with keys that aren't strings"),
+        }
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), StructError> {
+        let key = self.key.take().unwrap_or_default();
+        value.serialize(self.field(&key))
+    }
+
+    fn end(self) -> Result<(), StructError> {
+        Ok(())
+    }
+}
+
+macro_rules! forward_to_leaf {
+    ($($method:ident($ty:ty),)*) => {
+        $(
+            fn $method(self, v: $ty) -> Result<(), StructError> {
+                let value = LeafSerializer.$method(v)?;
+                self.entries.push((self.name, value));
+                Ok(())
+            }
+        )*
+    };
+}
+
+/// Serializes the field of a struct into an entry, or a sub-table if it is itself a struct
+struct FieldSerializer<'a> {
+    name: String,
+    entries: &'a mut Vec<(String, EntryValue)>,
+}
+
+impl<'a> FieldSerializer<'a> {
+    fn table(self) -> TableSerializer<'a> {
+        TableSerializer {
+            prefix: self.name,
+            entries: self.entries,
+            key: None,
+        }
+    }
+
+    fn array(self) -> ArraySerializer<'a> {
+        ArraySerializer {
+            name: self.name,
+            entries: self.entries,
+            values: Vec::new(),
+        }
+    }
+}
+
+impl<'a> ser::Serializer for FieldSerializer<'a> {
+    type Ok = ();
+    type Error = StructError;
+    type SerializeSeq = ArraySerializer<'a>;
+    type SerializeTuple = ArraySerializer<'a>;
+    type SerializeTupleStruct = ArraySerializer<'a>;
+    type SerializeTupleVariant = Impossible<(), StructError>;
+    type SerializeMap = TableSerializer<'a>;
+    type SerializeStruct = TableSerializer<'a>;
+    type SerializeStructVariant = Impossible<(), StructError>;
+
+    forward_to_leaf! {
+        serialize_bool(bool),
+        serialize_i8(i8),
+        serialize_i16(i16),
+        serialize_i32(i32),
+        serialize_i64(i64),
+        serialize_u8(u8),
+        serialize_u16(u16),
+        serialize_u32(u32),
+        serialize_u64(u64),
+        serialize_f32(f32),
+        serialize_f64(f64),
+        serialize_char(char),
+        serialize_str(&str),
+        serialize_bytes(&[u8]),
+    }
+
+    fn serialize_none(self) -> Result<(), StructError> {
+        Ok(())
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), StructError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), StructError> {
+        unsupported("A unit value")
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), StructError> {
+        unsupported("A unit struct")
+    }
+
+    fn serialize_unit_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+    ) -> Result<(), StructError> {
+        let value = LeafSerializer.serialize_unit_variant(name, index, variant)?;
+        self.entries.push((self.name, value));
+        Ok(())
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), StructError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<(), StructError> {
+        unsupported("An enum with data")
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<ArraySerializer<'a>, StructError> {
+        Ok(self.array())
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<ArraySerializer<'a>, StructError> {
+        Ok(self.array())
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<ArraySerializer<'a>, StructError> {
+        Ok(self.array())
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, StructError> {
+        unsupported("An enum with data")
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<TableSerializer<'a>, StructError> {
+        Ok(self.table())
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<TableSerializer<'a>, StructError> {
+        Ok(self.table())
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, StructError> {
+        unsupported("An enum with data")
+    }
+}
+
+/// Collects the elements of a sequence into an array entry
+struct ArraySerializer<'a> {
+    name: String,
+    entries: &'a mut Vec<(String, EntryValue)>,
+    values: Vec<EntryValue>,
+}
+
+impl<'a> ArraySerializer<'a> {
+    fn push<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), StructError> {
+        self.values.push(value.serialize(LeafSerializer)?);
+        Ok(())
+    }
+
+    fn finish(self) -> Result<(), StructError> {
+        self.entries.push((self.name, array_value(self.values)?));
+        Ok(())
+    }
+}
+
+impl<'a> ser::SerializeSeq for ArraySerializer<'a> {
+    type Ok = ();
+    type Error = StructError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), StructError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<(), StructError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTuple for ArraySerializer<'a> {
+    type Ok = ();
+    type Error = StructError;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), StructError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<(), StructError> {
+        self.finish()
+    }
+}
+
+impl<'a> ser::SerializeTupleStruct for ArraySerializer<'a> {
+    type Ok = ();
+    type Error = StructError;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), StructError> {
+        self.push(value)
+    }
+
+    fn end(self) -> Result<(), StructError> {
+        self.finish()
+    }
+}
+
+/// Serializes a single scalar into an `EntryValue`
+struct LeafSerializer;
+
+impl ser::Serializer for LeafSerializer {
+    type Ok = EntryValue;
+    type Error = StructError;
+    type SerializeSeq = Impossible<EntryValue, StructError>;
+    type SerializeTuple = Impossible<EntryValue, StructError>;
+    type SerializeTupleStruct = Impossible<EntryValue, StructError>;
+    type SerializeTupleVariant = Impossible<EntryValue, StructError>;
+    type SerializeMap = Impossible<EntryValue, StructError>;
+    type SerializeStruct = Impossible<EntryValue, StructError>;
+    type SerializeStructVariant = Impossible<EntryValue, StructError>;
+
+    fn serialize_bool(self, v: bool) -> Result<EntryValue, StructError> {
+        Ok(EntryValue::Boolean(v))
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<EntryValue, StructError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<EntryValue, StructError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<EntryValue, StructError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<EntryValue, StructError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<EntryValue, StructError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<EntryValue, StructError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<EntryValue, StructError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<EntryValue, StructError> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<EntryValue, StructError> {
+        self.serialize_f64(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<EntryValue, StructError> {
+        Ok(EntryValue::Double(v))
+    }
+
+    fn serialize_char(self, v: char) -> Result<EntryValue, StructError> {
+        Ok(EntryValue::String(v.to_string()))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<EntryValue, StructError> {
+        Ok(EntryValue::String(v.to_string()))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<EntryValue, StructError> {
+        Ok(EntryValue::RawData(v.to_vec()))
+    }
+
+    fn serialize_none(self) -> Result<EntryValue, StructError> {
+        unsupported("A missing array element")
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<EntryValue, StructError> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<EntryValue, StructError> {
+        unsupported("A unit value")
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<EntryValue, StructError> {
+        unsupported("A unit struct")
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<EntryValue, StructError> {
+        Ok(EntryValue::String(variant.to_string()))
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<EntryValue, StructError> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _value: &T,
+    ) -> Result<EntryValue, StructError> {
+        unsupported("An enum with data")
+    }
+
+    fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, StructError> {
+        unsupported("A nested array")
+    }
+
+    fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, StructError> {
+        unsupported("A nested array")
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleStruct, StructError> {
+        unsupported("A nested array")
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeTupleVariant, StructError> {
+        unsupported("An enum with data")
+    }
+
+    fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, StructError> {
+        unsupported("A map inside of an array")
+    }
+
+    fn serialize_struct(
+        self,
+        _name: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStruct, StructError> {
+        unsupported("A struct inside of an array")
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        _variant: &'static str,
+        _len: usize,
+    ) -> Result<Self::SerializeStructVariant, StructError> {
+        unsupported("An enum with data")
+    }
+}
+
+/// Deserializes a struct or map from the entries of a table
+struct TableDeserializer<'a> {
+    prefix: String,
+    entries: &'a BTreeMap<String, EntryValue>,
+}
+
+enum Child<'a> {
+    Value(EntryValue),
+    Table(TableDeserializer<'a>),
+}
+
+impl<'a> TableDeserializer<'a> {
+    fn child(&self, key: &str) -> Option<Child<'a>> {
+        let name = format!("{}/{}", self.prefix, key);
+        if let Some(value) = self.entries.get(&name) {
+            return Some(Child::Value(value.clone()));
+        }
+
+        let prefix = format!("{}/", name);
+        let has_children = self
+            .entries
+            .range(prefix.clone()..)
+            .next()
+            .map(|(name, _)| name.starts_with(&prefix))
+            .unwrap_or(false);
+        if has_children {
+            Some(Child::Table(TableDeserializer {
+                prefix: name,
+                entries: self.entries,
+            }))
+        } else {
+            None
+        }
+    }
+
+    /// The keys of the entries and sub-tables directly in this table
+    fn keys(&self) -> Vec<String> {
+        let prefix = format!("{}/", self.prefix);
+        let mut keys = self
+            .entries
+            .range(prefix.clone()..)
+            .map(|(name, _)| name)
+            .take_while(|name| name.starts_with(&prefix))
+            .map(|name| {
+                let rest = &name[prefix.len()..];
+                rest.split('/').next().unwrap_or(rest).to_string()
+            })
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys.dedup();
+        keys
+    }
+}
+
+impl<'de, 'a> de::Deserializer<'de> for TableDeserializer<'a> {
+    type Error = StructError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, StructError> {
+        self.deserialize_map(visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, StructError> {
+        let keys = self.keys();
+        visitor.visit_map(TableAccess {
+            table: self,
+            keys: keys.into_iter(),
+            child: None,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, StructError> {
+        // Fields without an entry are left out, so that serde can fill in defaults and `None`s
+        let keys = fields
+            .iter()
+            .filter(|field| self.child(field).is_some())
+            .map(|field| field.to_string())
+            .collect::<Vec<_>>();
+        visitor.visit_map(TableAccess {
+            table: self,
+            keys: keys.into_iter(),
+            child: None,
+        })
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, StructError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, StructError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct enum identifier ignored_any
+    }
+}
+
+struct TableAccess<'a> {
+    table: TableDeserializer<'a>,
+    keys: std::vec::IntoIter<String>,
+    child: Option<Child<'a>>,
+}
+
+impl<'de, 'a> MapAccess<'de> for TableAccess<'a> {
+    type Error = StructError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, StructError> {
+        for key in &mut self.keys {
+            if let Some(child) = self.table.child(&key) {
+                self.child = Some(child);
+                return seed.deserialize(key.into_deserializer()).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, StructError> {
+        match self.child.take() {
+            Some(Child::Value(value)) => seed.deserialize(ValueDeserializer(value)),
+            Some(Child::Table(table)) => seed.deserialize(table),
+            None => Err(StructError("Value requested before key".to_string())),
+        }
+    }
+}
+
+macro_rules! deserialize_integer {
+    ($($method:ident)*) => {
+        $(
+            // Integers are stored as doubles, which are only accepted if they have no fractional part
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, StructError> {
+                match self.0 {
+                    EntryValue::Double(d) if d.fract() == 0.0 && d >= 0.0 => visitor.visit_u64(d as u64),
+                    EntryValue::Double(d) if d.fract() == 0.0 => visitor.visit_i64(d as i64),
+                    _ => self.deserialize_any(visitor),
+                }
+            }
+        )*
+    };
+}
+
+/// Deserializes the value of a single entry
+struct ValueDeserializer(EntryValue);
+
+impl<'de> de::Deserializer<'de> for ValueDeserializer {
+    type Error = StructError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, StructError> {
+        let seq = |values: Vec<EntryValue>| ValueSeq(values.into_iter());
+        match self.0 {
+            EntryValue::Boolean(b) => visitor.visit_bool(b),
+            EntryValue::Double(d) => visitor.visit_f64(d),
+            EntryValue::String(s) => visitor.visit_string(s),
+            EntryValue::RawData(data) => visitor.visit_byte_buf(data),
+            EntryValue::Integer(i) => visitor.visit_i64(i),
+            EntryValue::Float(f) => visitor.visit_f32(f),
+            EntryValue::BooleanArray(v) => {
+                visitor.visit_seq(seq(v.into_iter().map(Into::into).collect()))
+            }
+            EntryValue::DoubleArray(v) => {
+                visitor.visit_seq(seq(v.into_iter().map(Into::into).collect()))
+            }
+            EntryValue::StringArray(v) => {
+                visitor.visit_seq(seq(v.into_iter().map(Into::into).collect()))
+            }
+            EntryValue::IntegerArray(v) => {
+                visitor.visit_seq(seq(v.into_iter().map(Into::into).collect()))
+            }
+            EntryValue::FloatArray(v) => {
+                visitor.visit_seq(seq(v.into_iter().map(Into::into).collect()))
+            }
+            EntryValue::RpcDefinition(_) => unsupported("An RPC definition"),
+        }
+    }
+
+    deserialize_integer! {
+        deserialize_i8 deserialize_i16 deserialize_i32 deserialize_i64
+        deserialize_u8 deserialize_u16 deserialize_u32 deserialize_u64
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, StructError> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, StructError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, StructError> {
+        match self.0 {
+            EntryValue::String(variant) => visitor.visit_enum(variant.into_deserializer()),
+            _ => self.deserialize_any(visitor),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i128 u128 f32 f64 char str string bytes byte_buf unit unit_struct seq tuple
+        tuple_struct map struct identifier ignored_any
+    }
+}
+
+struct ValueSeq(std::vec::IntoIter<EntryValue>);
+
+impl<'de> SeqAccess<'de> for ValueSeq {
+    type Error = StructError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, StructError> {
+        match self.0.next() {
+            Some(value) => seed.deserialize(ValueDeserializer(value)).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.0.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    enum Mode {
+        Brake,
+        Coast,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Gains {
+        p: f64,
+        i: f64,
+    }
+
+    #[derive(Debug, PartialEq, Serialize, Deserialize)]
+    struct Arm {
+        enabled: bool,
+        name: String,
+        port: u8,
+        offset: i32,
+        mode: Mode,
+        gains: Gains,
+        setpoints: Vec<f64>,
+        labels: Vec<String>,
+        limits: (bool, bool),
+        note: Option<String>,
+    }
+
+    fn arm() -> Arm {
+        Arm {
+            enabled: true,
+            name: "Arm".to_string(),
+            port: 4,
+            offset: -12,
+            mode: Mode::Coast,
+            gains: Gains { p: 0.5, i: 0.0 },
+            setpoints: vec![0.0, 45.5, 90.0],
+            labels: vec!["Stow".to_string(), "Score".to_string()],
+            limits: (true, false),
+            note: None,
+        }
+    }
+
+    fn round_trip<T: Serialize + de::DeserializeOwned>(prefix: &str, value: &T) -> T {
+        let entries = to_entries(prefix, value).unwrap().into_iter().collect();
+        from_entries(prefix, &entries).unwrap()
+    }
+
+    #[test]
+    fn structs_are_flattened_into_entries() {
+        let entries = to_entries("/Arm", &arm())
+            .unwrap()
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+        let expected = vec![
+            ("/Arm/enabled", EntryValue::Boolean(true)),
+            ("/Arm/name", EntryValue::String("Arm".to_string())),
+            ("/Arm/port", EntryValue::Double(4.0)),
+            ("/Arm/offset", EntryValue::Double(-12.0)),
+            ("/Arm/mode", EntryValue::String("Coast".to_string())),
+            ("/Arm/gains/p", EntryValue::Double(0.5)),
+            ("/Arm/gains/i", EntryValue::Double(0.0)),
+            (
+                "/Arm/setpoints",
+                EntryValue::DoubleArray(vec![0.0, 45.5, 90.0]),
+            ),
+            (
+                "/Arm/labels",
+                EntryValue::StringArray(vec!["Stow".to_string(), "Score".to_string()]),
+            ),
+            ("/Arm/limits", EntryValue::BooleanArray(vec![true, false])),
+        ]
+        .into_iter()
+        .map(|(name, value)| (name.to_string(), value))
+        .collect::<BTreeMap<_, _>>();
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn structs_round_trip() {
+        assert_eq!(round_trip("/Arm", &arm()), arm());
+
+        let arm = Arm {
+            note: Some("Geared 100:1".to_string()),
+            setpoints: Vec::new(),
+            ..arm()
+        };
+        assert_eq!(round_trip("/Robot/Arm", &arm), arm);
+    }
+
+    #[test]
+    fn maps_round_trip() {
+        let mut gains = BTreeMap::new();
+        gains.insert("shoulder".to_string(), Gains { p: 1.0, i: 0.1 });
+        gains.insert("wrist".to_string(), Gains { p: 2.0, i: 0.0 });
+        assert_eq!(round_trip("/Gains", &gains), gains);
+
+        let mut speeds = BTreeMap::new();
+        speeds.insert("left".to_string(), 0.5);
+        speeds.insert("right".to_string(), -0.5);
+        let entries = to_entries("/Speeds", &speeds).unwrap();
+        assert_eq!(
+            entries,
+            vec![
+                ("/Speeds/left".to_string(), EntryValue::Double(0.5)),
+                ("/Speeds/right".to_string(), EntryValue::Double(-0.5)),
+            ]
+        );
+        assert_eq!(round_trip("/Speeds", &speeds), speeds);
+    }
+
+    #[test]
+    fn sequences_round_trip() {
+        #[derive(Debug, PartialEq, Serialize, Deserialize)]
+        struct Seqs {
+            flags: Vec<bool>,
+            ids: Vec<u16>,
+            names: Vec<String>,
+            point: (f64, f64),
+        }
+
+        let seqs = Seqs {
+            flags: vec![false, true, true],
+            ids: vec![1, 2, 300],
+            names: vec![String::new(), "a/b".to_string()],
+            point: (1.5, -2.5),
+        };
+        assert_eq!(round_trip("/Seqs", &seqs), seqs);
+    }
+
+    #[test]
+    fn missing_entries_fall_back_to_defaults() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct Partial {
+            name: String,
+            #[serde(default)]
+            count: u32,
+            note: Option<String>,
+        }
+
+        let mut entries = BTreeMap::new();
+        entries.insert("/T/name".to_string(), EntryValue::String("x".to_string()));
+        // Entries outside of the table are ignored
+        entries.insert("/Other/count".to_string(), EntryValue::Double(3.0));
+        assert_eq!(
+            from_entries::<Partial>("/T", &entries).unwrap(),
+            Partial {
+                name: "x".to_string(),
+                count: 0,
+                note: None,
+            }
+        );
+
+        entries.remove("/T/name");
+        assert!(from_entries::<Partial>("/T", &entries).is_err());
+    }
+
+    #[test]
+    fn unsupported_values_are_rejected() {
+        assert!(to_entries("/Value", &1.0).is_err());
+        assert!(to_entries("/Value", &vec![1.0]).is_err());
+
+        #[derive(Serialize)]
+        enum WithData {
+            Speed(f64),
+        }
+        #[derive(Serialize)]
+        struct Holder {
+            value: WithData,
+        }
+        let holder = Holder {
+            value: WithData::Speed(1.0),
+        };
+        assert!(to_entries("/Holder", &holder).is_err());
+
+        let mut entries = BTreeMap::new();
+        entries.insert("/Gains/p".to_string(), EntryValue::Double(1.5));
+        entries.insert("/Gains/i".to_string(), EntryValue::Double(0.0));
+        #[derive(Debug, Deserialize)]
+        struct Counts {
+            #[allow(dead_code)]
+            p: u32,
+        }
+        assert!(from_entries::<Counts>("/Gains", &entries).is_err());
+    }
+}
@@ -0,0 +1,332 @@
+//! Reading and writing persistent entries in the `networktables.ini` format used by WPILib.
+//!
+//! Each entry is stored on its own line, after a header:
+//!
+//! ```text
+//! [NetworkTables Storage 3.0]
+//! double "/SmartDashboard/kP"=0.5
+//! array string "/SmartDashboard/Auto"="Left","Right"
+//! ```
+
+use crate::{EntryData, EntryValue, PERSISTENT_FLAG};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use tracing::warn;
+
+const HEADER: &str = "[NetworkTables Storage 3.0]";
+
+/// Reads the entries stored in `reader`, which are all flagged as persistent.
+///
+/// Lines that can't be parsed are skipped with a warning, as WPILib does. A missing header is an error.
+pub fn read_entries<R: BufRead>(reader: R) -> io::Result<Vec<EntryData>> {
+    let mut lines = reader.lines();
+    let header = lines.next().transpose()?;
+    if header.as_ref().map(|header| header.trim()) != Some(HEADER) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Missing NetworkTables storage header",
+        ));
+    }
+
+    let mut entries = Vec::new();
+    for (i, line) in lines.enumerate() {
+        let line = line?;
+        let line = line.trim();
+        // Blank lines and comments are allowed between entries
+        if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+            continue;
+        }
+
+        match parse_line(line) {
+            Some((name, value)) => entries.push(EntryData::new(name, PERSISTENT_FLAG, value)),
+            None => warn!(line = i + 2, "Skipping malformed persistent entry"),
+        }
+    }
+    Ok(entries)
+}
+
+/// Writes the given entries to `writer`, sorted by name.
+///
+/// RPC definitions can't be stored and are skipped. NT4 integer and float values are stored as doubles.
+pub fn write_entries<'a, W: Write>(
+    mut writer: W,
+    entries: impl IntoIterator<Item = &'a EntryData>,
+) -> io::Result<()> {
+    let mut entries = entries.into_iter().collect::<Vec<_>>();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+    writeln!(writer, "{}", HEADER)?;
+    for entry in entries {
+        let (ty, value) = match &entry.value {
+            EntryValue::Boolean(b) => ("boolean", bool_str(*b).to_string()),
+            EntryValue::Double(d) => ("double", d.to_string()),
+            EntryValue::Integer(i) => ("double", i.to_string()),
+            EntryValue::Float(f) => ("double", f.to_string()),
+            EntryValue::String(s) => ("string", quote(s)),
+            EntryValue::RawData(data) => ("raw", base64::encode(data)),
+            EntryValue::BooleanArray(v) => ("array boolean", join(v, |b| bool_str(*b).to_string())),
+            EntryValue::DoubleArray(v) => ("array double", join(v, f64::to_string)),
+            EntryValue::IntegerArray(v) => ("array double", join(v, i64::to_string)),
+            EntryValue::FloatArray(v) => ("array double", join(v, f32::to_string)),
+            EntryValue::StringArray(v) => ("array string", join(v, |s| quote(s))),
+            EntryValue::RpcDefinition(_) => continue,
+        };
+        writeln!(writer, "{} {}={}", ty, quote(&entry.name), value)?;
+    }
+    writer.flush()
+}
+
+/// Loads the entries stored in the file at `path`, or none if it doesn't exist
+pub fn load(path: &Path) -> io::Result<Vec<EntryData>> {
+    match File::open(path) {
+        Ok(file) => read_entries(BufReader::new(file)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Saves the given entries to the file at `path`.
+///
+/// The entries are written to a temporary file that then replaces `path`, so the file is never left half written.
+pub fn save<'a>(path: &Path, entries: impl IntoIterator<Item = &'a EntryData>) -> io::Result<()> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+
+    let file = File::create(&tmp)?;
+    write_entries(BufWriter::new(&file), entries)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+fn parse_line(line: &str) -> Option<(String, EntryValue)> {
+    // Array types are two words, like `array double`
+    let (ty, rest) = match line.strip_prefix("array ") {
+        Some(array) => {
+            let end = array.find(' ')?;
+            (&line[.."array ".len() + end], &array[end + 1..])
+        }
+        None => {
+            let end = line.find(' ')?;
+            (&line[..end], &line[end + 1..])
+        }
+    };
+
+    let (name, rest) = unquote(rest.trim_start())?;
+    let value = rest.trim_start().strip_prefix('=')?.trim();
+
+    let value = match ty {
+        "boolean" => EntryValue::Boolean(parse_bool(value)?),
+        "double" => EntryValue::Double(value.parse().ok()?),
+        "string" => match unquote(value)? {
+            (s, "") => EntryValue::String(s),
+            _ => return None,
+        },
+        "raw" => EntryValue::RawData(base64::decode(value).ok()?),
+        "array boolean" => EntryValue::BooleanArray(split(value, parse_bool)?),
+        "array double" => EntryValue::DoubleArray(split(value, |d| d.parse().ok())?),
+        "array string" => {
+            let mut strings = Vec::new();
+            let mut rest = value;
+            while !rest.is_empty() {
+                let (s, next) = unquote(rest)?;
+                strings.push(s);
+                rest = next.trim_start();
+                if !rest.is_empty() {
+                    rest = rest.strip_prefix(',')?.trim_start();
+                }
+            }
+            EntryValue::StringArray(strings)
+        }
+        _ => return None,
+    };
+    Some((name, value))
+}
+
+fn bool_str(b: bool) -> &'static str {
+    if b {
+        "true"
+    } else {
+        "false"
+    }
+}
+
+fn parse_bool(s: &str) -> Option<bool> {
+    match s.trim() {
+        "true" => Some(true),
+        "false" => Some(false),
+        _ => None,
+    }
+}
+
+fn join<T>(values: &[T], f: impl Fn(&T) -> String) -> String {
+    values.iter().map(f).collect::<Vec<_>>().join(",")
+}
+
+fn split<T>(s: &str, f: impl Fn(&str) -> Option<T>) -> Option<Vec<T>> {
+    if s.is_empty() {
+        return Some(Vec::new());
+    }
+    s.split(',').map(|part| f(part.trim())).collect()
+}
+
+/// Quotes and escapes a string. Bytes that aren't printable ASCII are written as `\xHH`.
+fn quote(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for b in s.bytes() {
+        match b {
+            b'\\' => quoted.push_str("\\\\"),
+            b'"' => quoted.push_str("\\\""),
+            b'\t' => quoted.push_str("\\t"),
+            b'\n' => quoted.push_str("\\n"),
+            0x20..=0x7e => quoted.push(b as char),
+            _ => quoted.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Parses a quoted string from the start of `s`, returning it along with the rest of `s`
+fn unquote(s: &str) -> Option<(String, &str)> {
+    let bytes = s.as_bytes();
+    if bytes.first() != Some(&b'"') {
+        return None;
+    }
+
+    let mut out = Vec::new();
+    let mut i = 1;
+    loop {
+        match *bytes.get(i)? {
+            b'"' => break,
+            b'\\' => {
+                i += 1;
+                match *bytes.get(i)? {
+                    b't' => out.push(b'\t'),
+                    b'n' => out.push(b'\n'),
+                    b'x' => {
+                        let hex = s.get(i + 1..i + 3)?;
+                        out.push(u8::from_str_radix(hex, 16).ok()?);
+                        i += 2;
+                    }
+                    b => out.push(b),
+                }
+            }
+            b => out.push(b),
+        }
+        i += 1;
+    }
+
+    Some((String::from_utf8(out).ok()?, &s[i + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn persistent(name: &str, value: EntryValue) -> EntryData {
+        EntryData::new(name.to_string(), PERSISTENT_FLAG, value)
+    }
+
+    fn round_trip(entries: &[EntryData]) -> Vec<EntryData> {
+        let mut buf = Vec::new();
+        write_entries(&mut buf, entries).unwrap();
+        read_entries(&buf[..]).unwrap()
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let entries = vec![
+            persistent("/a/boolean", EntryValue::Boolean(true)),
+            persistent("/b/double", EntryValue::Double(-0.25)),
+            persistent("/c/string", EntryValue::String("Hello".to_string())),
+            persistent("/d/raw", EntryValue::RawData(vec![0, 1, 0xfe, 0xff])),
+            persistent("/e/booleans", EntryValue::BooleanArray(vec![true, false])),
+            persistent("/f/doubles", EntryValue::DoubleArray(vec![1.5, 2.0, -3.0])),
+            persistent(
+                "/g/strings",
+                EntryValue::StringArray(vec!["Left".to_string(), "".to_string()]),
+            ),
+            persistent("/h/empty", EntryValue::DoubleArray(vec![])),
+        ];
+        assert_eq!(round_trip(&entries), entries);
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        let name = "/Smart \"Dashboard\"\\tab\there";
+        let value = "line\nbreak, \x01 and \u{e9}";
+        let entries = vec![persistent(name, EntryValue::String(value.to_string()))];
+        assert_eq!(round_trip(&entries), entries);
+
+        let mut buf = Vec::new();
+        write_entries(&mut buf, &entries).unwrap();
+        let text = String::from_utf8(buf).unwrap();
+        assert_eq!(text.lines().count(), 2);
+        assert!(text.contains("\\x01"));
+    }
+
+    #[test]
+    fn entries_are_written_sorted_after_the_header() {
+        let entries = vec![
+            persistent("/b", EntryValue::Boolean(false)),
+            persistent("/a", EntryValue::Double(1.0)),
+        ];
+        let mut buf = Vec::new();
+        write_entries(&mut buf, &entries).unwrap();
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "[NetworkTables Storage 3.0]\ndouble \"/a\"=1\nboolean \"/b\"=false\n"
+        );
+    }
+
+    #[test]
+    fn nt4_numbers_are_stored_as_doubles() {
+        let entries = vec![
+            persistent("/int", EntryValue::Integer(3)),
+            persistent("/ints", EntryValue::IntegerArray(vec![1, -2])),
+        ];
+        assert_eq!(
+            round_trip(&entries),
+            vec![
+                persistent("/int", EntryValue::Double(3.0)),
+                persistent("/ints", EntryValue::DoubleArray(vec![1.0, -2.0])),
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        let text = "[NetworkTables Storage 3.0]\n\
+                    ; a comment\n\
+                    \n\
+                    double \"/ok\"=2\n\
+                    double \"/bad\"=two\n\
+                    integer \"/unknown\"=1\n\
+                    string \"/unterminated=\"x\"\n";
+        assert_eq!(
+            read_entries(text.as_bytes()).unwrap(),
+            vec![persistent("/ok", EntryValue::Double(2.0))]
+        );
+    }
+
+    #[test]
+    fn a_missing_header_is_an_error() {
+        let err = read_entries("double \"/a\"=1\n".as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(read_entries("".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn files_round_trip() {
+        let path = std::env::temp_dir().join(format!("nt-persist-{}.ini", std::process::id()));
+        assert!(load(&path).unwrap().is_empty());
+
+        let entries = vec![persistent("/saved", EntryValue::Boolean(true))];
+        save(&path, &entries).unwrap();
+        let loaded = load(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.unwrap(), entries);
+    }
+}
@@ -1,13 +1,78 @@
-use crate::nt::{callback::CallbackType, EntryData};
+use crate::nt::{callback::CallbackType, subscription::Subscription, EntryData};
 use futures_channel::mpsc::Receiver;
+use futures_channel::oneshot;
+use futures_util::future::{abortable, AbortHandle, BoxFuture};
 use nt_network::types::EntryValue;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::thread;
+use tokio::runtime::Runtime;
+use tracing::error;
 
 pub mod client;
 pub mod server;
 #[cfg(feature = "websocket")]
 pub mod ws;
 
+/// The task running a connection, which completes once the connection has closed
+pub type ConnectionTask = BoxFuture<'static, crate::Result<()>>;
+
+/// Runs the task of a connection on a new thread with its own runtime, returning a receiver for the
+/// result of the task. Failures are logged too, as nothing may be waiting on the receiver.
+pub(crate) fn spawn_thread(task: ConnectionTask) -> oneshot::Receiver<crate::Result<()>> {
+    match Runtime::new() {
+        Ok(rt) => spawn_thread_on(rt, task),
+        Err(e) => {
+            error!(error = %e, "Failed to create the runtime of a connection");
+            let (result_tx, result_rx) = oneshot::channel();
+            let _ = result_tx.send(Err(e.into()));
+            result_rx
+        }
+    }
+}
+
+/// Like `spawn_thread`, but with a runtime created by the caller, so that a failure to create it can
+/// be returned to whoever is waiting for the connection
+pub(crate) fn spawn_thread_on(
+    mut rt: Runtime,
+    task: ConnectionTask,
+) -> oneshot::Receiver<crate::Result<()>> {
+    let (result_tx, result_rx) = oneshot::channel();
+    thread::spawn(move || {
+        let res = rt.block_on(task);
+        if let Err(e) = &res {
+            error!(error = %e, "Connection failed");
+        }
+        let _ = result_tx.send(res);
+    });
+    result_rx
+}
+
+/// Spawns a task that runs until the returned guard is dropped, for the tasks of a connection that
+/// would otherwise outlive it
+pub(crate) fn spawn_abortable(fut: impl Future<Output = ()> + Send + 'static) -> TaskGuard {
+    let (task, handle) = abortable(fut);
+    tokio::spawn(task);
+    TaskGuard(handle)
+}
+
+/// Aborts a task spawned by `spawn_abortable` when dropped
+pub(crate) struct TaskGuard(AbortHandle);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Held by tests that listen on or connect to the standard NT3 and NT4 ports, so that they don't run
+/// at the same time
+#[cfg(all(test, feature = "websocket"))]
+pub(crate) fn lock_standard_ports() -> std::sync::MutexGuard<'static, ()> {
+    static PORTS: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    PORTS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
 pub trait NTBackend {
     type State: State;
 }
@@ -25,21 +90,33 @@ impl NTBackend for Server {
 pub trait State {
     fn entries(&self) -> &HashMap<u16, EntryData>;
 
+    /// Entries can be modified in place, but must not be added, removed or renamed through this
     fn entries_mut(&mut self) -> &mut HashMap<u16, EntryData>;
 
+    /// The ids of the entries, indexed by name
+    fn entry_ids(&self) -> &BTreeMap<String, u16>;
+
     fn create_entry(&mut self, data: EntryData) -> crate::Result<Receiver<u16>>;
 
-    fn delete_entry(&mut self, id: u16);
+    /// Creates the entry unless one with the same name exists, in which case its value is kept.
+    ///
+    /// Clients wait until the handshake has completed, so that entries the server already has are known.
+    fn set_default(&mut self, data: EntryData) -> crate::Result<Receiver<u16>>;
+
+    fn delete_entry(&mut self, id: u16) -> crate::Result<()>;
 
-    fn update_entry(&mut self, id: u16, new_value: EntryValue);
+    fn update_entry(&mut self, id: u16, new_value: EntryValue) -> crate::Result<()>;
 
-    fn update_entry_flags(&mut self, id: u16, flags: u8);
+    fn update_entry_flags(&mut self, id: u16, flags: u8) -> crate::Result<()>;
 
-    fn clear_entries(&mut self);
+    fn clear_entries(&mut self) -> crate::Result<()>;
 
     fn add_callback(
         &mut self,
         callback_type: CallbackType,
         action: impl FnMut(&EntryData) + Send + 'static,
     );
+
+    /// Registers a subscription to changes of entries whose name starts with `prefix`
+    fn subscribe(&mut self, prefix: String) -> Subscription;
 }
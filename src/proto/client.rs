@@ -1,109 +1,248 @@
-use super::State;
+use self::supervisor::Backoff;
+use super::{ConnectionTask, State};
 use crate::error::Error;
+use crate::nt::connection::{ConnectionInfo, Protocol};
+use crate::nt::subscription::{
+    ConnectionEvent, ConnectionEvents, ConnectionSubscribers, Subscribers,
+};
 use crate::{
-    Action, CallbackType, ConnectionAction, ConnectionCallbackType, EntryData, EntryValue, Result,
-    RpcCallback,
+    Action, CallbackType, ConnectionAction, ConnectionCallbackType, EntryData, EntryEventKind,
+    EntryValue, EventSource, Result, RpcCallback, Subscription,
+};
+use futures_channel::mpsc::{
+    channel, unbounded, Receiver, Sender, UnboundedReceiver, UnboundedSender,
 };
-use futures_channel::mpsc::{channel, unbounded, Receiver, Sender, UnboundedSender};
 use futures_util::StreamExt;
 use multimap::MultiMap;
+use nt_network::types::EntryType;
 use nt_network::{
     ClearAllEntries, EntryAssignment, EntryDelete, EntryFlagsUpdate, EntryUpdate, Packet,
     RpcExecute,
 };
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::future::Future;
+use std::mem;
 use std::net::SocketAddr;
 use std::sync::{Arc, Mutex};
-use std::thread;
-use tokio::runtime::Runtime;
+use std::time::Instant;
+use tracing::{info, info_span, warn, Instrument, Span};
+
+/// Runs a connection, handing a failed handshake to the caller waiting on `ready_tx` rather than
+/// returning it
+async fn report_handshake(
+    connection: impl Future<Output = Result<()>>,
+    ready_tx: UnboundedSender<Result<()>>,
+) -> Result<()> {
+    match connection.await {
+        Err(e) => match ready_tx.unbounded_send(Err(e)) {
+            Ok(()) => Ok(()),
+            // Nobody is waiting for the handshake anymore, so the error is the connection's
+            Err(e) => e.into_inner(),
+        },
+        Ok(()) => Ok(()),
+    }
+}
 
 pub(crate) mod conn;
+#[cfg(feature = "websocket")]
+pub(crate) mod nt4;
+pub mod supervisor;
 
 pub struct ClientState {
     pub(crate) connected: bool,
     ip: String,
     name: String,
     entries: HashMap<u16, EntryData>,
+    entry_ids: BTreeMap<String, u16>,
     callbacks: MultiMap<CallbackType, Box<Action>>,
     connection_callbacks: MultiMap<ConnectionCallbackType, Box<ConnectionAction>>,
-    pub(crate) pending_entries: HashMap<String, Sender<u16>>,
+    /// Creations waiting for the server to assign an id, by entry name
+    pub(crate) pending_entries: HashMap<String, Vec<Sender<u16>>>,
     pub(crate) packet_tx: UnboundedSender<Box<dyn Packet>>,
     rpc_callbacks: HashMap<u16, Box<RpcCallback>>,
     next_rpc_id: u16,
+    start: Instant,
+    /// Offset between the server clock and `start`, in microseconds, once measured by an NT4 connection
+    pub(crate) time_offset: Option<i64>,
+    /// Entries assigned by the server during the handshake, merged with the existing ones once it completes
+    handshake_entries: HashMap<u16, EntryData>,
+    /// Names of the entries this client published on the previous NT4 connection
+    republish: HashSet<String>,
+    /// Values written while disconnected by entry name, which take precedence over the server's
+    /// once the connection is reestablished
+    dirty_entries: HashMap<String, EntryValue>,
+    /// Defaults set before the handshake completed, which are applied once the server's entries are known
+    queued_defaults: Vec<(EntryData, Sender<u16>)>,
+    /// Defaults sent to the server that are yet to be assigned an id, queued again if the connection is lost
+    sent_defaults: HashMap<String, EntryData>,
+    pub(crate) subscribers: Subscribers,
+    connection_subscribers: ConnectionSubscribers,
+    /// The remote end of the connection, while connected
+    connection: Option<ConnectionInfo>,
 }
 
 impl ClientState {
-    pub async fn new(
+    fn new_state(
         ip: String,
         name: String,
-        close_rx: Receiver<()>,
-    ) -> Result<Arc<Mutex<ClientState>>> {
-        let (packet_tx, packet_rx) = unbounded::<Box<dyn Packet>>();
-        let (ready_tx, mut ready_rx) = unbounded::<Result<()>>();
-
-        let state = Arc::new(Mutex::new(ClientState {
+        packet_tx: UnboundedSender<Box<dyn Packet>>,
+    ) -> Arc<Mutex<ClientState>> {
+        Arc::new(Mutex::new(ClientState {
             connected: false,
             ip,
             name,
             entries: HashMap::new(),
+            entry_ids: BTreeMap::new(),
             callbacks: MultiMap::new(),
             connection_callbacks: MultiMap::new(),
             pending_entries: HashMap::new(),
             packet_tx,
             rpc_callbacks: HashMap::new(),
             next_rpc_id: 0,
-        }));
-
-        let rt_state = Arc::clone(&state);
-        let ready_tx_clone = ready_tx.clone(); // clone ready_tx and move inside thread
-        thread::spawn(move || {
-            let mut rt = Runtime::new().unwrap();
-            if let Err(e) = rt.block_on(conn::connection(rt_state, packet_rx, ready_tx, close_rx)) {
-                /*
-                 * If unbounded_send panics, then there is no more memory
-                 * to send connection error info to main thread.
-                 */
-                ready_tx_clone.unbounded_send(Err(e)).unwrap();
-            }
-        });
+            start: Instant::now(),
+            time_offset: None,
+            handshake_entries: HashMap::new(),
+            republish: HashSet::new(),
+            dirty_entries: HashMap::new(),
+            queued_defaults: Vec::new(),
+            sent_defaults: HashMap::new(),
+            subscribers: Subscribers::default(),
+            connection_subscribers: ConnectionSubscribers::default(),
+            connection: None,
+        }))
+    }
 
-        ready_rx.next().await.unwrap()?;
-        Ok(state)
+    /// Connects over TCP to the given ip, returning the state once the handshake has completed along
+    /// with the result of running the connection task with `spawn`
+    pub async fn new<R>(
+        ip: String,
+        name: String,
+        close_rx: Receiver<()>,
+        spawn: impl FnOnce(ConnectionTask) -> R,
+    ) -> Result<(Arc<Mutex<ClientState>>, R)> {
+        ClientState::start(ip, name, close_rx, spawn, conn::connection).await
     }
 
     #[cfg(feature = "websocket")]
-    pub async fn new_ws(
+    pub async fn new_ws<R>(
         url: String,
         name: String,
         close_rx: Receiver<()>,
-    ) -> crate::Result<Arc<Mutex<ClientState>>> {
-        let (packet_tx, packet_rx) = unbounded::<Box<dyn Packet>>();
-        let (ready_tx, mut ready_rx) = unbounded::<()>();
+        spawn: impl FnOnce(ConnectionTask) -> R,
+    ) -> Result<(Arc<Mutex<ClientState>>, R)> {
+        ClientState::start(url, name, close_rx, spawn, conn::connection_ws).await
+    }
 
-        let state = Arc::new(Mutex::new(ClientState {
-            connected: false,
-            ip: url,
-            name,
-            entries: HashMap::new(),
-            callbacks: MultiMap::new(),
-            connection_callbacks: MultiMap::new(),
-            pending_entries: HashMap::new(),
-            packet_tx,
-            rpc_callbacks: HashMap::new(),
-            next_rpc_id: 0,
-        }));
+    /// Connects to an NT4 server on the given host, falling back to NT3 if the server rejects NT4
+    #[cfg(feature = "websocket")]
+    pub async fn new_nt4<R>(
+        host: String,
+        name: String,
+        close_rx: Receiver<()>,
+        spawn: impl FnOnce(ConnectionTask) -> R,
+    ) -> Result<(Arc<Mutex<ClientState>>, R)> {
+        ClientState::start(host, name, close_rx, spawn, nt4::connection).await
+    }
 
-        let rt_state = Arc::clone(&state);
-        thread::spawn(move || {
-            let mut rt = Runtime::new().unwrap();
+    async fn start<R, F>(
+        addr: String,
+        name: String,
+        close_rx: Receiver<()>,
+        spawn: impl FnOnce(ConnectionTask) -> R,
+        connection: impl FnOnce(
+            Arc<Mutex<ClientState>>,
+            UnboundedReceiver<Box<dyn Packet>>,
+            UnboundedSender<Result<()>>,
+            Receiver<()>,
+        ) -> F,
+    ) -> Result<(Arc<Mutex<ClientState>>, R)>
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        let (packet_tx, packet_rx) = unbounded::<Box<dyn Packet>>();
+        let (ready_tx, mut ready_rx) = unbounded::<Result<()>>();
+
+        let state = ClientState::new_state(addr, name, packet_tx);
 
-            let _ = rt.block_on(conn::connection_ws(rt_state, packet_rx, ready_tx, close_rx));
-        });
+        let span = state.lock().unwrap().span();
+        let conn = connection(Arc::clone(&state), packet_rx, ready_tx.clone(), close_rx);
+        let task = spawn(Box::pin(report_handshake(conn, ready_tx).instrument(span)));
 
-        if let None = ready_rx.next().await {
-            return Err(Error::ConnectionAborted);
+        match ready_rx.next().await {
+            Some(res) => res?,
+            None => return Err(Error::ConnectionAborted),
         }
-        Ok(state)
+        Ok((state, task))
+    }
+
+    /// Creates a state that is kept connected by a supervisor, which is run with `spawn`. The
+    /// state is returned right away, before the first connection has been made.
+    pub(crate) fn supervised<R, F>(
+        addr: String,
+        name: String,
+        backoff: Backoff,
+        close_rx: Receiver<()>,
+        reset: fn(&mut ClientState, UnboundedSender<Box<dyn Packet>>),
+        spawn: impl FnOnce(ConnectionTask) -> R,
+        connection: impl Fn(
+                Arc<Mutex<ClientState>>,
+                UnboundedReceiver<Box<dyn Packet>>,
+                UnboundedSender<Result<()>>,
+                Receiver<()>,
+            ) -> F
+            + Send
+            + 'static,
+    ) -> (Arc<Mutex<ClientState>>, R)
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        let (packet_tx, _) = unbounded::<Box<dyn Packet>>();
+        let state = ClientState::new_state(addr, name, packet_tx);
+        let span = state.lock().unwrap().span();
+        let task = spawn(Box::pin(
+            supervisor::supervise(Arc::clone(&state), backoff, close_rx, reset, connection)
+                .instrument(span),
+        ));
+        (state, task)
+    }
+
+    /// Starts a new connection for an existing state, once `reset` has prepared it with the new
+    /// packet channel. Waits for the handshake, and returns the result of running the connection
+    /// task with `spawn`.
+    pub(crate) async fn restart<R, F>(
+        state: &Arc<Mutex<ClientState>>,
+        close_rx: Receiver<()>,
+        reset: fn(&mut ClientState, UnboundedSender<Box<dyn Packet>>),
+        spawn: impl FnOnce(ConnectionTask) -> R,
+        connection: impl FnOnce(
+            Arc<Mutex<ClientState>>,
+            UnboundedReceiver<Box<dyn Packet>>,
+            UnboundedSender<Result<()>>,
+            Receiver<()>,
+        ) -> F,
+    ) -> R
+    where
+        F: Future<Output = Result<()>> + Send + 'static,
+    {
+        let (packet_tx, packet_rx) = unbounded::<Box<dyn Packet>>();
+        let (ready_tx, mut ready_rx) = unbounded::<Result<()>>();
+
+        let span = {
+            let mut state = state.lock().unwrap();
+            reset(&mut state, packet_tx);
+            state.span()
+        };
+
+        let conn = connection(Arc::clone(state), packet_rx, ready_tx.clone(), close_rx);
+        let task = spawn(Box::pin(report_handshake(conn, ready_tx).instrument(span)));
+
+        let _ = ready_rx.next().await;
+        task
+    }
+
+    /// The span that the connections of this client are run in
+    fn span(&self) -> Span {
+        info_span!("client", server = %self.ip, name = %self.name)
     }
 
     pub fn add_connection_callback(
@@ -115,19 +254,436 @@ impl ClientState {
             .insert(callback_type, Box::new(action));
     }
 
+    /// Returns a `Stream` of the changes to the connection
+    pub fn connection_events(&mut self) -> ConnectionEvents {
+        self.connection_subscribers.subscribe()
+    }
+
+    pub(crate) fn notify_connection(&mut self, event: ConnectionEvent) {
+        self.connection_subscribers.notify(event);
+    }
+
+    /// Marks the handshake with the server as complete, telling the connection callbacks and events
+    pub(crate) fn set_connected(
+        &mut self,
+        addr: SocketAddr,
+        server_name: String,
+        protocol: Protocol,
+    ) {
+        info!(%addr, %server_name, ?protocol, "Connected");
+        self.connected = true;
+        self.connection = Some(ConnectionInfo::new(addr, server_name, protocol));
+        self.connection_callbacks
+            .iter_all_mut()
+            .filter(|(cb, _)| **cb == ConnectionCallbackType::ClientConnected)
+            .flat_map(|(_, cbs)| cbs)
+            .for_each(|cb| cb(&addr));
+        self.notify_connection(ConnectionEvent::Connected { addr });
+    }
+
+    /// Marks an established connection as lost for the given reason, telling the connection
+    /// callbacks and events
+    pub(crate) fn set_disconnected(&mut self, reason: String) {
+        warn!(%reason, "Connection lost");
+        self.connected = false;
+        if let Some(ConnectionInfo { addr, .. }) = self.connection.take() {
+            self.connection_callbacks
+                .iter_all_mut()
+                .filter(|(cb, _)| **cb == ConnectionCallbackType::ClientDisconnected)
+                .flat_map(|(_, cbs)| cbs)
+                .for_each(|cb| cb(&addr));
+        }
+        self.notify_connection(ConnectionEvent::Disconnected { reason });
+    }
+
+    /// Information about the server, while connected
+    pub fn connection_info(&self) -> Option<&ConnectionInfo> {
+        self.connection.as_ref()
+    }
+
+    /// Notes that a packet was just received from the server
+    pub(crate) fn mark_received(&mut self) {
+        if let Some(connection) = &mut self.connection {
+            connection.last_received = Instant::now();
+        }
+    }
+
+    /// Microseconds elapsed on the local clock since this state was created
+    pub(crate) fn local_time(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    /// The current server time in microseconds, if the clock has been synchronized with the server
+    pub fn server_time(&self) -> Option<u64> {
+        self.time_offset
+            .map(|offset| (self.local_time() as i64 + offset).max(0) as u64)
+    }
+
+    /// Prepares the state for a new connection that will send packets to `packet_tx`.
+    ///
+    /// Existing entries stay readable and writable while the new connection is made, and are merged
+    /// with the server's by `merge_entries` once the handshake completes.
+    pub(crate) fn reset_connection(&mut self, packet_tx: UnboundedSender<Box<dyn Packet>>) {
+        self.packet_tx = packet_tx;
+        self.connected = false;
+        self.connection = None;
+        self.clear_pending();
+        self.handshake_entries.clear();
+    }
+
+    /// Prepares the state for a new NT4 connection.
+    ///
+    /// Existing entries are kept until the new connection is ready, when `merge_nt4_entries`
+    /// reconciles them with the topics the server has.
+    #[cfg(feature = "websocket")]
+    pub(crate) fn reset_nt4_connection(&mut self, packet_tx: UnboundedSender<Box<dyn Packet>>) {
+        self.reset_connection(packet_tx);
+        self.time_offset = None;
+    }
+
+    /// Remembers the entries with the given names, to be published again by the next NT4
+    /// connection if the server no longer has them
+    #[cfg(feature = "websocket")]
+    pub(crate) fn keep_published<'a>(&mut self, names: impl Iterator<Item = &'a String>) {
+        self.republish.extend(names.cloned());
+    }
+
+    /// Notes an entry the server assigned during the handshake, to be merged by `merge_entries`
+    pub(crate) fn insert_handshake_entry(&mut self, id: u16, data: EntryData) {
+        self.handshake_entries.insert(id, data);
+    }
+
+    /// Merges the existing entries with the ones sent by the server during the handshake, as
+    /// described in the NT3 spec.
+    ///
+    /// Entries the server doesn't know about are assigned again, and stay readable under their old
+    /// id until the server has assigned them a new one. For entries both sides have, the server's
+    /// value is kept if it has seen this client before, unless the entry was updated while
+    /// disconnected. Otherwise the server has restarted, and the local values are sent to it.
+    pub(crate) fn merge_entries(&mut self, server_seen: bool) {
+        let local = mem::take(&mut self.entries);
+        self.entry_ids.clear();
+        for (id, data) in mem::take(&mut self.handshake_entries) {
+            self.entry_ids.insert(data.name.clone(), id);
+            self.entries.insert(id, data);
+        }
+        let local_names = local
+            .values()
+            .map(|entry| entry.name.clone())
+            .collect::<HashSet<_>>();
+
+        for (local_id, mut local) in local {
+            let dirty = match self.dirty_entries.remove(&local.name) {
+                Some(value) => {
+                    local.value = value;
+                    true
+                }
+                None => false,
+            };
+            let entries = &mut self.entries;
+            let existing = self
+                .entry_ids
+                .get(&local.name)
+                .and_then(|id| Some((*id, entries.get_mut(id)?)));
+
+            let (id, entry) = match existing {
+                Some(existing) => existing,
+                None => {
+                    // Defaults for the same name wait for this assignment instead of sending their own
+                    self.pending_entries.entry(local.name.clone()).or_default();
+                    let _ = self.packet_tx.unbounded_send(Box::new(EntryAssignment::new(
+                        local.name.clone(),
+                        local.entry_type(),
+                        0xFFFF,
+                        local.seqnum.0,
+                        local.flags,
+                        local.value.clone(),
+                    )));
+                    if !self.entries.contains_key(&local_id) {
+                        self.entry_ids.insert(local.name.clone(), local_id);
+                        self.entries.insert(local_id, local);
+                    }
+                    continue;
+                }
+            };
+
+            if entry.entry_type() != local.entry_type() || (server_seen && !dirty) {
+                // The server's entry stands
+                if entry.value != local.value {
+                    let entry = entry.clone();
+                    self.fire_callbacks(CallbackType::Update, &entry);
+                    self.subscribers.notify(
+                        EntryEventKind::Updated,
+                        id,
+                        Some(&local),
+                        Some(&entry),
+                        EventSource::Remote,
+                    );
+                }
+                continue;
+            }
+
+            // The local entry stands, and is sent to the server
+            if entry.flags != local.flags {
+                let _ = self
+                    .packet_tx
+                    .unbounded_send(Box::new(EntryFlagsUpdate::new(id, local.flags)));
+            }
+            let seqnum = if entry.value != local.value {
+                let seqnum = entry.seqnum.next();
+                let _ = self.packet_tx.unbounded_send(Box::new(EntryUpdate::new(
+                    id,
+                    seqnum.0,
+                    local.entry_type(),
+                    local.value.clone(),
+                )));
+                seqnum
+            } else {
+                entry.seqnum
+            };
+            *entry = EntryData { seqnum, ..local };
+        }
+
+        // Entries only the server has are new to this client
+        let added = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| !local_names.contains(&entry.name))
+            .map(|(id, entry)| (*id, entry.clone()))
+            .collect::<Vec<_>>();
+        for (id, data) in added {
+            self.fire_callbacks(CallbackType::Add, &data);
+            self.subscribers.notify(
+                EntryEventKind::Added,
+                id,
+                None,
+                Some(&data),
+                EventSource::Remote,
+            );
+        }
+
+        self.republish.clear();
+        self.dirty_entries.clear();
+    }
+
+    /// Reconciles the existing entries with the topics announced by a new NT4 connection, once it is
+    /// ready.
+    ///
+    /// Entries the server doesn't have are published again if this client published them or
+    /// updated them while disconnected, and are removed otherwise. Values updated while
+    /// disconnected are sent to the server.
+    #[cfg(feature = "websocket")]
+    pub(crate) fn merge_nt4_entries(&mut self, announced: impl Fn(u16) -> bool) {
+        let republish = mem::take(&mut self.republish);
+        let mut dirty = mem::take(&mut self.dirty_entries);
+        let existing = self
+            .entries
+            .iter()
+            .map(|(id, entry)| (*id, entry.clone()))
+            .collect::<Vec<_>>();
+
+        for (id, mut entry) in existing {
+            let is_dirty = match dirty.remove(&entry.name) {
+                Some(value) => {
+                    entry.value = value;
+                    true
+                }
+                None => false,
+            };
+            let packet: Box<dyn Packet> = if announced(id) {
+                if !is_dirty {
+                    continue;
+                }
+                Box::new(EntryUpdate::new(
+                    id,
+                    entry.seqnum.0,
+                    entry.entry_type(),
+                    entry.value,
+                ))
+            } else if republish.contains(&entry.name) || is_dirty {
+                self.pending_entries.entry(entry.name.clone()).or_default();
+                let entry_type = entry.entry_type();
+                Box::new(EntryAssignment::new(
+                    entry.name,
+                    entry_type,
+                    0xFFFF,
+                    entry.seqnum.0,
+                    entry.flags,
+                    entry.value,
+                ))
+            } else {
+                self.remove_entry(id, EventSource::Remote);
+                continue;
+            };
+            let _ = self.packet_tx.unbounded_send(packet);
+        }
+    }
+
+    /// Applies the defaults set while the handshake was in progress, now that the server's entries are known
+    pub(crate) fn apply_defaults(&mut self) {
+        for (data, tx) in mem::take(&mut self.queued_defaults) {
+            self.request_default(data, tx);
+        }
+    }
+
+    fn request_default(&mut self, data: EntryData, tx: Sender<u16>) {
+        let default = data.clone();
+        self.request_entry(data, tx);
+        if self.pending_entries.contains_key(&default.name) {
+            self.sent_defaults
+                .entry(default.name.clone())
+                .or_insert(default);
+        }
+    }
+
+    /// Drops the creations waiting on the previous connection, queueing the defaults among them to
+    /// be applied again on the next one
+    fn clear_pending(&mut self) {
+        for (name, data) in self.sent_defaults.drain() {
+            for tx in self.pending_entries.remove(&name).unwrap_or_default() {
+                self.queued_defaults.push((data.clone(), tx));
+            }
+        }
+        self.pending_entries.clear();
+    }
+
+    /// Completes `tx` with the id of the entry named in `data`, asking the server to create it if
+    /// it doesn't exist yet
+    fn request_entry(&mut self, data: EntryData, mut tx: Sender<u16>) {
+        if let Some(id) = self.entry_ids.get(&data.name) {
+            let _ = tx.try_send(*id);
+            return;
+        }
+
+        // Concurrent creations of the same name share the assignment sent by the first one
+        let in_flight = self.pending_entries.contains_key(&data.name);
+        self.pending_entries
+            .entry(data.name.clone())
+            .or_default()
+            .push(tx);
+        if in_flight {
+            return;
+        }
+
+        // If the connection has gone, the creation is retried on the next one
+        let _ = self.packet_tx.unbounded_send(Box::new(EntryAssignment::new(
+            data.name.clone(),
+            data.entry_type(),
+            0xFFFF,
+            data.seqnum.0,
+            data.flags,
+            data.value,
+        )));
+    }
+
+    /// Completes the pending creations of the entry with the given name, returning whether there were any
+    pub(crate) fn resolve_pending(&mut self, name: &str, id: u16) -> bool {
+        self.sent_defaults.remove(name);
+        match self.pending_entries.remove(name) {
+            Some(pending) => {
+                for mut tx in pending {
+                    let _ = tx.try_send(id);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Whether there is an entry with the given name
+    pub(crate) fn has_entry(&self, name: &str) -> bool {
+        self.entry_ids.contains_key(name)
+    }
+
+    /// Adds an entry received from the server. An entry kept from a previous connection under
+    /// another id is moved to the new one.
+    pub(crate) fn insert_entry(&mut self, id: u16, data: EntryData) {
+        let name = data.name.clone();
+        if let Some(old_id) = self.entry_ids.insert(name.clone(), id) {
+            if old_id != id {
+                self.entries.remove(&old_id);
+            }
+        }
+        if let Some(old) = self.entries.insert(id, data) {
+            // The server reassigned the id to another name
+            if name != old.name && self.entry_ids.get(&old.name) == Some(&id) {
+                self.entry_ids.remove(&old.name);
+            }
+        }
+    }
+
+    /// Removes an entry locally, without telling the server
+    pub(crate) fn remove_entry(&mut self, id: u16, source: EventSource) {
+        if let Some(data) = self.entries.remove(&id) {
+            self.entry_ids.remove(&data.name);
+            self.fire_callbacks(CallbackType::Delete, &data);
+            self.subscribers
+                .notify(EntryEventKind::Deleted, id, Some(&data), None, source);
+        }
+    }
+
+    /// Removes every entry locally, without telling the server
+    pub(crate) fn remove_all_entries(&mut self, source: EventSource) {
+        self.entry_ids.clear();
+        for (id, data) in self.entries.drain() {
+            self.subscribers
+                .notify(EntryEventKind::Cleared, id, Some(&data), None, source);
+        }
+    }
+
+    pub(crate) fn fire_callbacks(&mut self, callback_type: CallbackType, data: &EntryData) {
+        self.callbacks
+            .iter_all_mut()
+            .filter(|(cb, _)| **cb == callback_type)
+            .flat_map(|(_, cbs)| cbs)
+            .for_each(|cb| cb(data));
+    }
+
+    /// Calls the RPC with the given id, with `callback` receiving its result.
+    ///
+    /// RPCs are only available on NT3 connections.
     pub fn call_rpc(
         &mut self,
         id: u16,
         parameter: Vec<u8>,
         callback: impl Fn(Vec<u8>) + Send + 'static,
-    ) {
+    ) -> crate::Result<()> {
+        let found = self
+            .entries
+            .get(&id)
+            .map(EntryData::entry_type)
+            .ok_or(Error::EntryNotFound { id })?;
+        if found != EntryType::RpcDefinition {
+            return Err(Error::TypeMismatch {
+                expected: EntryType::RpcDefinition,
+                found,
+            });
+        }
+        match self.connection.as_ref().map(|conn| conn.protocol) {
+            Some(Protocol::NT3) => {}
+            Some(protocol) => {
+                return Err(Error::RpcFailed {
+                    reason: format!("{:?} connections have no RPCs", protocol),
+                })
+            }
+            None => return Err(Error::Disconnected),
+        }
+
+        self.send(RpcExecute::new(id, self.next_rpc_id, parameter))?;
         self.rpc_callbacks
             .insert(self.next_rpc_id, Box::new(callback));
-        self.packet_tx
-            .unbounded_send(Box::new(RpcExecute::new(id, self.next_rpc_id, parameter)))
-            .unwrap();
+        self.next_rpc_id = self.next_rpc_id.wrapping_add(1);
+        Ok(())
+    }
 
-        self.next_rpc_id += 1;
+    /// Sends a packet to the server, failing if the connection isn't established
+    fn send(&self, packet: impl Packet + 'static) -> crate::Result<()> {
+        if !self.connected {
+            return Err(Error::Disconnected);
+        }
+        self.packet_tx
+            .unbounded_send(Box::new(packet))
+            .map_err(|_| Error::Disconnected)
     }
 }
 
@@ -140,59 +696,95 @@ impl State for ClientState {
         &mut self.entries
     }
 
+    fn entry_ids(&self) -> &BTreeMap<String, u16> {
+        &self.entry_ids
+    }
+
     fn create_entry(&mut self, data: EntryData) -> crate::Result<Receiver<u16>> {
-        if !self.connected {
-            return Err(Error::BrokenPipe);
+        if !self.connected && !self.entry_ids.contains_key(&data.name) {
+            return Err(Error::Disconnected);
         }
+
         let (tx, rx) = channel::<u16>(1);
-        self.pending_entries.insert(data.name.clone(), tx);
-        self.packet_tx
-            .unbounded_send(Box::new(EntryAssignment::new(
-                data.name.clone(),
-                data.entry_type(),
-                0xFFFF,
-                data.seqnum,
-                data.flags,
-                data.value,
-            )))
-            .unwrap();
+        self.request_entry(data, tx);
         Ok(rx)
     }
 
-    fn delete_entry(&mut self, id: u16) {
-        let packet = EntryDelete::new(id);
-        self.packet_tx.unbounded_send(Box::new(packet)).unwrap();
+    fn set_default(&mut self, data: EntryData) -> crate::Result<Receiver<u16>> {
+        let (tx, rx) = channel::<u16>(1);
+        if self.connected {
+            self.request_default(data, tx);
+        } else {
+            self.queued_defaults.push((data, tx));
+        }
+        Ok(rx)
     }
 
-    fn update_entry(&mut self, id: u16, new_value: EntryValue) {
-        if let Some(entry) = self.entries.get_mut(&id) {
-            entry.value = new_value.clone();
-            entry.seqnum += 1;
-            self.packet_tx
-                .unbounded_send(Box::new(EntryUpdate::new(
-                    id,
-                    entry.seqnum,
-                    entry.entry_type(),
-                    new_value,
-                )))
-                .unwrap();
+    fn delete_entry(&mut self, id: u16) -> crate::Result<()> {
+        if !self.entries.contains_key(&id) {
+            return Err(Error::EntryNotFound { id });
         }
+        self.send(EntryDelete::new(id))?;
+        self.remove_entry(id, EventSource::Local);
+        Ok(())
     }
 
-    fn update_entry_flags(&mut self, id: u16, flags: u8) {
+    fn update_entry(&mut self, id: u16, new_value: EntryValue) -> crate::Result<()> {
+        let now = self.server_time();
+        let entry = self
+            .entries
+            .get_mut(&id)
+            .ok_or(Error::EntryNotFound { id })?;
+        if entry.entry_type() != new_value.entry_type() {
+            return Err(Error::TypeMismatch {
+                expected: entry.entry_type(),
+                found: new_value.entry_type(),
+            });
+        }
+        let old = entry.clone();
+        entry.value = new_value.clone();
+        entry.timestamp = now;
+        entry.seqnum = entry.seqnum.next();
+        self.subscribers.notify(
+            EntryEventKind::Updated,
+            id,
+            Some(&old),
+            Some(entry),
+            EventSource::Local,
+        );
+
+        let packet = EntryUpdate::new(id, entry.seqnum.0, entry.entry_type(), new_value.clone());
+        // The update is sent to the server when the connection is reestablished
+        if !self.connected || self.packet_tx.unbounded_send(Box::new(packet)).is_err() {
+            self.dirty_entries.insert(entry.name.clone(), new_value);
+        }
+        Ok(())
+    }
+
+    fn update_entry_flags(&mut self, id: u16, flags: u8) -> crate::Result<()> {
+        if !self.entries.contains_key(&id) {
+            return Err(Error::EntryNotFound { id });
+        }
+        self.send(EntryFlagsUpdate::new(id, flags))?;
+
         if let Some(entry) = self.entries.get_mut(&id) {
-            entry.flags = flags;
-            self.packet_tx
-                .unbounded_send(Box::new(EntryFlagsUpdate::new(id, flags)))
-                .unwrap();
+            let old = entry.clone();
+            entry.set_flags(flags);
+            self.subscribers.notify(
+                EntryEventKind::FlagsChanged,
+                id,
+                Some(&old),
+                Some(entry),
+                EventSource::Local,
+            );
         }
+        Ok(())
     }
 
-    fn clear_entries(&mut self) {
-        self.packet_tx
-            .unbounded_send(Box::new(ClearAllEntries::new()))
-            .unwrap();
-        self.entries.clear();
+    fn clear_entries(&mut self) -> crate::Result<()> {
+        self.send(ClearAllEntries::new())?;
+        self.remove_all_entries(EventSource::Local);
+        Ok(())
     }
 
     fn add_callback(
@@ -202,4 +794,70 @@ impl State for ClientState {
     ) {
         self.callbacks.insert(callback_type, Box::new(action));
     }
+
+    fn subscribe(&mut self, prefix: String) -> Subscription {
+        self.subscribers.subscribe(prefix)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nt_network::ReceivedPacket;
+
+    #[test]
+    fn values_set_during_an_outage_are_sent_after_the_handshake() {
+        let (packet_tx, _) = unbounded();
+        let state = ClientState::new_state("127.0.0.1".to_string(), "test".to_string(), packet_tx);
+        let mut state = state.lock().unwrap();
+        let addr = "127.0.0.1:1735".parse().unwrap();
+
+        let (packet_tx, _packet_rx) = unbounded();
+        state.reset_connection(packet_tx);
+        let data = EntryData::new("/value".to_string(), 0, EntryValue::Double(1.0));
+        state.insert_handshake_entry(1, data.clone());
+        state.merge_entries(false);
+        state.set_connected(addr, "server".to_string(), Protocol::NT3);
+
+        // The value is written after the connection is lost and before the next handshake completes
+        state.set_disconnected("The server closed the connection".to_string());
+        let (packet_tx, mut packet_rx) = unbounded();
+        state.reset_connection(packet_tx);
+        state.update_entry(1, EntryValue::Double(2.0)).unwrap();
+        assert_eq!(state.entries()[&1].value, EntryValue::Double(2.0));
+
+        // The server remembers the old value, under another id
+        state.insert_handshake_entry(4, data);
+        state.merge_entries(true);
+        state.set_connected(addr, "server".to_string(), Protocol::NT3);
+
+        match packet_rx.try_recv().unwrap().to_received() {
+            Some(ReceivedPacket::EntryUpdate(update)) => {
+                assert_eq!(update.entry_id, 4);
+                assert_eq!(update.entry_value, EntryValue::Double(2.0));
+            }
+            _ => panic!("expected an entry update"),
+        }
+        assert_eq!(state.entries()[&4].value, EntryValue::Double(2.0));
+        assert!(!state.entries().contains_key(&1));
+    }
+
+    #[test]
+    fn updates_must_keep_the_entry_type() {
+        let (packet_tx, _packet_rx) = unbounded();
+        let state = ClientState::new_state("127.0.0.1".to_string(), "test".to_string(), packet_tx);
+        let mut state = state.lock().unwrap();
+        let data = EntryData::new("/value".to_string(), 0, EntryValue::Double(1.0));
+        state.insert_handshake_entry(1, data);
+        state.merge_entries(false);
+
+        match state.update_entry(1, EntryValue::Boolean(true)) {
+            Err(Error::TypeMismatch { expected, found }) => {
+                assert_eq!(expected, EntryType::Double);
+                assert_eq!(found, EntryType::Boolean);
+            }
+            res => panic!("expected a type mismatch, got {:?}", res),
+        }
+        assert_eq!(state.entries()[&1].value, EntryValue::Double(1.0));
+    }
 }
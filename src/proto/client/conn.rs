@@ -1,23 +1,32 @@
 use crate::error::Error;
+use crate::nt::connection::Protocol;
 use crate::proto::client::ClientState;
+use crate::proto::spawn_abortable;
 #[cfg(feature = "websocket")]
 use crate::proto::ws::WSCodec;
-use crate::proto::State;
 use crate::Result;
-use crate::{CallbackType, ConnectionCallbackType, EntryData};
+use crate::{CallbackType, EntryData, EntryEventKind, EventSource};
 use futures_channel::mpsc::{Receiver, UnboundedReceiver, UnboundedSender};
-use futures_util::future::Either;
+use futures_util::future::{self, Either};
+use futures_util::pin_mut;
 use futures_util::sink::SinkExt;
-use futures_util::stream::select;
+use futures_util::stream::{self, select};
 use futures_util::StreamExt;
 use nt_network::codec::NTCodec;
-use nt_network::{ClientHello, ClientHelloComplete, KeepAlive, NTVersion, Packet, ReceivedPacket};
+use nt_network::nt2::NT2Codec;
+use nt_network::{
+    ClientHello, ClientHelloComplete, DecodeError, EncodeError, KeepAlive, NTVersion, Packet,
+    ReceivedPacket, SequenceNumber,
+};
+use std::future::Future;
+use std::mem;
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use tokio::net::TcpStream;
 #[cfg(feature = "websocket")]
 use tokio_tungstenite::tungstenite::handshake::client::Request;
-use tokio_util::codec::Decoder;
+use tokio_util::codec::{Decoder, Encoder, Framed};
+use tracing::{debug, info, warn};
 #[cfg(feature = "websocket")]
 use url::Url;
 
@@ -27,42 +36,131 @@ pub async fn connection(
     ready_tx: UnboundedSender<Result<()>>,
     close_rx: Receiver<()>,
 ) -> crate::Result<()> {
-    let (ip, client_name) = {
-        let state = state.lock().unwrap();
-        (state.ip.clone(), state.name.clone())
-    };
-    let conn = TcpStream::connect(ip).await?;
-    let addr = conn.local_addr().unwrap();
-    let (mut tx, mut rx) = NTCodec.framed(conn).split();
+    let ip = state.lock().unwrap().ip.clone();
+    connection_to(ip, state, packet_rx, ready_tx, close_rx).await
+}
+
+/// Connects over TCP to the given ip, rather than the one stored in the state
+pub async fn connection_to(
+    ip: String,
+    state: Arc<Mutex<ClientState>>,
+    packet_rx: UnboundedReceiver<Box<dyn Packet>>,
+    ready_tx: UnboundedSender<Result<()>>,
+    close_rx: Receiver<()>,
+) -> crate::Result<()> {
+    let client_name = state.lock().unwrap().name.clone();
+    let mut conn = NTCodec::default().framed(TcpStream::connect(&ip).await?);
+    conn.send(Box::new(ClientHello::new(NTVersion::V3, client_name)))
+        .await?;
+    debug!("Sent ClientHello");
+
+    match conn.next().await {
+        // NT2 servers reject the hello, so the connection is retried speaking NT2
+        Some(Ok(ReceivedPacket::ProtocolVersionUnsupported(pvu)))
+            if pvu.supported_version == NTVersion::V2 as u16 =>
+        {
+            info!("The server only speaks NT2, reconnecting with NT2");
+            let mut conn = NT2Codec::default().framed(TcpStream::connect(&ip).await?);
+            conn.send(Box::new(ClientHello::new(NTVersion::V2, String::new())))
+                .await?;
+            run_connection(
+                conn,
+                Protocol::NT2,
+                None,
+                state,
+                packet_rx,
+                ready_tx,
+                close_rx,
+            )
+            .await
+        }
+        Some(Ok(ReceivedPacket::ProtocolVersionUnsupported(pvu))) => {
+            warn!(
+                supported_version = pvu.supported_version,
+                "The server doesn't support NT3"
+            );
+            Err(Error::UnsupportedProtocolVersion {
+                supported_version: NTVersion::from_u16(pvu.supported_version)?,
+            })
+        }
+        Some(Ok(packet)) => {
+            run_connection(
+                conn,
+                Protocol::NT3,
+                Some(packet),
+                state,
+                packet_rx,
+                ready_tx,
+                close_rx,
+            )
+            .await
+        }
+        Some(Err(e)) => Err(e.into()),
+        None => Err(Error::ConnectionAborted),
+    }
+}
+
+/// Drives a connection speaking the given protocol once the client hello has been sent, handling
+/// `first` before anything else that is received
+async fn run_connection<C>(
+    conn: Framed<TcpStream, C>,
+    protocol: Protocol,
+    first: Option<ReceivedPacket>,
+    state: Arc<Mutex<ClientState>>,
+    packet_rx: UnboundedReceiver<Box<dyn Packet>>,
+    ready_tx: UnboundedSender<Result<()>>,
+    close_rx: Receiver<()>,
+) -> crate::Result<()>
+where
+    C: Encoder<Item = Box<dyn Packet>, Error = EncodeError>
+        + Decoder<Item = ReceivedPacket, Error = DecodeError>
+        + Send
+        + 'static,
+{
+    let addr = conn.get_ref().peer_addr()?;
+    let (mut tx, rx) = conn.split();
+    let mut rx = stream::iter(first.map(Ok)).chain(rx);
 
     let rx_state = Arc::clone(&state);
-    tokio::spawn(async move {
+    let reader = async move {
+        // NT2 servers don't send a ServerHello, and never recognize returning clients
+        let mut server_seen = false;
+        let mut server_name = String::new();
         while let Some(msg) = rx.next().await {
-            if let Ok(packet) = msg {
-                match packet {
-                    ReceivedPacket::ServerHelloComplete => {
-                        ready_tx.unbounded_send(Ok(())).unwrap();
-                        let mut state = rx_state.lock().unwrap();
-                        state
-                            .connection_callbacks
-                            .iter_all_mut()
-                            .filter(|(cb, _)| **cb == ConnectionCallbackType::ClientConnected)
-                            .flat_map(|(_, cbs)| cbs)
-                            .for_each(|cb| cb(&addr));
-                        state.connected = true;
-                        state
-                            .packet_tx
-                            .unbounded_send(Box::new(ClientHelloComplete))
-                            .unwrap();
+            let packet = match msg {
+                Ok(packet) => packet,
+                Err(e) => return Ended::Lost(e.into()),
+            };
+            match packet {
+                ReceivedPacket::ServerHello(hello) => {
+                    server_seen = hello.flags & 1 != 0;
+                    debug!(server_name = %hello.name, server_seen, "Received ServerHello");
+                    server_name = hello.name;
+                }
+                ReceivedPacket::ServerHelloComplete => {
+                    debug!("Received ServerHelloComplete");
+                    let mut state = rx_state.lock().unwrap();
+                    state.merge_entries(server_seen);
+                    state.apply_defaults();
+                    state.set_connected(addr, mem::take(&mut server_name), protocol);
+                    let _ = state
+                        .packet_tx
+                        .unbounded_send(Box::new(ClientHelloComplete));
+                    // Only signal readiness once the state reflects the connection
+                    let _ = ready_tx.unbounded_send(Ok(()));
+                }
+                packet => {
+                    if let Err(e) = handle_packet(packet, &rx_state) {
+                        return Ended::Lost(e);
                     }
-                    packet => handle_packet(packet, &rx_state).unwrap(),
                 }
             }
         }
-    });
+        Ended::Lost(Error::ConnectionClosed)
+    };
 
     let tick_state = Arc::clone(&state);
-    tokio::spawn(async move {
+    let _keepalive = spawn_abortable(async move {
         let mut interval = tokio::time::interval(Duration::new(1, 0));
 
         loop {
@@ -76,48 +174,65 @@ pub async fn connection(
     });
 
     let mut rx = select(packet_rx.map(Either::Left), close_rx.map(Either::Right));
-
-    let tx_state = Arc::clone(&state);
-    tx.send(Box::new(ClientHello::new(NTVersion::V3, client_name)))
-        .await
-        .unwrap();
-    while let Some(msg) = rx.next().await {
-        match msg {
-            Either::Left(packet) => {
-                match tx.send(packet).await {
-                    Ok(_) => {}
-                    Err(e) => {
-                        if let Ok(e) = e.downcast::<std::io::Error>() {
-                            if e.kind() == std::io::ErrorKind::BrokenPipe {
-                                // connection terminated
-                                let mut state = tx_state.lock().unwrap();
-                                state
-                                    .connection_callbacks
-                                    .iter_all_mut()
-                                    .filter(|(cb, _)| {
-                                        **cb == ConnectionCallbackType::ClientDisconnected
-                                    })
-                                    .flat_map(|(_, cbs)| cbs)
-                                    .for_each(|cb| cb(&addr));
-                                state.connected = false;
-                                return Ok(());
-                            }
-                        }
+    let writer = async move {
+        while let Some(msg) = rx.next().await {
+            match msg {
+                Either::Left(packet) => {
+                    if let Err(e) = tx.send(packet).await {
+                        return Ended::Lost(e.into());
                     }
                 }
+                Either::Right(_) => return Ended::Closed,
             }
-            Either::Right(_) => return Ok(()),
         }
-    }
+        Ended::Closed
+    };
 
-    Ok(())
+    finish(&state, reader, writer).await
+}
+
+/// How the reading or writing half of a connection came to an end
+enum Ended {
+    /// The connection was closed on purpose
+    Closed,
+    /// The connection was lost because of the given error
+    Lost(Error),
+}
+
+/// Runs the two halves of a connection until either ends, marking the connection as lost if it
+/// didn't end on purpose. A connection lost before the handshake completed is an error instead.
+async fn finish(
+    state: &Arc<Mutex<ClientState>>,
+    reader: impl Future<Output = Ended>,
+    writer: impl Future<Output = Ended>,
+) -> Result<()> {
+    pin_mut!(reader, writer);
+    let ended = match future::select(reader, writer).await {
+        Either::Left((ended, _)) | Either::Right((ended, _)) => ended,
+    };
+    match ended {
+        Ended::Closed => {
+            debug!("Connection closed");
+            Ok(())
+        }
+        Ended::Lost(e) => {
+            let mut state = state.lock().unwrap();
+            if state.connected {
+                state.set_disconnected(e.to_string());
+                Ok(())
+            } else {
+                debug!(error = %e, "Handshake failed");
+                Err(e)
+            }
+        }
+    }
 }
 
 #[cfg(feature = "websocket")]
 pub async fn connection_ws(
     state: Arc<Mutex<ClientState>>,
     mut packet_rx: UnboundedReceiver<Box<dyn Packet>>,
-    ready_tx: UnboundedSender<()>,
+    ready_tx: UnboundedSender<Result<()>>,
     close_rx: Receiver<()>,
 ) -> crate::Result<()> {
     use tokio_tungstenite::tungstenite::http::HeaderValue;
@@ -126,35 +241,43 @@ pub async fn connection_ws(
         (state.ip.clone(), state.name.clone())
     };
 
-    let _url = Url::parse(&url).unwrap();
-
-    let domain = _url.host_str().unwrap();
-    let port = _url.port().unwrap();
-    let addr = format!("{}:{}", domain, port).parse().unwrap();
+    let invalid = |reason: &str| Error::InvalidUrl {
+        url: url.clone(),
+        reason: reason.to_string(),
+    };
+    let parsed = Url::parse(&url).map_err(|e| invalid(&e.to_string()))?;
+    if parsed.host_str().is_none() {
+        return Err(invalid("missing host"));
+    }
 
-    let req = Request::get(url)
+    let req = Request::get(url.as_str())
         .header(
             "Sec-WebSocket-Protocol",
-            HeaderValue::from_str("NetworkTables").unwrap(),
+            HeaderValue::from_static("NetworkTables"),
         )
         .body(())
-        .unwrap();
+        .map_err(|e| invalid(&e.to_string()))?;
     let (sock, _resp) = tokio_tungstenite::connect_async(req).await?;
-    println!("Connected to remote.");
+    let addr = sock.get_ref().peer_addr()?;
+    debug!("Websocket handshake complete");
 
     let (mut tx, rx) = WSCodec::new(sock).split();
 
-    tokio::spawn(async move {
-        tx.send(Box::new(ClientHello::new(NTVersion::V3, client_name)))
-            .await
-            .unwrap();
+    let writer = async move {
+        let hello = Box::new(ClientHello::new(NTVersion::V3, client_name));
+        if let Err(e) = tx.send(hello).await {
+            return Ended::Lost(e);
+        }
         while let Some(packet) = packet_rx.next().await {
-            tx.send(packet).await.unwrap();
+            if let Err(e) = tx.send(packet).await {
+                return Ended::Lost(e);
+            }
         }
-    });
+        Ended::Closed
+    };
 
     let tick_state = Arc::clone(&state);
-    tokio::spawn(async move {
+    let _keepalive = spawn_abortable(async move {
         let mut interval = tokio::time::interval(Duration::new(1, 0));
 
         loop {
@@ -168,62 +291,89 @@ pub async fn connection_ws(
     });
 
     let mut rx = select(rx.map(Either::Left), close_rx.map(Either::Right));
-
-    while let Some(msg) = rx.next().await {
-        match msg {
-            Either::Left(packet) => match packet {
-                Ok(packet) => match packet {
-                    ReceivedPacket::ServerHelloComplete => {
-                        ready_tx.unbounded_send(()).unwrap();
-                        let mut state = state.lock().unwrap();
-                        state
-                            .packet_tx
-                            .unbounded_send(Box::new(ClientHelloComplete))
-                            .unwrap();
-                        state.connected = true;
+    let rx_state = Arc::clone(&state);
+    let reader = async move {
+        let mut server_seen = false;
+        let mut server_name = String::new();
+        while let Some(msg) = rx.next().await {
+            let packet = match msg {
+                Either::Left(Ok(packet)) => packet,
+                Either::Left(Err(e)) => return Ended::Lost(e),
+                Either::Right(_) => return Ended::Closed,
+            };
+            match packet {
+                ReceivedPacket::ServerHello(hello) => {
+                    server_seen = hello.flags & 1 != 0;
+                    debug!(server_name = %hello.name, server_seen, "Received ServerHello");
+                    server_name = hello.name;
+                }
+                ReceivedPacket::ServerHelloComplete => {
+                    debug!("Received ServerHelloComplete");
+                    let mut state = rx_state.lock().unwrap();
+                    state.merge_entries(server_seen);
+                    state.apply_defaults();
+                    let _ = state
+                        .packet_tx
+                        .unbounded_send(Box::new(ClientHelloComplete));
+                    state.set_connected(addr, mem::take(&mut server_name), Protocol::NT3);
+                    let _ = ready_tx.unbounded_send(Ok(()));
+                }
+                packet => {
+                    if let Err(e) = handle_packet(packet, &rx_state) {
+                        return Ended::Lost(e);
                     }
-                    packet => handle_packet(packet, &state)?,
-                },
-                Err(_) => {
-                    let mut state = state.lock().unwrap();
-                    state
-                        .connection_callbacks
-                        .iter_all_mut()
-                        .filter(|(cb, _)| **cb == ConnectionCallbackType::ClientDisconnected)
-                        .flat_map(|(_, cbs)| cbs)
-                        .for_each(|cb| cb(&addr));
-                    state.connected = false;
-                    return Ok(());
                 }
-            },
-            Either::Right(_) => return Ok(()),
+            }
         }
-    }
-    Ok(())
+        Ended::Lost(Error::ConnectionClosed)
+    };
+
+    finish(&state, reader, writer).await
 }
 
 fn handle_packet(packet: ReceivedPacket, state: &Arc<Mutex<ClientState>>) -> crate::Result<()> {
+    state.lock().unwrap().mark_received();
     match packet {
         ReceivedPacket::EntryAssignment(ea) => {
             let mut state = state.lock().unwrap();
-            if let Some(mut tx) = state.pending_entries.remove(&ea.entry_name) {
-                tx.try_send(ea.entry_id).unwrap();
+            let data = EntryData::new_with_seqnum(
+                ea.entry_name,
+                ea.entry_flags,
+                ea.entry_value,
+                ea.entry_seqnum.into(),
+            );
+            if !state.connected {
+                state.insert_handshake_entry(ea.entry_id, data);
+                return Ok(());
             }
 
-            let data = EntryData::new(ea.entry_name, ea.entry_flags, ea.entry_value);
-            state
-                .callbacks
-                .iter_all_mut()
-                .filter(|(cb, _)| **cb == CallbackType::Add)
-                .flat_map(|(_, cbs)| cbs)
-                .for_each(|cb| cb(&data));
-            state.entries.insert(ea.entry_id, data);
+            // Entries this client asked for are reported as local changes
+            let source = if state.resolve_pending(&data.name, ea.entry_id) {
+                EventSource::Local
+            } else {
+                EventSource::Remote
+            };
+            // Entries kept from the previous connection only move to the id the server assigned
+            if state.has_entry(&data.name) {
+                state.insert_entry(ea.entry_id, data);
+                return Ok(());
+            }
+
+            state.fire_callbacks(CallbackType::Add, &data);
+            state.subscribers.notify(
+                EntryEventKind::Added,
+                ea.entry_id,
+                None,
+                Some(&data),
+                source,
+            );
+            state.insert_entry(ea.entry_id, data);
         }
         ReceivedPacket::KeepAlive => {}
         ReceivedPacket::ClientHello(_) => {}
         ReceivedPacket::ProtocolVersionUnsupported(pvu) => {
             return Err(Error::UnsupportedProtocolVersion {
-                supported_version: NTVersion::from_u16(pvu.supported_version).unwrap(),
+                supported_version: NTVersion::from_u16(pvu.supported_version)?,
             });
         }
         ReceivedPacket::ServerHello(_) => {}
@@ -231,41 +381,57 @@ fn handle_packet(packet: ReceivedPacket, state: &Arc<Mutex<ClientState>>) -> cra
         ReceivedPacket::EntryUpdate(eu) => {
             let mut state = state.lock().unwrap();
             if let Some(entry) = state.entries.get_mut(&eu.entry_id) {
-                entry.value = eu.entry_value;
-                entry.seqnum = eu.entry_seqnum;
+                // NT4 only types arrive widened, so only the wire types have to match
+                let seqnum = SequenceNumber::from(eu.entry_seqnum);
+                let entry_type = entry.entry_type();
+                if !seqnum.is_newer_than(entry.seqnum)
+                    || eu.entry_type.nt3_type() != entry_type.nt3_type()
+                {
+                    return Ok(());
+                }
+                let old = entry.clone();
+                entry.value = eu.entry_value.narrow_to(entry_type);
+                entry.seqnum = seqnum;
 
                 // Gross but necessary to ensure unique mutable borrows
                 let entry = entry.clone();
-
-                state
-                    .callbacks
-                    .iter_all_mut()
-                    .filter(|(cb, _)| **cb == CallbackType::Update)
-                    .flat_map(|(_, cbs)| cbs)
-                    .for_each(|cb| cb(&entry));
+                state.fire_callbacks(CallbackType::Update, &entry);
+                state.subscribers.notify(
+                    EntryEventKind::Updated,
+                    eu.entry_id,
+                    Some(&old),
+                    Some(&entry),
+                    EventSource::Remote,
+                );
             }
         }
         ReceivedPacket::EntryFlagsUpdate(efu) => {
             let mut state = state.lock().unwrap();
             if let Some(entry) = state.entries.get_mut(&efu.entry_id) {
-                entry.flags = efu.entry_flags;
+                let old = entry.clone();
+                entry.set_flags(efu.entry_flags);
+
+                let entry = entry.clone();
+                state.subscribers.notify(
+                    EntryEventKind::FlagsChanged,
+                    efu.entry_id,
+                    Some(&old),
+                    Some(&entry),
+                    EventSource::Remote,
+                );
             }
         }
         ReceivedPacket::EntryDelete(ed) => {
-            let mut state = state.lock().unwrap();
-            if let Some(data) = state.entries.remove(&ed.entry_id) {
-                state
-                    .callbacks
-                    .iter_all_mut()
-                    .filter(|(cb, _)| **cb == CallbackType::Delete)
-                    .flat_map(|(_, cbs)| cbs)
-                    .for_each(|cb| cb(&data));
-            }
+            state
+                .lock()
+                .unwrap()
+                .remove_entry(ed.entry_id, EventSource::Remote);
         }
-        ReceivedPacket::ClearAllEntries(cea) => {
-            if cea.is_valid() {
-                state.lock().unwrap().clear_entries();
-            }
+        ReceivedPacket::ClearAllEntries(cea) if cea.is_valid() => {
+            state
+                .lock()
+                .unwrap()
+                .remove_all_entries(EventSource::Remote);
         }
         ReceivedPacket::RpcResponse(rpc) => {
             let mut state = state.lock().unwrap();
@@ -0,0 +1,573 @@
+use crate::error::Error;
+use crate::nt::connection::Protocol;
+use crate::proto::client::{conn, ClientState};
+use crate::proto::ws::NT4Codec;
+use crate::{CallbackType, EntryData, EntryEventKind, EventSource, Result, PERSISTENT_FLAG};
+use futures_channel::mpsc::{Receiver, UnboundedReceiver, UnboundedSender};
+use futures_util::future;
+use futures_util::sink::SinkExt;
+use futures_util::stream::{self, select};
+use futures_util::StreamExt;
+use nt_network::nt4::{
+    ControlMessage, NT4Frame, Properties, Publish, SetProperties, Subscribe, SubscriptionOptions,
+    Unpublish, ValueFrame, NT4_PORT, NT4_SUBPROTOCOL, RTT_TOPIC_ID,
+};
+use nt_network::types::EntryValue;
+use nt_network::{Packet, ReceivedPacket};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use tokio_tungstenite::tungstenite::Error as WsError;
+use tokio_tungstenite::WebSocketStream;
+use tracing::warn;
+use url::Url;
+
+/// The port connected to when the server rejects NT4 and the client falls back to NT3
+const NT3_PORT: u16 = 1735;
+
+/// The entry id NT3 reserves for entries that haven't been assigned one, which is never allocated
+const UNASSIGNED_ID: u16 = 0xFFFF;
+
+/// How long the server has to answer the upgrade to NT4 before the client falls back to NT3
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the client measures the offset between its clock and the server's
+const RTT_INTERVAL: Duration = Duration::from_secs(3);
+
+enum Event {
+    Frame(Result<NT4Frame>),
+    /// The server closed the connection
+    Ended,
+    Packet(Box<dyn Packet>),
+    Ping,
+    Close,
+}
+
+struct Topic {
+    /// The id of the entry the topic is presented as
+    id: u16,
+    name: String,
+    type_str: String,
+    properties: Properties,
+}
+
+/// Translates between the NT3 shaped `ClientState` and an NT4 session
+///
+/// Topics are presented as entries with ids allocated by the client, as NT4 topic ids don't fit in
+/// an entry id. A topic keeps the id of the entry with the same name, if there is one.
+#[derive(Default)]
+struct Session {
+    topics: HashMap<i32, Topic>,
+    /// The NT4 topic id of each entry id in `topics`
+    topic_ids: HashMap<u16, i32>,
+    /// Where the search for a free entry id starts
+    next_id: u16,
+    publishers: HashMap<String, i32>,
+    /// Entries created by this client that haven't yet been announced by the server
+    pending: HashMap<String, EntryData>,
+    next_pubuid: i32,
+}
+
+/// How the server answered the request to speak NT4
+enum Handshake {
+    Accepted(Box<WebSocketStream<TcpStream>>),
+    /// The server doesn't speak NT4, for the given reason
+    Rejected(String),
+}
+
+async fn handshake(host: &str, name: &str) -> Result<Handshake> {
+    let base = format!("ws://{}:{}/nt", host, NT4_PORT);
+    let invalid = |reason: String| Error::InvalidUrl {
+        url: base.clone(),
+        reason,
+    };
+    let mut url = Url::parse(&base).map_err(|e| invalid(e.to_string()))?;
+    url.path_segments_mut()
+        .map_err(|_| invalid(format!("invalid NT4 server host {}", host)))?
+        .push(name);
+
+    let req = Request::get(url.as_str())
+        .header("Sec-WebSocket-Protocol", NT4_SUBPROTOCOL)
+        .body(())
+        .map_err(|e| invalid(e.to_string()))?;
+    let connecting = tokio::time::timeout(HANDSHAKE_TIMEOUT, tokio_tungstenite::connect_async(req));
+    let (sock, resp) = match connecting.await {
+        Ok(Ok(res)) => res,
+        // NT3 only servers don't listen on the NT4 port at all
+        Ok(Err(WsError::Io(e))) if e.kind() == ErrorKind::ConnectionRefused => {
+            return Ok(Handshake::Rejected(format!(
+                "Nothing is listening for NT4 on port {}",
+                NT4_PORT
+            )))
+        }
+        // Servers that don't serve NT4 answer with an error status or a broken upgrade
+        Ok(Err(WsError::Http(status))) => {
+            return Ok(Handshake::Rejected(format!(
+                "The server answered the upgrade with {}",
+                status
+            )))
+        }
+        Ok(Err(WsError::Protocol(reason))) => return Ok(Handshake::Rejected(reason.into_owned())),
+        Ok(Err(e)) => return Err(e.into()),
+        Err(_) => {
+            return Ok(Handshake::Rejected(
+                "The server did not answer the upgrade to NT4 in time".to_string(),
+            ))
+        }
+    };
+
+    let proto = resp
+        .headers()
+        .get("Sec-WebSocket-Protocol")
+        .and_then(|proto| proto.to_str().ok());
+    if proto != Some(NT4_SUBPROTOCOL) {
+        return Ok(Handshake::Rejected(
+            "The server did not accept the NT4 subprotocol".to_string(),
+        ));
+    }
+
+    Ok(Handshake::Accepted(Box::new(sock)))
+}
+
+pub async fn connection(
+    state: Arc<Mutex<ClientState>>,
+    packet_rx: UnboundedReceiver<Box<dyn Packet>>,
+    ready_tx: UnboundedSender<Result<()>>,
+    close_rx: Receiver<()>,
+) -> Result<()> {
+    let (host, client_name) = {
+        let state = state.lock().unwrap();
+        (state.ip.clone(), state.name.clone())
+    };
+
+    let sock = match handshake(&host, &client_name).await? {
+        Handshake::Accepted(sock) => *sock,
+        Handshake::Rejected(reason) => {
+            warn!(%reason, "The server rejected NT4, falling back to NT3");
+            let ip = format!("{}:{}", host, NT3_PORT);
+            return conn::connection_to(ip, state, packet_rx, ready_tx, close_rx).await;
+        }
+    };
+    let addr = sock.get_ref().peer_addr()?;
+    let (mut tx, rx) = NT4Codec::new(sock).split();
+
+    // Everything the server knows about is mirrored into the entries map
+    let subscribe = Subscribe {
+        topics: vec![String::new()],
+        subuid: 0,
+        options: SubscriptionOptions {
+            prefix: true,
+            ..Default::default()
+        },
+    };
+    tx.send(NT4Frame::Control(vec![ControlMessage::Subscribe(
+        subscribe,
+    )]))
+    .await?;
+
+    // Taken once the server has answered the first ping, which is when the connection is ready
+    let mut ready_tx = Some(ready_tx);
+    let mut session = Session::default();
+    // The first tick fires immediately, so the clock is synchronized as soon as possible
+    let pings = tokio::time::interval(RTT_INTERVAL).map(|_| Event::Ping);
+    let mut events = select(
+        select(
+            // The merged stream only ends once all of these have, so the end of this one is an event
+            rx.map(Event::Frame)
+                .chain(stream::once(future::ready(Event::Ended))),
+            packet_rx.map(Event::Packet),
+        ),
+        select(pings, close_rx.map(|_| Event::Close)),
+    );
+
+    let error = 'events: loop {
+        let event = match events.next().await {
+            Some(event) => event,
+            None => break Error::ConnectionClosed,
+        };
+        let frames = match event {
+            Event::Frame(Ok(frame)) => {
+                let mut state = state.lock().unwrap();
+                state.mark_received();
+                session.handle_frame(frame, &mut state);
+
+                // The server answers in order, so every existing topic was announced before the ping
+                // reply. Only then are the entries known well enough to apply defaults.
+                if state.time_offset.is_some() {
+                    if let Some(ready_tx) = ready_tx.take() {
+                        let announced = &session.topic_ids;
+                        state.merge_nt4_entries(|id| announced.contains_key(&id));
+                        state.apply_defaults();
+                        // NT4 servers don't identify themselves
+                        state.set_connected(addr, String::new(), Protocol::NT4);
+                        let _ = ready_tx.unbounded_send(Ok(()));
+                    }
+                }
+                continue;
+            }
+            Event::Frame(Err(e)) => break e,
+            Event::Ended => break Error::ConnectionClosed,
+            Event::Packet(packet) => match packet.to_received() {
+                Some(packet) => session.translate(packet, &mut state.lock().unwrap()),
+                None => continue,
+            },
+            Event::Ping => {
+                let now = state.lock().unwrap().local_time();
+                let ping = ValueFrame::new(RTT_TOPIC_ID, 0, EntryValue::Integer(now as i64));
+                vec![NT4Frame::Values(vec![ping])]
+            }
+            Event::Close => return Ok(()),
+        };
+
+        for frame in frames {
+            if let Err(e) = tx.send(frame).await {
+                break 'events e;
+            }
+        }
+    };
+
+    if ready_tx.is_some() {
+        return Err(error);
+    }
+    let mut state = state.lock().unwrap();
+    state.keep_published(session.publishers.keys());
+    state.set_disconnected(error.to_string());
+    Ok(())
+}
+
+impl Session {
+    fn handle_frame(&mut self, frame: NT4Frame, state: &mut ClientState) {
+        match frame {
+            NT4Frame::Control(messages) => {
+                for msg in messages {
+                    self.handle_control(msg, state);
+                }
+            }
+            NT4Frame::Values(values) => {
+                for value in values {
+                    self.handle_value(value, state);
+                }
+            }
+        }
+    }
+
+    fn handle_control(&mut self, msg: ControlMessage, state: &mut ClientState) {
+        match msg {
+            ControlMessage::Announce(announce) => {
+                let id = match self.entry_id(announce.id, &announce.name, state) {
+                    Some(id) => id,
+                    None => {
+                        warn!(name = %announce.name, "Ignoring topic, as every entry id is taken");
+                        return;
+                    }
+                };
+
+                // Entries created locally already have a value, the rest get added when their first value arrives
+                if let Some(mut data) = self.pending.remove(&announce.name) {
+                    data.update_properties(&announce.properties);
+                    state.resolve_pending(&announce.name, id);
+                    // Entries kept from the previous connection were already reported
+                    if state.has_entry(&announce.name) {
+                        state.insert_entry(id, data);
+                        return;
+                    }
+                    state.fire_callbacks(CallbackType::Add, &data);
+                    state.subscribers.notify(
+                        EntryEventKind::Added,
+                        id,
+                        None,
+                        Some(&data),
+                        EventSource::Local,
+                    );
+                    state.insert_entry(id, data);
+                }
+
+                self.topics.insert(
+                    announce.id,
+                    Topic {
+                        id,
+                        name: announce.name,
+                        type_str: announce.type_str,
+                        properties: announce.properties,
+                    },
+                );
+            }
+            ControlMessage::Unannounce(unannounce) => {
+                if let Some(topic) = self.topics.remove(&unannounce.id) {
+                    self.topic_ids.remove(&topic.id);
+                    state.remove_entry(topic.id, EventSource::Remote);
+                }
+            }
+            ControlMessage::Properties(update) => {
+                let topic = self
+                    .topics
+                    .iter_mut()
+                    .find(|(_, topic)| topic.name == update.name);
+                if let Some((_, topic)) = topic {
+                    for (key, value) in &update.update {
+                        if value.is_null() {
+                            topic.properties.remove(key);
+                        } else {
+                            topic.properties.insert(key.clone(), value.clone());
+                        }
+                    }
+                    let id = topic.id;
+                    if let Some(entry) = state.entries.get_mut(&id) {
+                        let old = entry.clone();
+                        entry.update_properties(&update.update);
+                        state.subscribers.notify(
+                            EntryEventKind::FlagsChanged,
+                            id,
+                            Some(&old),
+                            Some(entry),
+                            EventSource::Remote,
+                        );
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_value(&mut self, value: ValueFrame, state: &mut ClientState) {
+        if value.topic_id == RTT_TOPIC_ID {
+            // The server echoes the local time the ping was sent, stamped with its own time
+            if let EntryValue::Integer(sent) = value.entry_value {
+                let now = state.local_time() as i64;
+                let rtt = now - sent;
+                state.time_offset = Some(value.timestamp as i64 + rtt / 2 - now);
+            }
+            return;
+        }
+
+        let topic = match i32::try_from(value.topic_id)
+            .ok()
+            .and_then(|topic_id| self.topics.get(&topic_id))
+        {
+            Some(topic) => topic,
+            None => return,
+        };
+        let id = topic.id;
+        // Values updated while disconnected are sent to the server once the connection is ready
+        if !state.connected && state.dirty_entries.contains_key(&topic.name) {
+            return;
+        }
+
+        match state.entries.get_mut(&id) {
+            Some(entry) => {
+                let old = entry.clone();
+                entry.value = value.entry_value;
+                entry.seqnum = entry.seqnum.next();
+                entry.timestamp = Some(value.timestamp);
+                state.subscribers.notify(
+                    EntryEventKind::Updated,
+                    id,
+                    Some(&old),
+                    Some(entry),
+                    EventSource::Remote,
+                );
+
+                let entry = entry.clone();
+                state.fire_callbacks(CallbackType::Update, &entry);
+            }
+            None => {
+                let mut data = EntryData::new(topic.name.clone(), 0, value.entry_value);
+                data.timestamp = Some(value.timestamp);
+                data.update_properties(&topic.properties);
+                state.fire_callbacks(CallbackType::Add, &data);
+                state.subscribers.notify(
+                    EntryEventKind::Added,
+                    id,
+                    None,
+                    Some(&data),
+                    EventSource::Remote,
+                );
+                state.insert_entry(id, data);
+            }
+        }
+    }
+
+    /// Translates a packet sent by the `ClientState` into the equivalent NT4 messages
+    ///
+    /// Keep alives and RPC calls have no NT4 equivalent, and are dropped.
+    fn translate(&mut self, packet: ReceivedPacket, state: &mut ClientState) -> Vec<NT4Frame> {
+        let mut control = Vec::new();
+        let mut values = Vec::new();
+        // A timestamp of 0 tells the server to use the time it received the value
+        let now = state.server_time().unwrap_or(0);
+
+        match packet {
+            ReceivedPacket::EntryAssignment(ea) => {
+                // Topics can be announced before their first value, which is when they become entries
+                let announced = self
+                    .topics
+                    .iter()
+                    .find(|(_, topic)| topic.name == ea.entry_name);
+                if let Some((_, topic)) = announced {
+                    state.resolve_pending(&ea.entry_name, topic.id);
+                    return Vec::new();
+                }
+
+                let pubuid =
+                    self.publisher(&ea.entry_name, ea.entry_type.nt4_type_str(), &mut control);
+                values.push(ValueFrame::new(pubuid as i64, now, ea.entry_value.clone()));
+
+                let mut data = EntryData::new(ea.entry_name, ea.entry_flags, ea.entry_value);
+                data.timestamp = state.server_time();
+                self.pending.insert(data.name.clone(), data);
+            }
+            ReceivedPacket::EntryUpdate(eu) => {
+                if let Some(entry) = state.entries.get(&eu.entry_id) {
+                    let topic = self
+                        .topic_ids
+                        .get(&eu.entry_id)
+                        .and_then(|topic_id| self.topics.get(topic_id));
+                    let type_str = match topic {
+                        Some(topic) => topic.type_str.clone(),
+                        None => eu.entry_type.nt4_type_str().to_string(),
+                    };
+                    let pubuid = self.publisher(&entry.name, &type_str, &mut control);
+                    let timestamp = entry.timestamp.unwrap_or(now);
+                    values.push(ValueFrame::new(pubuid as i64, timestamp, eu.entry_value));
+                }
+            }
+            ReceivedPacket::EntryFlagsUpdate(efu) => {
+                if let Some(entry) = state.entries.get(&efu.entry_id) {
+                    let mut update = Properties::new();
+                    update.insert(
+                        "persistent".to_string(),
+                        (efu.entry_flags & PERSISTENT_FLAG != 0).into(),
+                    );
+                    control.push(ControlMessage::SetProperties(SetProperties {
+                        name: entry.name.clone(),
+                        update,
+                    }));
+                }
+            }
+            // NT4 has no way to delete a topic, the best that can be done is to stop publishing it
+            ReceivedPacket::EntryDelete(ed) => {
+                if let Some(entry) = state.entries.get(&ed.entry_id) {
+                    if let Some(pubuid) = self.publishers.remove(&entry.name) {
+                        control.push(ControlMessage::Unpublish(Unpublish { pubuid }));
+                    }
+                }
+            }
+            ReceivedPacket::ClearAllEntries(_) => {
+                for (_, pubuid) in self.publishers.drain() {
+                    control.push(ControlMessage::Unpublish(Unpublish { pubuid }));
+                }
+            }
+            _ => {}
+        }
+
+        let mut frames = Vec::new();
+        // Publish messages must reach the server before values are sent with their pubuid
+        if !control.is_empty() {
+            frames.push(NT4Frame::Control(control));
+        }
+        if !values.is_empty() {
+            frames.push(NT4Frame::Values(values));
+        }
+        frames
+    }
+
+    /// Returns the entry id the topic with the given id and name is presented as, allocating one if
+    /// it doesn't have one yet. Returns `None` if every entry id is taken.
+    fn entry_id(&mut self, topic_id: i32, name: &str, state: &ClientState) -> Option<u16> {
+        if let Some(topic) = self.topics.get(&topic_id) {
+            return Some(topic.id);
+        }
+
+        let id = match state.entry_ids.get(name) {
+            Some(id) if !self.topic_ids.contains_key(id) => *id,
+            _ => {
+                let topic_ids = &self.topic_ids;
+                let taken = |id: u16| {
+                    id == UNASSIGNED_ID
+                        || topic_ids.contains_key(&id)
+                        || state.entries.contains_key(&id)
+                };
+                let start = self.next_id;
+                while taken(self.next_id) {
+                    self.next_id = self.next_id.wrapping_add(1);
+                    if self.next_id == start {
+                        return None;
+                    }
+                }
+                let id = self.next_id;
+                self.next_id = self.next_id.wrapping_add(1);
+                id
+            }
+        };
+        self.topic_ids.insert(id, topic_id);
+        Some(id)
+    }
+
+    /// Returns the pubuid used to publish to the given topic, publishing it if necessary
+    fn publisher(&mut self, name: &str, type_str: &str, control: &mut Vec<ControlMessage>) -> i32 {
+        if let Some(pubuid) = self.publishers.get(name) {
+            return *pubuid;
+        }
+
+        let pubuid = self.next_pubuid;
+        self.next_pubuid += 1;
+        self.publishers.insert(name.to_string(), pubuid);
+        control.push(ControlMessage::Publish(Publish {
+            name: name.to_string(),
+            pubuid,
+            type_str: type_str.to_string(),
+            properties: Properties::new(),
+        }));
+        pubuid
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::NetworkTables;
+    use nt_network::codec::NTCodec;
+    use nt_network::{ServerHello, ServerHelloComplete};
+    use tokio::net::TcpListener;
+    use tokio::runtime::Runtime;
+    use tokio_util::codec::Decoder;
+
+    #[test]
+    fn servers_not_listening_for_nt4_are_reached_over_nt3() {
+        let _ports = crate::proto::lock_standard_ports();
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            // A server that only speaks NT3, so nothing listens on the NT4 port
+            let mut listener = TcpListener::bind(("127.0.0.1", NT3_PORT)).await.unwrap();
+            let server = tokio::spawn(async move {
+                let (conn, _) = listener.accept().await.unwrap();
+                let mut conn = NTCodec::default().framed(conn);
+                match conn.next().await {
+                    Some(Ok(ReceivedPacket::ClientHello(_))) => {}
+                    _ => panic!("expected a client hello"),
+                }
+                conn.send(Box::new(ServerHello::new(0, "nt3".to_string())))
+                    .await
+                    .unwrap();
+                conn.send(Box::new(ServerHelloComplete)).await.unwrap();
+                match conn.next().await {
+                    Some(Ok(ReceivedPacket::ClientHelloComplete)) => {}
+                    _ => panic!("expected the client hello to complete"),
+                }
+                conn
+            });
+
+            let nt = NetworkTables::connect_nt4("127.0.0.1", "client")
+                .await
+                .unwrap();
+            let info = nt.connection_info().unwrap();
+            assert_eq!(info.protocol, Protocol::NT3);
+            assert_eq!(info.remote_name, "nt3");
+            let _conn = server.await.unwrap();
+        });
+    }
+}
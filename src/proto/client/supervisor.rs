@@ -0,0 +1,140 @@
+//! Keeps a client connected, reconnecting with exponential backoff whenever the connection is lost
+
+use crate::nt::subscription::ConnectionEvent;
+use crate::proto::client::ClientState;
+use crate::Result;
+use futures_channel::mpsc::{channel, unbounded, Receiver, UnboundedReceiver, UnboundedSender};
+use futures_util::{pin_mut, select, FutureExt, StreamExt};
+use nt_network::Packet;
+use std::collections::hash_map::RandomState;
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// How long a supervised client waits between attempts to connect
+///
+/// The first attempt after a connection is lost waits `initial`, and each failed attempt after it
+/// multiplies the delay by `multiplier`, up to `max`. Up to `jitter` of each delay is taken off at
+/// random, so that many clients don't all retry at once when a server comes back.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub multiplier: f64,
+    /// The fraction of each delay that is randomized, between 0 and 1
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(250),
+            max: Duration::from_secs(5),
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl Backoff {
+    /// The delay before the given attempt, counted from 1
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let exponent = attempt.saturating_sub(1).min(i32::MAX as u32) as i32;
+        let delay = self.initial.as_secs_f64() * self.multiplier.powi(exponent);
+        let delay = delay.min(self.max.as_secs_f64());
+        let jitter = self.jitter.clamp(0.0, 1.0) * random_fraction();
+        Duration::from_secs_f64(delay * (1.0 - jitter))
+    }
+}
+
+/// A number in `[0, 1)`, random enough to spread out retries
+fn random_fraction() -> f64 {
+    // Every `RandomState` is seeded differently
+    let bits = RandomState::new().build_hasher().finish();
+    (bits >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Connects the client over and over until `close_rx` fires or is dropped, preparing the state for
+/// each connection with `reset`.
+///
+/// Connections that are lost, and attempts that fail, are retried after the delay given by `backoff`.
+pub(crate) async fn supervise<F>(
+    state: Arc<Mutex<ClientState>>,
+    backoff: Backoff,
+    mut close_rx: Receiver<()>,
+    reset: fn(&mut ClientState, UnboundedSender<Box<dyn Packet>>),
+    connection: impl Fn(
+        Arc<Mutex<ClientState>>,
+        UnboundedReceiver<Box<dyn Packet>>,
+        UnboundedSender<Result<()>>,
+        Receiver<()>,
+    ) -> F,
+) -> Result<()>
+where
+    F: Future<Output = Result<()>>,
+{
+    let mut attempt = 0;
+    loop {
+        let (packet_tx, packet_rx) = unbounded::<Box<dyn Packet>>();
+        let (ready_tx, mut ready_rx) = unbounded::<Result<()>>();
+        // The connection is stopped by dropping it, so this is never sent to
+        let (_conn_close_tx, conn_close_rx) = channel::<()>(1);
+
+        reset(&mut state.lock().unwrap(), packet_tx);
+
+        let conn = connection(Arc::clone(&state), packet_rx, ready_tx, conn_close_rx).fuse();
+        pin_mut!(conn);
+
+        let mut established = false;
+        let res = loop {
+            select! {
+                res = conn => break res,
+                ready = ready_rx.next() => {
+                    if let Some(Ok(())) = ready {
+                        established = true;
+                        attempt = 0;
+                    }
+                }
+                _ = close_rx.next() => return Ok(()),
+            }
+        };
+
+        attempt += 1;
+        let delay = backoff.delay(attempt);
+        // Packets sent while waiting to reconnect are dropped, rather than failing to send
+        let (idle_tx, _idle_rx) = unbounded::<Box<dyn Packet>>();
+        {
+            let mut state = state.lock().unwrap();
+            state.packet_tx = idle_tx;
+            match res {
+                // A lost connection has already said why
+                Ok(()) if established => {}
+                Ok(()) => {
+                    let reason = "The server closed the connection during the handshake";
+                    warn!(%reason, "Failed to connect");
+                    state.notify_connection(ConnectionEvent::ConnectFailed {
+                        reason: reason.to_string(),
+                    });
+                }
+                Err(e) if established => state.set_disconnected(e.to_string()),
+                Err(e) => {
+                    warn!(reason = %e, "Failed to connect");
+                    state.notify_connection(ConnectionEvent::ConnectFailed {
+                        reason: e.to_string(),
+                    });
+                }
+            }
+            info!(attempt, ?delay, "Reconnecting");
+            state.notify_connection(ConnectionEvent::Reconnecting { attempt, delay });
+        }
+
+        let delay = tokio::time::delay_for(delay).fuse();
+        pin_mut!(delay);
+        select! {
+            _ = delay => {}
+            _ = close_rx.next() => return Ok(()),
+        }
+    }
+}
@@ -1,56 +1,140 @@
-use crate::proto::State;
+use crate::error::Error;
+use crate::nt::connection::ConnectionInfo;
+use crate::nt::subscription::{
+    ConnectionEvent, ConnectionEvents, ConnectionSubscribers, Subscribers,
+};
+use crate::proto::{ConnectionTask, State};
 use crate::{
-    Action, CallbackType, ConnectionAction, ConnectionCallbackType, EntryData, EntryValue,
-    RpcAction,
+    Action, CallbackType, ConnectionAction, ConnectionCallbackType, EntryData, EntryEventKind,
+    EntryValue, EventSource, RpcAction, Subscription,
 };
 use futures_channel::mpsc::{channel, Receiver, UnboundedSender};
+use futures_channel::oneshot;
+use futures_util::future::{FutureExt, Shared};
 use multimap::MultiMap;
+use nt_network::types::EntryType;
 use nt_network::{
     ClearAllEntries, EntryAssignment, EntryDelete, EntryFlagsUpdate, EntryUpdate, Packet,
 };
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::future::Future;
 use std::net::SocketAddr;
 use std::panic::RefUnwindSafe;
+use std::path::PathBuf;
 
 use std::sync::{Arc, Mutex};
-use std::thread;
-use tokio::runtime::Runtime;
+use std::time::Instant;
+use tracing::{info, warn};
 
 mod conn;
+pub mod limits;
+#[cfg(feature = "websocket")]
+mod nt4;
+mod persist;
+
+use self::limits::Limits;
+
+/// The entry id NT3 reserves for entries that haven't been assigned one yet
+const UNASSIGNED_ID: u16 = 0xFFFF;
+
+/// How many client names the server remembers to recognize returning clients
+const MAX_SEEN_CLIENTS: usize = 1024;
 
 pub struct ServerState {
     server_name: String,
     clients: HashMap<SocketAddr, UnboundedSender<Box<dyn Packet>>>,
+    /// The clients that have completed their handshake
+    connections: HashMap<SocketAddr, ConnectionInfo>,
     entries: HashMap<u16, EntryData>,
+    entry_ids: BTreeMap<String, u16>,
     callbacks: MultiMap<CallbackType, Box<Action>>,
     server_callbacks: MultiMap<ConnectionCallbackType, Box<ConnectionAction>>,
     next_id: u16,
     rpc_actions: HashMap<u16, Arc<RpcAction>>,
-}
-
-fn spawn_rt(ip: String, state: Arc<Mutex<ServerState>>, close_rx: Receiver<()>) {
-    thread::spawn(move || {
-        let mut rt = Runtime::new().unwrap();
-        rt.block_on(conn::connection(ip, state, close_rx)).unwrap();
-    });
+    start: Instant,
+    /// The number of NT4 publishers of each entry that was created by an NT4 publish
+    nt4_publishers: HashMap<u16, usize>,
+    /// Names of the NT3 clients that have connected before, so that they can be told they're
+    /// reconnecting. The least recently connected are forgotten past `MAX_SEEN_CLIENTS`.
+    seen_clients: VecDeque<String>,
+    limits: Limits,
+    pub(crate) subscribers: Subscribers,
+    connection_subscribers: ConnectionSubscribers,
+    /// Completes once the server task has finished
+    stopped: Shared<oneshot::Receiver<()>>,
 }
 
 impl ServerState {
-    pub fn new(ip: String, server_name: String, close_rx: Receiver<()>) -> Arc<Mutex<ServerState>> {
+    /// Creates the server state, loading persistent entries from `persist_path` and saving them
+    /// there when they change. Returns the state once the server is listening on `ip`, along with
+    /// the result of running the server task with `spawn`.
+    pub async fn new<R>(
+        ip: String,
+        server_name: String,
+        persist_path: Option<PathBuf>,
+        close_rx: Receiver<()>,
+        spawn: impl FnOnce(ConnectionTask) -> R,
+    ) -> crate::Result<(Arc<Mutex<ServerState>>, R)> {
+        let (stopped_tx, stopped_rx) = oneshot::channel::<()>();
         let state = Arc::new(Mutex::new(ServerState {
             server_name,
             clients: HashMap::new(),
+            connections: HashMap::new(),
             entries: HashMap::new(),
+            entry_ids: BTreeMap::new(),
             callbacks: MultiMap::new(),
             server_callbacks: MultiMap::new(),
             next_id: 0,
             rpc_actions: HashMap::new(),
+            start: Instant::now(),
+            nt4_publishers: HashMap::new(),
+            seen_clients: VecDeque::new(),
+            limits: Limits::default(),
+            subscribers: Subscribers::default(),
+            connection_subscribers: ConnectionSubscribers::default(),
+            stopped: stopped_rx.shared(),
         }));
 
+        if let Some(path) = &persist_path {
+            match crate::persist::load(path) {
+                Ok(entries) => {
+                    let mut state = state.lock().unwrap();
+                    for entry in entries {
+                        let _ = state.create_entry(entry);
+                    }
+                }
+                Err(e) => warn!(?path, error = %e, "Failed to load persistent entries"),
+            }
+        }
+
+        let (ready_tx, ready_rx) = oneshot::channel::<crate::Result<()>>();
         let rt_state = Arc::clone(&state);
-        spawn_rt(ip, rt_state, close_rx);
+        let task = spawn(Box::pin(async move {
+            // Dropped once the server has stopped, which the saver and `stopped` wait for
+            let _stopped_tx = stopped_tx;
+            let (saver_tx, saver_rx) = oneshot::channel::<()>();
+            let saver = persist_path.map(|path| {
+                let stopping = saver_rx.map(|_| ());
+                tokio::spawn(persist::save_on_change(
+                    path,
+                    Arc::clone(&rt_state),
+                    stopping,
+                ))
+            });
 
-        state
+            let res = conn::connection(ip, Arc::clone(&rt_state), close_rx, ready_tx).await;
+            drop(saver_tx);
+            if let Some(saver) = saver {
+                let _ = saver.await;
+            }
+            res
+        }));
+
+        match ready_rx.await {
+            Ok(res) => res?,
+            Err(_) => return Err(Error::ConnectionAborted),
+        }
+        Ok((state, task))
     }
 
     pub fn add_server_callback(
@@ -62,116 +146,378 @@ impl ServerState {
             .insert(callback_type, Box::new(action));
     }
 
-    pub fn create_rpc(
+    /// Returns a `Stream` of clients connecting and disconnecting
+    pub fn connection_events(&mut self) -> ConnectionEvents {
+        self.connection_subscribers.subscribe()
+    }
+
+    /// The limits placed on what clients can send
+    pub fn limits(&self) -> Limits {
+        self.limits
+    }
+
+    /// Sets the limits placed on what clients can send, for clients that connect afterwards
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Completes once the server has stopped, after disconnecting its clients and saving its
+    /// persistent entries
+    pub(crate) fn stopped(&self) -> impl Future<Output = ()> {
+        self.stopped.clone().map(|_| ())
+    }
+
+    /// Information about each client that has completed its handshake
+    pub fn connected_clients(&self) -> Vec<ConnectionInfo> {
+        self.connections.values().cloned().collect()
+    }
+
+    /// Records a client that has completed its handshake, telling the connection callbacks and events
+    pub(crate) fn client_connected(&mut self, info: ConnectionInfo) {
+        let addr = info.addr;
+        info!(name = %info.remote_name, protocol = ?info.protocol, "Client connected");
+        self.connections.insert(addr, info);
+        self.fire_server_callbacks(ConnectionCallbackType::ClientConnected, &addr);
+        self.connection_subscribers
+            .notify(ConnectionEvent::ClientConnected { addr });
+    }
+
+    /// Forgets a client whose connection has ended for the given reason. The connection callbacks
+    /// and events are only told about clients that completed their handshake.
+    pub(crate) fn client_disconnected(&mut self, addr: SocketAddr, reason: String) {
+        self.clients.remove(&addr);
+        if self.connections.remove(&addr).is_none() {
+            info!(%reason, "Client left before completing the handshake");
+            return;
+        }
+        info!(%reason, "Client disconnected");
+        self.fire_server_callbacks(ConnectionCallbackType::ClientDisconnected, &addr);
+        self.connection_subscribers
+            .notify(ConnectionEvent::ClientDisconnected { addr, reason });
+    }
+
+    /// Records that a client named `name` has connected, returning whether one with that name
+    /// connected before. Nameless clients can't be recognized when they return.
+    pub(crate) fn client_seen(&mut self, name: &str) -> bool {
+        if name.is_empty() {
+            return false;
+        }
+        let seen = match self.seen_clients.iter().position(|seen| seen == name) {
+            Some(i) => {
+                self.seen_clients.remove(i);
+                true
+            }
+            None => false,
+        };
+        if self.seen_clients.len() >= MAX_SEEN_CLIENTS {
+            self.seen_clients.pop_front();
+        }
+        self.seen_clients.push_back(name.to_string());
+        seen
+    }
+
+    /// Notes that a packet was just received from the client at `addr`
+    pub(crate) fn mark_received(&mut self, addr: SocketAddr) {
+        if let Some(info) = self.connections.get_mut(&addr) {
+            info.last_received = Instant::now();
+        }
+    }
+
+    /// The current server time in microseconds, which is what NT4 clients synchronize their clocks to
+    pub fn server_time(&self) -> u64 {
+        self.start.elapsed().as_micros() as u64
+    }
+
+    pub(crate) fn fire_callbacks(&mut self, callback_type: CallbackType, data: &EntryData) {
+        self.callbacks
+            .iter_all_mut()
+            .filter(|(cb, _)| **cb == callback_type)
+            .flat_map(|(_, cbs)| cbs)
+            .for_each(|cb| cb(data));
+    }
+
+    pub(crate) fn fire_server_callbacks(
         &mut self,
-        data: EntryData,
-        callback: impl Fn(Vec<u8>) -> Vec<u8> + Send + Sync + RefUnwindSafe + 'static,
+        callback_type: ConnectionCallbackType,
+        addr: &SocketAddr,
     ) {
-        let id = self
-            .create_entry(data)
-            .unwrap()
-            .try_next()
-            .unwrap()
-            .unwrap();
-        self.rpc_actions.insert(id, Arc::new(callback));
+        self.server_callbacks
+            .iter_all_mut()
+            .filter(|(cb, _)| **cb == callback_type)
+            .flat_map(|(_, cbs)| cbs)
+            .for_each(|cb| cb(addr));
     }
-}
 
-impl State for ServerState {
-    fn entries(&self) -> &HashMap<u16, EntryData> {
-        &self.entries
+    /// Releases an NT4 publisher of the given entry.
+    ///
+    /// Entries created by NT4 clients are deleted once their last publisher goes away, unless they
+    /// are persistent or retained.
+    #[cfg(feature = "websocket")]
+    pub(crate) fn release_publisher(&mut self, id: u16) {
+        let publishers = match self.nt4_publishers.get_mut(&id) {
+            Some(publishers) => publishers,
+            None => return,
+        };
+        *publishers -= 1;
+        if *publishers > 0 {
+            return;
+        }
+        self.nt4_publishers.remove(&id);
+
+        let keep = match self.entries.get(&id) {
+            Some(entry) => entry.is_persistent() || entry.is_retained(),
+            None => true,
+        };
+        if !keep {
+            let _ = self.remove_entry(id, EventSource::Remote);
+        }
     }
 
-    fn entries_mut(&mut self) -> &mut HashMap<u16, EntryData> {
-        &mut self.entries
+    /// Fails if a client creating another entry would go over the entry limit
+    pub(crate) fn check_entry_limit(&self) -> crate::Result<()> {
+        if self.entries.len() >= self.limits.max_entries {
+            return Err(Error::LimitExceeded {
+                what: "Entry count",
+                limit: self.limits.max_entries,
+            });
+        }
+        Ok(())
     }
 
-    fn create_entry(&mut self, data: EntryData) -> crate::Result<Receiver<u16>> {
+    /// Finds an id that isn't taken by any entry, starting from where the last search left off
+    fn free_id(&mut self) -> crate::Result<u16> {
+        if self.entries.len() >= UNASSIGNED_ID as usize {
+            return Err(Error::LimitExceeded {
+                what: "Entry count",
+                limit: UNASSIGNED_ID as usize,
+            });
+        }
+        while self.next_id == UNASSIGNED_ID || self.entries.contains_key(&self.next_id) {
+            self.next_id = self.next_id.wrapping_add(1);
+        }
         let id = self.next_id;
-        self.next_id += 1;
+        self.next_id = self.next_id.wrapping_add(1);
+        Ok(id)
+    }
+
+    /// Adds a new entry, assigning it an id and broadcasting it to every client
+    pub(crate) fn add_entry(
+        &mut self,
+        mut data: EntryData,
+        source: EventSource,
+    ) -> crate::Result<u16> {
+        if data.timestamp.is_none() {
+            data.timestamp = Some(self.server_time());
+        }
+
+        let id = self.free_id()?;
         self.entries.insert(id, data.clone());
+        self.entry_ids.insert(data.name.clone(), id);
 
         let packet = Box::new(EntryAssignment::new(
             data.name.clone(),
             data.entry_type(),
             id,
-            data.seqnum,
+            data.seqnum.0,
             data.flags,
             data.value.clone(),
         ));
-        for tx in self.clients.values() {
-            tx.unbounded_send(packet.clone()).unwrap()
-        }
+        self.broadcast(packet);
 
-        self.callbacks
-            .iter_all_mut()
-            .filter(|(cb, _)| **cb == CallbackType::Add)
-            .flat_map(|(_, cbs)| cbs)
-            .for_each(|cb| cb(&data));
+        self.fire_callbacks(CallbackType::Add, &data);
+        self.subscribers
+            .notify(EntryEventKind::Added, id, None, Some(&data), source);
+        Ok(id)
+    }
 
-        let (mut tx, rx) = channel(1);
-        tx.try_send(id).unwrap();
-        Ok(rx)
+    /// Removes an entry, broadcasting the deletion to every client
+    pub(crate) fn remove_entry(&mut self, id: u16, source: EventSource) -> crate::Result<()> {
+        self.remove_entry_except(id, source, None)
     }
 
-    fn delete_entry(&mut self, id: u16) {
-        let entry = self.entries.remove(&id).unwrap();
+    /// Removes an entry deleted by the client at `addr`, broadcasting the deletion to the others
+    pub(crate) fn remove_client_entry(&mut self, id: u16, addr: SocketAddr) -> crate::Result<()> {
+        self.remove_entry_except(id, EventSource::Remote, Some(addr))
+    }
 
-        let packet = Box::new(EntryDelete::new(id));
-        for tx in self.clients.values() {
-            tx.unbounded_send(packet.clone()).unwrap();
-        }
+    fn remove_entry_except(
+        &mut self,
+        id: u16,
+        source: EventSource,
+        except: Option<SocketAddr>,
+    ) -> crate::Result<()> {
+        let entry = self
+            .entries
+            .remove(&id)
+            .ok_or(Error::EntryNotFound { id })?;
+        self.nt4_publishers.remove(&id);
+        self.entry_ids.remove(&entry.name);
+        self.broadcast_except(Box::new(EntryDelete::new(id)), except);
 
-        self.callbacks
-            .iter_all_mut()
-            .filter(|(cb, _)| **cb == CallbackType::Delete)
-            .flat_map(|(_, cbs)| cbs)
-            .for_each(|cb| cb(&entry));
-    }
-
-    fn update_entry(&mut self, id: u16, new_value: EntryValue) {
-        if let Some(entry) = self.entries.get_mut(&id) {
-            entry.seqnum = entry.seqnum.wrapping_add(1);
-            entry.value = new_value;
-
-            let packet = Box::new(EntryUpdate::new(
-                id,
-                entry.seqnum,
-                entry.entry_type(),
-                entry.value.clone(),
-            ));
-            for tx in self.clients.values() {
-                tx.unbounded_send(packet.clone()).unwrap();
-            }
+        self.fire_callbacks(CallbackType::Delete, &entry);
+        self.subscribers
+            .notify(EntryEventKind::Deleted, id, Some(&entry), None, source);
+        Ok(())
+    }
 
-            let entry = &*entry;
+    /// Sends a packet to every client. Clients whose connection is closing are skipped, they are
+    /// removed once their connection has ended.
+    fn broadcast<P: Packet + Clone + 'static>(&self, packet: Box<P>) {
+        self.broadcast_except(packet, None);
+    }
 
-            self.callbacks
-                .iter_all_mut()
-                .filter(|(cb, _)| **cb == CallbackType::Update)
-                .flat_map(|(_, cbs)| cbs)
-                .for_each(|cb| cb(entry));
+    /// Like `broadcast`, but skips the client at `except`
+    fn broadcast_except<P: Packet + Clone + 'static>(
+        &self,
+        packet: Box<P>,
+        except: Option<SocketAddr>,
+    ) {
+        for (addr, tx) in &self.clients {
+            if Some(*addr) != except {
+                let _ = tx.unbounded_send(packet.clone());
+            }
         }
     }
 
-    fn update_entry_flags(&mut self, id: u16, flags: u8) {
-        if let Some(entry) = self.entries.get_mut(&id) {
-            entry.flags = flags;
+    /// Removes every entry, sending subscribers a `Cleared` event for each
+    pub(crate) fn remove_all_entries(&mut self, source: EventSource) {
+        self.nt4_publishers.clear();
+        self.entry_ids.clear();
+        for (id, entry) in self.entries.drain() {
+            self.subscribers
+                .notify(EntryEventKind::Cleared, id, Some(&entry), None, source);
+        }
+    }
 
-            let packet = Box::new(EntryFlagsUpdate::new(id, flags));
-            for tx in self.clients.values() {
-                tx.unbounded_send(packet.clone()).unwrap();
-            }
+    /// Creates an RPC entry whose calls are answered by `callback`, returning its id.
+    ///
+    /// Fails if `data` doesn't hold an RPC definition, or an entry of another type has the same name.
+    pub fn create_rpc(
+        &mut self,
+        data: EntryData,
+        callback: impl Fn(Vec<u8>) -> Vec<u8> + Send + Sync + RefUnwindSafe + 'static,
+    ) -> crate::Result<u16> {
+        if data.entry_type() != EntryType::RpcDefinition {
+            return Err(Error::RpcFailed {
+                reason: format!("{} is not an RPC definition", data.name),
+            });
         }
+
+        let id = match self.entry_ids.get(&data.name) {
+            Some(id) => *id,
+            None => self.add_entry(data, EventSource::Local)?,
+        };
+        let expected = self
+            .entries
+            .get(&id)
+            .map(EntryData::entry_type)
+            .ok_or(Error::EntryNotFound { id })?;
+        if expected != EntryType::RpcDefinition {
+            return Err(Error::TypeMismatch {
+                expected,
+                found: EntryType::RpcDefinition,
+            });
+        }
+
+        self.rpc_actions.insert(id, Arc::new(callback));
+        Ok(id)
+    }
+}
+
+impl State for ServerState {
+    fn entries(&self) -> &HashMap<u16, EntryData> {
+        &self.entries
     }
 
-    fn clear_entries(&mut self) {
-        self.entries.clear();
+    fn entries_mut(&mut self) -> &mut HashMap<u16, EntryData> {
+        &mut self.entries
+    }
+
+    fn entry_ids(&self) -> &BTreeMap<String, u16> {
+        &self.entry_ids
+    }
 
-        let packet = Box::new(ClearAllEntries::new());
-        for tx in self.clients.values() {
-            tx.unbounded_send(packet.clone()).unwrap();
+    fn create_entry(&mut self, data: EntryData) -> crate::Result<Receiver<u16>> {
+        let id = match self.entry_ids.get(&data.name) {
+            Some(id) => *id,
+            None => self.add_entry(data, EventSource::Local)?,
+        };
+
+        let (mut tx, rx) = channel(1);
+        // The channel has room for the id, and the receiver is still held here
+        let _ = tx.try_send(id);
+        Ok(rx)
+    }
+
+    fn set_default(&mut self, data: EntryData) -> crate::Result<Receiver<u16>> {
+        self.create_entry(data)
+    }
+
+    fn delete_entry(&mut self, id: u16) -> crate::Result<()> {
+        self.remove_entry(id, EventSource::Local)
+    }
+
+    fn update_entry(&mut self, id: u16, new_value: EntryValue) -> crate::Result<()> {
+        let now = self.server_time();
+        let entry = self
+            .entries
+            .get_mut(&id)
+            .ok_or(Error::EntryNotFound { id })?;
+        if entry.entry_type() != new_value.entry_type() {
+            return Err(Error::TypeMismatch {
+                expected: entry.entry_type(),
+                found: new_value.entry_type(),
+            });
         }
+        let old = entry.clone();
+        entry.seqnum = entry.seqnum.next();
+        entry.value = new_value;
+        entry.timestamp = Some(now);
+        let entry = entry.clone();
+
+        self.broadcast(Box::new(EntryUpdate::new(
+            id,
+            entry.seqnum.0,
+            entry.entry_type(),
+            entry.value.clone(),
+        )));
+
+        self.fire_callbacks(CallbackType::Update, &entry);
+        self.subscribers.notify(
+            EntryEventKind::Updated,
+            id,
+            Some(&old),
+            Some(&entry),
+            EventSource::Local,
+        );
+        Ok(())
+    }
+
+    fn update_entry_flags(&mut self, id: u16, flags: u8) -> crate::Result<()> {
+        let entry = self
+            .entries
+            .get_mut(&id)
+            .ok_or(Error::EntryNotFound { id })?;
+        let old = entry.clone();
+        entry.set_flags(flags);
+        let entry = entry.clone();
+
+        self.broadcast(Box::new(EntryFlagsUpdate::new(id, flags)));
+
+        self.subscribers.notify(
+            EntryEventKind::FlagsChanged,
+            id,
+            Some(&old),
+            Some(&entry),
+            EventSource::Local,
+        );
+        Ok(())
+    }
+
+    fn clear_entries(&mut self) -> crate::Result<()> {
+        self.remove_all_entries(EventSource::Local);
+        self.broadcast(Box::new(ClearAllEntries::new()));
+        Ok(())
     }
 
     fn add_callback(
@@ -181,4 +527,85 @@ impl State for ServerState {
     ) {
         self.callbacks.insert(callback_type, Box::new(action));
     }
+
+    fn subscribe(&mut self, prefix: String) -> Subscription {
+        self.subscribers.subscribe(prefix)
+    }
+}
+
+#[cfg(all(test, feature = "websocket"))]
+mod tests {
+    use crate::nt::connection::Protocol;
+    use crate::{EntryData, EntryValue, NTBackend, NetworkTables};
+    use std::time::Duration;
+    use tokio::runtime::Runtime;
+
+    /// Waits for the entry named `name` to have the value `value`
+    async fn wait_for_value<T: NTBackend>(nt: &NetworkTables<T>, name: &str, value: EntryValue) {
+        let found = async {
+            while nt
+                .get_entry_by_name(name)
+                .and_then(|e| e.value().ok())
+                .map(|e| e.value)
+                != Some(value.clone())
+            {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), found)
+            .await
+            .unwrap_or_else(|_| panic!("{} never became {:?}", name, value));
+    }
+
+    #[test]
+    fn nt3_and_nt4_clients_are_served_on_their_standard_ports() {
+        let _ports = crate::proto::lock_standard_ports();
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let _server = NetworkTables::bind("127.0.0.1:1735", "server")
+                .await
+                .unwrap();
+            let nt3 = NetworkTables::connect("127.0.0.1:1735", "nt3")
+                .await
+                .unwrap();
+            let nt4 = NetworkTables::connect_nt4("127.0.0.1", "nt4")
+                .await
+                .unwrap();
+            assert_eq!(nt3.connection_info().unwrap().protocol, Protocol::NT3);
+            assert_eq!(nt4.connection_info().unwrap().protocol, Protocol::NT4);
+            assert_eq!(nt4.connection_info().unwrap().addr.port(), 5810);
+
+            let data = EntryData::new("/shared".to_string(), 0, EntryValue::Double(1.0));
+            nt3.create_entry(data).await.unwrap();
+            wait_for_value(&nt4, "/shared", EntryValue::Double(1.0)).await;
+        });
+    }
+
+    #[test]
+    fn nt3_clients_update_integer_topics_created_by_nt4_clients() {
+        let _ports = crate::proto::lock_standard_ports();
+        let mut rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let server = NetworkTables::bind("127.0.0.1:1735", "server")
+                .await
+                .unwrap();
+            let nt4 = NetworkTables::connect_nt4("127.0.0.1", "nt4")
+                .await
+                .unwrap();
+            let data = EntryData::new("/count".to_string(), 0, EntryValue::Integer(1));
+            nt4.create_entry(data).await.unwrap();
+            wait_for_value(&server, "/count", EntryValue::Integer(1)).await;
+
+            // NT3 has no integers, so the client sees the topic as a double
+            let nt3 = NetworkTables::connect("127.0.0.1:1735", "nt3")
+                .await
+                .unwrap();
+            wait_for_value(&nt3, "/count", EntryValue::Double(1.0)).await;
+            let id = *nt3.get_entry_by_name("/count").unwrap().id();
+            nt3.update_entry(id, EntryValue::Double(5.0)).unwrap();
+
+            wait_for_value(&server, "/count", EntryValue::Integer(5)).await;
+            wait_for_value(&nt4, "/count", EntryValue::Integer(5)).await;
+        });
+    }
 }
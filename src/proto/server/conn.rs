@@ -1,58 +1,269 @@
 use crate::error::Error;
+use crate::nt::connection::{ConnectionInfo, Protocol};
 use crate::proto::server::ServerState;
 use crate::proto::State;
-use crate::{CallbackType, ConnectionCallbackType, EntryData};
-use futures_channel::mpsc::{unbounded, Receiver, UnboundedReceiver};
+use crate::{CallbackType, EntryData, EntryEventKind, EventSource};
+use futures_channel::mpsc::{channel, unbounded, Receiver, Sender, UnboundedReceiver};
+use futures_channel::oneshot;
+use futures_util::future::{self, Either, FutureExt, Shared};
 use futures_util::sink::{Sink, SinkExt};
-use futures_util::stream::Stream;
-use futures_util::{StreamExt, TryStreamExt};
+use futures_util::stream::{self, Stream};
+use futures_util::{pin_mut, StreamExt, TryStreamExt};
 use nt_network::codec::NTCodec;
+use nt_network::nt2::NT2Codec;
 use nt_network::{
-    EntryAssignment, NTVersion, Packet, ProtocolVersionUnsupported, ReceivedPacket, RpcResponse,
+    EntryAssignment, EntryUpdate, NTVersion, Packet, ReceivedPacket, RpcResponse, SequenceNumber,
     ServerHello, ServerHelloComplete,
 };
+use std::future::Future;
 use std::net::SocketAddr;
 use std::panic;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tokio::net::{TcpListener, TcpStream};
+#[cfg(feature = "websocket")]
+use tokio_tungstenite::tungstenite::handshake::server::{
+    Callback, ErrorResponse, Request, Response,
+};
 use tokio_util::codec::Decoder;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+
+/// The number of bytes it takes to tell which protocol a client speaks
+const PROTOCOL_PREFIX_LEN: usize = 3;
+
+/// How long a new client has to send enough bytes to tell which protocol it speaks
+const DETECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long to wait for more bytes when a client has sent too few to tell its protocol
+const PEEK_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Tells the tasks of the server's clients when the server stops
+#[derive(Clone)]
+pub(super) struct Stop {
+    signal: Shared<oneshot::Receiver<()>>,
+    /// Never sent to, the server waits for every clone of this to be dropped before it has stopped
+    _running: Sender<()>,
+}
+
+impl Stop {
+    fn new() -> (Stopper, Stop) {
+        let (signal_tx, signal_rx) = oneshot::channel();
+        let (running_tx, running_rx) = channel(0);
+        let stopper = Stopper {
+            signal: signal_tx,
+            running: running_rx,
+        };
+        let stop = Stop {
+            signal: signal_rx.shared(),
+            _running: running_tx,
+        };
+        (stopper, stop)
+    }
+
+    /// Completes once the server has started stopping
+    pub(super) fn stopping(&self) -> impl Future<Output = ()> + Unpin {
+        self.signal.clone().map(|_| ())
+    }
+}
+
+/// Stops the tasks holding a `Stop`
+struct Stopper {
+    signal: oneshot::Sender<()>,
+    running: Receiver<()>,
+}
+
+impl Stopper {
+    /// Tells the tasks to stop, completing once all of them have
+    async fn stop(self) {
+        let Stopper {
+            signal,
+            mut running,
+        } = self;
+        drop(signal);
+        while running.next().await.is_some() {}
+    }
+}
 
+/// The addresses the server listens on when bound to `ip`
+#[cfg(not(feature = "websocket"))]
+fn listen_addresses(ip: &str) -> Vec<String> {
+    vec![ip.to_string()]
+}
+
+/// The addresses the server listens on when bound to `ip`. The server also listens on the NT4 port
+/// of the same host, where standard NT4 clients connect.
+#[cfg(feature = "websocket")]
+fn listen_addresses(ip: &str) -> Vec<String> {
+    use nt_network::nt4::NT4_PORT;
+
+    let mut ips = vec![ip.to_string()];
+    if let Some((host, port)) = ip.rsplit_once(':') {
+        if port.parse() != Ok(NT4_PORT) {
+            ips.push(format!("{}:{}", host, NT4_PORT));
+        }
+    }
+    ips
+}
+
+/// Accepts clients on each of the `listen_addresses` of `ip` until `close_rx` fires or is dropped,
+/// then disconnects every client
 pub async fn connection(
     ip: String,
     state: Arc<Mutex<ServerState>>,
-    _close_rx: Receiver<()>,
+    mut close_rx: Receiver<()>,
+    ready_tx: oneshot::Sender<crate::Result<()>>,
 ) -> crate::Result<()> {
-    let mut listener = TcpListener::bind(ip).await?;
+    let ips = listen_addresses(&ip);
+    let mut listeners = Vec::with_capacity(ips.len());
+    for ip in &ips {
+        match TcpListener::bind(ip.as_str()).await {
+            Ok(listener) => listeners.push(listener),
+            // A failure to bind is handed to the caller waiting on `ready_tx` rather than returned
+            Err(e) => {
+                return match ready_tx.send(Err(e.into())) {
+                    Ok(()) => Ok(()),
+                    Err(res) => res,
+                }
+            }
+        }
+    }
+    info!(?ips, "Server listening");
+    let _ = ready_tx.send(Ok(()));
+    // Clients are told apart by what they send, so every listener accepts every protocol
+    let mut incoming = stream::select_all(listeners);
+    let (stopper, stop) = Stop::new();
 
-    //TODO: integrate close_rx
-    loop {
-        let (mut conn, addr) = listener.accept().await?;
+    let res = loop {
+        let accepted = tokio::select! {
+            Some(accepted) = incoming.next() => accepted,
+            _ = close_rx.next() => break Ok(()),
+        };
+        let conn = match accepted {
+            Ok(conn) => conn,
+            Err(e) => {
+                error!(error = %e, "Server failed");
+                break Err(e.into());
+            }
+        };
+        let addr = match conn.peer_addr() {
+            Ok(addr) => addr,
+            Err(e) => {
+                warn!(error = %e, "Failed to accept client");
+                continue;
+            }
+        };
 
-        let mut buf = [0; 4];
+        // Clients are accepted on their own task, so that one that never finishes its handshake
+        // doesn't hold up the others
+        let state = Arc::clone(&state);
+        let stop = stop.clone();
+        let span = info_span!("client", %addr, name = field::Empty);
+        tokio::spawn(
+            async move {
+                let mut stopping = stop.stopping();
+                tokio::select! {
+                    res = accept(conn, addr, &state, &stop) => if let Err(e) = res {
+                        warn!(error = %e, "Failed to accept client");
+                    },
+                    _ = &mut stopping => {}
+                }
+            }
+            .instrument(span),
+        );
+    };
 
-        // Can immediately figure out the protocol based on the first few bytes. NT is binary and WS starts with a HTTP request
-        conn.peek(&mut buf).await?;
+    info!("Server stopping");
+    drop(incoming);
+    drop(stop);
+    stopper.stop().await;
+    res
+}
 
-        match std::str::from_utf8(&buf[..]) {
-            // Spec says that the upgrade must be a GET, so check for that
-            Ok(s) if s.starts_with("GET") => {
-                println!("Client is websocket");
-                handle_ws_conn(addr, conn, &state).await.unwrap();
-            }
-            // If the first bytes weren't "GET" it cannot be a websocket client
-            _ => {
-                println!("Connection is TCP");
+/// Starts the task of a newly accepted client, speaking whichever protocol it opens with
+async fn accept(
+    mut conn: TcpStream,
+    addr: SocketAddr,
+    state: &Arc<Mutex<ServerState>>,
+    stop: &Stop,
+) -> crate::Result<()> {
+    let mut buf = [0; 4];
+
+    // Can figure out the protocol based on the first few bytes. NT is binary and WS starts with a HTTP request
+    tokio::time::timeout(DETECT_TIMEOUT, peek_prefix(&mut conn, &mut buf))
+        .await
+        .map_err(|_| Error::Timeout)??;
 
-                let (tx, rx) = unbounded::<Box<dyn Packet>>();
-                state.lock().unwrap().clients.insert(addr, tx);
-                tokio::spawn(client_conn(
+    match std::str::from_utf8(&buf[..]) {
+        // Spec says that the upgrade must be a GET, so check for that
+        Ok(s) if s.starts_with("GET") => {
+            debug!(protocol = "websocket", "Accepted connection");
+            handle_ws_conn(addr, conn, state, stop).await?;
+        }
+        // NT2 clients open with a hello for revision 0x0200, and need their own codec
+        _ if buf[..PROTOCOL_PREFIX_LEN] == [0x01, 0x02, 0x00] => {
+            debug!(protocol = "NT2", "Accepted connection");
+
+            let (tx, rx) = unbounded::<Box<dyn Packet>>();
+            let limits = {
+                let mut state = state.lock().unwrap();
+                state.clients.insert(addr, tx);
+                state.limits().decode()
+            };
+            tokio::spawn(
+                client_conn(
                     addr,
-                    NTCodec.framed(conn).map_err(Error::from),
+                    NT2Codec::with_limits(limits)
+                        .framed(conn)
+                        .map_err(Error::from)
+                        .sink_map_err(Error::from),
                     rx,
-                    Arc::clone(&state),
-                ));
-            }
+                    Arc::clone(state),
+                    stop.clone(),
+                )
+                .in_current_span(),
+            );
+        }
+        // If the first bytes weren't "GET" it cannot be a websocket client
+        _ => {
+            debug!(protocol = "NT3", "Accepted connection");
+
+            let (tx, rx) = unbounded::<Box<dyn Packet>>();
+            let limits = {
+                let mut state = state.lock().unwrap();
+                state.clients.insert(addr, tx);
+                state.limits().decode()
+            };
+            tokio::spawn(
+                client_conn(
+                    addr,
+                    NTCodec::with_limits(limits)
+                        .framed(conn)
+                        .map_err(Error::from)
+                        .sink_map_err(Error::from),
+                    rx,
+                    Arc::clone(state),
+                    stop.clone(),
+                )
+                .in_current_span(),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Peeks at the start of what the client sent until `PROTOCOL_PREFIX_LEN` bytes are available,
+/// as they may arrive over several reads
+async fn peek_prefix(conn: &mut TcpStream, buf: &mut [u8]) -> crate::Result<()> {
+    loop {
+        let read = conn.peek(buf).await?;
+        if read >= PROTOCOL_PREFIX_LEN {
+            return Ok(());
+        }
+        if read == 0 {
+            return Err(Error::ConnectionAborted);
         }
+        // Peeking doesn't consume what is there, so it would return right away until more arrives
+        tokio::time::delay_for(PEEK_INTERVAL).await;
     }
 }
 
@@ -61,6 +272,7 @@ async fn handle_ws_conn(
     _addr: SocketAddr,
     mut conn: TcpStream,
     _state: &Arc<Mutex<ServerState>>,
+    _stop: &Stop,
 ) -> crate::Result<()> {
     // no http libs here, so lets make a fun response by hand
     let resp = "\
@@ -70,52 +282,87 @@ async fn handle_ws_conn(
     \r\n\
     Server is not configured to serve websocket clients.";
     use tokio::io::AsyncWriteExt;
-    conn.write(resp.as_bytes()).await?;
-    println!("Rejecting websocket client as server is not configured with websocket feature.");
+    conn.write_all(resp.as_bytes()).await?;
+    warn!("Rejecting websocket client, as the server was built without the websocket feature");
     Ok(())
 }
 
+/// Picks the subprotocol to answer a websocket handshake with, recording what the client asked for
 #[cfg(feature = "websocket")]
-async fn handle_ws_conn(
-    addr: SocketAddr,
-    conn: TcpStream,
-    state: &Arc<Mutex<ServerState>>,
-) -> crate::Result<()> {
-    use crate::proto::ws::WSCodec;
-    use std::borrow::Cow;
-    use tokio_tungstenite::tungstenite::http::HeaderValue;
-    use tokio_tungstenite::tungstenite::{
-        handshake::server::{Request, Response},
-        protocol::{
-            frame::{coding::CloseCode, CloseFrame},
-            Message,
-        },
-    };
+struct SubprotocolCallback<'a> {
+    client_valid: &'a mut bool,
+    is_nt4: &'a mut bool,
+    path: &'a mut String,
+}
 
-    let mut client_valid = true;
+#[cfg(feature = "websocket")]
+impl Callback for SubprotocolCallback<'_> {
+    fn on_request(self, req: &Request, mut res: Response) -> Result<Response, ErrorResponse> {
+        use nt_network::nt4::NT4_SUBPROTOCOL;
+        use tokio_tungstenite::tungstenite::http::HeaderValue;
 
-    let mut conn = tokio_tungstenite::accept_hdr_async(conn, |req: &Request, mut res: Response| {
-        let default = HeaderValue::from_static("");
+        // Get protocol from headers. One that isn't visible ASCII can't name a subprotocol
         let proto = req
             .headers()
             .get("Sec-WebSocket-Protocol")
-            .unwrap_or(&default)
-            .to_str()
-            .unwrap(); // Get protocol from headers
-        if proto.to_lowercase().contains("networktables") {
+            .cloned()
+            .unwrap_or_else(|| HeaderValue::from_static(""));
+        let proto_str = proto.to_str().unwrap_or_default();
+        *self.path = req.uri().path().to_string();
+
+        // Clients may offer several subprotocols, NT4 is preferred when it is one of them
+        if proto_str.split(',').any(|p| p.trim() == NT4_SUBPROTOCOL) {
+            *self.is_nt4 = true;
             res.headers_mut().insert(
                 "Sec-WebSocket-Protocol",
-                HeaderValue::from_str(proto).unwrap(),
+                HeaderValue::from_static(NT4_SUBPROTOCOL),
             );
+        } else if proto_str.to_lowercase().contains("networktables") {
+            res.headers_mut()
+                .insert("Sec-WebSocket-Protocol", proto.clone());
         } else {
-            client_valid = false;
+            *self.client_valid = false;
         }
         Ok(res)
-    })
-    .await?;
+    }
+}
+
+#[cfg(feature = "websocket")]
+async fn handle_ws_conn(
+    addr: SocketAddr,
+    conn: TcpStream,
+    state: &Arc<Mutex<ServerState>>,
+    stop: &Stop,
+) -> crate::Result<()> {
+    use crate::proto::ws::{NT4Codec, WSCodec};
+    use percent_encoding::percent_decode_str;
+    use std::borrow::Cow;
+    use tokio_tungstenite::tungstenite::protocol::{
+        frame::{coding::CloseCode, CloseFrame},
+        Message, WebSocketConfig,
+    };
+
+    let mut client_valid = true;
+    let mut is_nt4 = false;
+    let mut path = String::new();
+
+    let limits = state.lock().unwrap().limits().decode();
+    let config = WebSocketConfig {
+        max_send_queue: None,
+        max_message_size: Some(limits.max_frame_size),
+        max_frame_size: Some(limits.max_frame_size),
+    };
+
+    let callback = SubprotocolCallback {
+        client_valid: &mut client_valid,
+        is_nt4: &mut is_nt4,
+        path: &mut path,
+    };
+    let mut conn =
+        tokio_tungstenite::accept_hdr_async_with_config(conn, callback, Some(config)).await?;
 
     if !client_valid {
-        println!("Rejecting client for not specifying correct protocol");
+        warn!("Rejecting websocket client that didn't ask for a NetworkTables subprotocol");
         let frame = CloseFrame {
             code: CloseCode::Unsupported, // WS 1003
             reason: Cow::Borrowed("NetworkTables protocol required."),
@@ -125,16 +372,41 @@ async fn handle_ws_conn(
         return Ok(());
     }
 
-    let codec = WSCodec::new(conn);
-
     let (tx, rx) = unbounded::<Box<dyn Packet>>();
     state.lock().unwrap().clients.insert(addr, tx);
-    tokio::spawn(client_conn(
-        addr,
-        codec.map_err(Error::from),
-        rx,
-        Arc::clone(&state),
-    ));
+
+    if is_nt4 {
+        // NT4 clients connect to /nt/<name>
+        let name = path.strip_prefix("/nt/").unwrap_or_default();
+        let name = percent_decode_str(name).decode_utf8_lossy().into_owned();
+        Span::current().record("name", name.as_str());
+        debug!(protocol = "NT4", "Websocket handshake complete");
+        tokio::spawn(
+            super::nt4::client_conn(
+                addr,
+                name,
+                NT4Codec::with_limits(conn, limits),
+                rx,
+                Arc::clone(state),
+                stop.clone(),
+            )
+            .in_current_span(),
+        );
+        return Ok(());
+    }
+
+    debug!(protocol = "NT3", "Websocket handshake complete");
+    let codec = WSCodec::with_limits(conn, limits);
+    tokio::spawn(
+        client_conn(
+            addr,
+            codec.map_err(Error::from),
+            rx,
+            Arc::clone(state),
+            stop.clone(),
+        )
+        .in_current_span(),
+    );
     Ok(())
 }
 
@@ -143,97 +415,185 @@ async fn client_conn<T>(
     conn: T,
     mut packet_rx: UnboundedReceiver<Box<dyn Packet>>,
     state: Arc<Mutex<ServerState>>,
+    stop: Stop,
 ) -> crate::Result<()>
 where
-    T: Sink<Box<dyn Packet>> + Stream<Item = crate::Result<ReceivedPacket>> + Send + 'static,
+    T: Sink<Box<dyn Packet>, Error = Error>
+        + Stream<Item = crate::Result<ReceivedPacket>>
+        + Send
+        + 'static,
 {
     let (mut tx, mut rx) = conn.split();
 
-    tokio::spawn(async move {
-        while let Some(packet) = packet_rx.next().await {
-            let _ = tx.send(packet).await;
+    let mut stopping = stop.stopping();
+    // Completes with `Ok` once the server has stopped
+    let writer = async move {
+        loop {
+            let packet = tokio::select! {
+                packet = packet_rx.next() => packet,
+                _ = &mut stopping => break,
+            };
+            match packet {
+                Some(packet) => {
+                    if let Err(e) = tx.send(packet).await {
+                        debug!(error = %e, "Failed to send packet");
+                        return Err(e);
+                    }
+                }
+                None => return Ok(()),
+            }
+        }
+
+        // Packets queued before the server stopped are still sent
+        while let Ok(packet) = packet_rx.try_recv() {
+            tx.send(packet).await?;
         }
-    });
+        let _ = tx.close().await;
+        Ok(())
+    };
 
-    while let Some(packet) = rx.next().await {
-        if let Ok(packet) = packet {
-            let packet: ReceivedPacket = packet;
+    // The name and protocol of the client, from its hello
+    let mut hello_info = None;
+    // Completes with `Ok` once the client has closed the connection, or with the error that got it
+    // disconnected
+    let reader = async {
+        while let Some(packet) = rx.next().await {
+            let packet: ReceivedPacket = packet?;
+            state.lock().unwrap().mark_received(addr);
             match packet {
                 ReceivedPacket::ClientHello(hello) => {
-                    if hello.version != NTVersion::V3 {
-                        state.lock().unwrap().clients[&addr]
-                            .unbounded_send(Box::new(ProtocolVersionUnsupported::new(
-                                NTVersion::V3,
-                            )))
-                            .unwrap();
-                        break;
+                    Span::current().record("name", hello.name.as_str());
+                    debug!(version = ?hello.version, "Received ClientHello");
+                    let mut state = state.lock().unwrap();
+                    let tx = state
+                        .clients
+                        .get(&addr)
+                        .cloned()
+                        .ok_or(Error::Disconnected)?;
+
+                    // NT2 has no server hello, the entries are sent right away
+                    if hello.version == NTVersion::V3 {
+                        // Clients are identified by name
+                        let flags = if state.client_seen(&hello.name) { 1 } else { 0 };
+                        let _ = tx.unbounded_send(Box::new(ServerHello::new(
+                            flags,
+                            state.server_name.clone(),
+                        )));
                     }
-                    let state = state.lock().unwrap();
-                    let tx = &state.clients[&addr];
-                    tx.unbounded_send(Box::new(ServerHello::new(0, state.server_name.clone())))
-                        .unwrap();
 
                     for (id, entry) in state.entries() {
                         let packet = Box::new(EntryAssignment::new(
                             entry.name.clone(),
                             entry.entry_type(),
                             *id,
-                            entry.seqnum,
+                            entry.seqnum.0,
                             entry.flags,
                             entry.value.clone(),
                         ));
-                        tx.unbounded_send(packet).unwrap();
+                        let _ = tx.unbounded_send(packet);
                     }
 
-                    tx.unbounded_send(Box::new(ServerHelloComplete)).unwrap();
-                }
-                ReceivedPacket::ClientHelloComplete => state
-                    .lock()
-                    .unwrap()
-                    .server_callbacks
-                    .iter_all_mut()
-                    .filter(|(cb, _)| **cb == ConnectionCallbackType::ClientConnected)
-                    .flat_map(|(_, cbs)| cbs)
-                    .for_each(|cb| cb(&addr)),
-                ReceivedPacket::EntryAssignment(ea) => {
-                    if ea.entry_id == 0xFFFF {
-                        let _ = state.lock().unwrap().create_entry(EntryData::new(
-                            ea.entry_name,
-                            ea.entry_flags,
-                            ea.entry_value,
+                    let _ = tx.unbounded_send(Box::new(ServerHelloComplete));
+
+                    // NT2 clients don't send a ClientHelloComplete, so they're connected once the entries are sent
+                    if hello.version == NTVersion::V2 {
+                        state.client_connected(ConnectionInfo::new(
+                            addr,
+                            hello.name,
+                            Protocol::NT2,
                         ));
+                    } else {
+                        hello_info = Some((hello.name, Protocol::NT3));
+                    }
+                }
+                ReceivedPacket::ClientHelloComplete => {
+                    debug!("Received ClientHelloComplete");
+                    if let Some((name, protocol)) = hello_info.take() {
+                        let info = ConnectionInfo::new(addr, name, protocol);
+                        state.lock().unwrap().client_connected(info);
+                    }
+                }
+                ReceivedPacket::EntryAssignment(ea) if ea.entry_id == 0xFFFF => {
+                    let mut state = state.lock().unwrap();
+                    let existing = state
+                        .entry_ids
+                        .get(&ea.entry_name)
+                        .and_then(|id| Some((*id, state.entries.get(id)?)));
+                    match existing {
+                        // The client is told the id of the existing entry instead, completing its creation
+                        Some((id, entry)) => {
+                            let packet = EntryAssignment::new(
+                                entry.name.clone(),
+                                entry.entry_type(),
+                                id,
+                                entry.seqnum.0,
+                                entry.flags,
+                                entry.value.clone(),
+                            );
+                            if let Some(tx) = state.clients.get(&addr) {
+                                let _ = tx.unbounded_send(Box::new(packet));
+                            }
+                        }
+                        None => {
+                            state.check_entry_limit()?;
+                            let data =
+                                EntryData::new(ea.entry_name, ea.entry_flags, ea.entry_value);
+                            state.add_entry(data, EventSource::Remote)?;
+                        }
                     }
-                    // should i be evil here? nasal demons are fun
                 }
                 ReceivedPacket::EntryUpdate(eu) => {
                     let mut state = state.lock().unwrap();
+                    let now = state.server_time();
                     if let Some(entry) = state.entries.get_mut(&eu.entry_id) {
-                        if eu.entry_seqnum > entry.seqnum && eu.entry_type == entry.entry_type() {
-                            entry.value = eu.entry_value.clone();
+                        // Stale updates lose the conflict, and aren't passed on to other clients.
+                        // NT3 updates of NT4 only types arrive widened, so only the wire types
+                        // have to match.
+                        let seqnum = SequenceNumber::from(eu.entry_seqnum);
+                        let entry_type = entry.entry_type();
+                        if !seqnum.is_newer_than(entry.seqnum)
+                            || eu.entry_type.nt3_type() != entry_type.nt3_type()
+                        {
+                            debug!(entry_id = eu.entry_id, "Ignoring stale or mistyped update");
+                            continue;
                         }
-                        entry.seqnum += 1;
+                        let old = entry.clone();
+                        entry.value = eu.entry_value.narrow_to(entry_type);
+                        entry.seqnum = seqnum;
+                        entry.timestamp = Some(now);
+
                         let entry = entry.clone();
+                        let update = EntryUpdate::new(
+                            eu.entry_id,
+                            eu.entry_seqnum,
+                            entry_type,
+                            entry.value.clone(),
+                        );
                         for tx in state
                             .clients
                             .iter()
                             .filter(|(_addr, _)| **_addr != addr)
                             .map(|(_, tx)| tx)
                         {
-                            tx.unbounded_send(Box::new(eu.clone())).unwrap();
+                            let _ = tx.unbounded_send(Box::new(update.clone()));
                         }
 
-                        state
-                            .callbacks
-                            .iter_all_mut()
-                            .filter(|(cb, _)| **cb == CallbackType::Update)
-                            .flat_map(|(_, cbs)| cbs)
-                            .for_each(|cb| cb(&entry));
+                        state.fire_callbacks(CallbackType::Update, &entry);
+                        state.subscribers.notify(
+                            EntryEventKind::Updated,
+                            eu.entry_id,
+                            Some(&old),
+                            Some(&entry),
+                            EventSource::Remote,
+                        );
                     }
                 }
                 ReceivedPacket::EntryFlagsUpdate(efu) => {
                     let mut state = state.lock().unwrap();
                     if let Some(entry) = state.entries.get_mut(&efu.entry_id) {
-                        entry.flags = efu.entry_flags;
+                        let old = entry.clone();
+                        entry.set_flags(efu.entry_flags);
+                        let entry = entry.clone();
 
                         for tx in state
                             .clients
@@ -241,91 +601,85 @@ where
                             .filter(|(_addr, _)| **_addr != addr)
                             .map(|(_, tx)| tx)
                         {
-                            tx.unbounded_send(Box::new(efu)).unwrap();
+                            let _ = tx.unbounded_send(Box::new(efu));
                         }
+
+                        state.subscribers.notify(
+                            EntryEventKind::FlagsChanged,
+                            efu.entry_id,
+                            Some(&old),
+                            Some(&entry),
+                            EventSource::Remote,
+                        );
                     }
                 }
                 ReceivedPacket::EntryDelete(ed) => {
+                    // The entry may have just been deleted by someone else
                     let mut state = state.lock().unwrap();
-                    let entry = state.entries.remove(&ed.entry_id).unwrap();
-
+                    if state.remove_client_entry(ed.entry_id, addr).is_err() {
+                        debug!(entry_id = ed.entry_id, "Ignoring deletion of unknown entry");
+                    }
+                }
+                ReceivedPacket::ClearAllEntries(cea) if cea.is_valid() => {
+                    let mut state = state.lock().unwrap();
+                    state.remove_all_entries(EventSource::Remote);
                     for tx in state
                         .clients
                         .iter()
                         .filter(|(_addr, _)| **_addr != addr)
                         .map(|(_, tx)| tx)
                     {
-                        tx.unbounded_send(Box::new(ed)).unwrap();
-                    }
-
-                    state
-                        .callbacks
-                        .iter_all_mut()
-                        .filter(|(cb, _)| **cb == CallbackType::Delete)
-                        .flat_map(|(_, cbs)| cbs)
-                        .for_each(|cb| cb(&entry));
-                }
-                ReceivedPacket::ClearAllEntries(cea) => {
-                    if cea.is_valid() {
-                        let mut state = state.lock().unwrap();
-                        state.entries.clear();
-                        for tx in state
-                            .clients
-                            .iter()
-                            .filter(|(_addr, _)| **_addr != addr)
-                            .map(|(_, tx)| tx)
-                        {
-                            tx.unbounded_send(Box::new(cea)).unwrap();
-                        }
+                        let _ = tx.unbounded_send(Box::new(cea));
                     }
                 }
                 ReceivedPacket::RpcExecute(rpc) => {
+                    debug!(entry_id = rpc.entry_id, "Executing RPC");
                     let state = state.lock().unwrap();
-                    let client = state.clients.get(&addr).unwrap().clone();
+                    let client = state
+                        .clients
+                        .get(&addr)
+                        .cloned()
+                        .ok_or(Error::Disconnected)?;
 
                     match state.rpc_actions.get(&rpc.entry_id) {
                         Some(func) => {
                             let func = func.clone();
                             tokio::spawn(async move {
-                                let result =
-                                    match panic::catch_unwind(|| func(rpc.parameter.clone())) {
-                                        Ok(res) => res,
-                                        Err(_) => Vec::new(),
-                                    };
-
-                                client
-                                    .unbounded_send(Box::new(RpcResponse::new(
-                                        rpc.entry_id,
-                                        rpc.unique_id,
-                                        result,
-                                    )))
-                                    .unwrap();
+                                let result: Vec<u8> =
+                                    panic::catch_unwind(|| func(rpc.parameter.clone()))
+                                        .unwrap_or_default();
+
+                                let _ = client.unbounded_send(Box::new(RpcResponse::new(
+                                    rpc.entry_id,
+                                    rpc.unique_id,
+                                    result,
+                                )));
                             });
                         }
                         None => {
-                            client
-                                .unbounded_send(Box::new(RpcResponse::new(
-                                    rpc.entry_id,
-                                    rpc.unique_id,
-                                    Vec::new(),
-                                )))
-                                .unwrap();
+                            let _ = client.unbounded_send(Box::new(RpcResponse::new(
+                                rpc.entry_id,
+                                rpc.unique_id,
+                                Vec::new(),
+                            )));
                         }
                     }
                 }
                 _ => {}
             }
         }
-    }
+        Ok(())
+    };
 
-    let mut state = state.lock().unwrap();
-    state.clients.remove(&addr);
+    // The connection ends when the client goes away or misbehaves, or once the writer has closed it
+    // as the server stops
+    pin_mut!(reader, writer);
+    let reason = match future::select(reader, writer).await {
+        Either::Left((Ok(()), _)) => "The client closed the connection".to_string(),
+        Either::Right((Ok(()), _)) => "The server stopped".to_string(),
+        Either::Left((Err(e), _)) | Either::Right((Err(e), _)) => e.to_string(),
+    };
 
-    state
-        .server_callbacks
-        .iter_all_mut()
-        .filter(|(cb, _)| **cb == ConnectionCallbackType::ClientDisconnected)
-        .flat_map(|(_, cbs)| cbs)
-        .for_each(|cb| cb(&addr));
+    state.lock().unwrap().client_disconnected(addr, reason);
     Ok(())
 }
@@ -0,0 +1,43 @@
+//! Limits protecting the server from clients that send more than it can handle
+
+use nt_network::DecodeLimits;
+
+/// The largest packets, and the most entries, the server accepts from its clients
+///
+/// A client that goes over any of these is disconnected, with the limit it broke given as the
+/// reason in its `ConnectionEvent::ClientDisconnected`. Changes apply to clients that connect
+/// afterwards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    /// The longest string a client can send, in bytes. Raw data and RPC parameters count as strings.
+    pub max_string_len: usize,
+    /// The most elements in an array value sent by a client
+    pub max_array_len: usize,
+    /// The most entries the server holds before clients can't create any more. NT4 clients are
+    /// also limited to this many subscriptions and publishers each.
+    pub max_entries: usize,
+    /// The largest single packet or websocket message a client can send, in bytes
+    pub max_frame_size: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_string_len: 1 << 20,
+            max_array_len: 1 << 16,
+            max_entries: 1 << 14,
+            max_frame_size: 4 << 20,
+        }
+    }
+}
+
+impl Limits {
+    /// The limits enforced by the codecs of the server's connections
+    pub(crate) fn decode(&self) -> DecodeLimits {
+        DecodeLimits {
+            max_string_len: self.max_string_len,
+            max_array_len: self.max_array_len,
+            max_frame_size: self.max_frame_size,
+        }
+    }
+}
@@ -0,0 +1,591 @@
+use crate::error::Error;
+use crate::nt::connection::{ConnectionInfo, Protocol};
+use crate::proto::server::conn::Stop;
+use crate::proto::server::ServerState;
+use crate::proto::ws::NT4Codec;
+use crate::{CallbackType, EntryData, EntryEventKind, EventSource};
+use futures_channel::mpsc::UnboundedReceiver;
+use futures_util::sink::SinkExt;
+use futures_util::StreamExt;
+use nt_network::nt4::{
+    Announce, ControlMessage, NT4Frame, Properties, PropertiesUpdate, Publish, Subscribe,
+    Unannounce, ValueFrame, RTT_TOPIC_ID,
+};
+use nt_network::types::EntryType;
+use nt_network::{EntryFlagsUpdate, EntryUpdate, Packet, ReceivedPacket};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// How often queued values are flushed to a client without any subscriptions
+const DEFAULT_PERIOD: Duration = Duration::from_millis(100);
+
+/// A topic this client has announced it will publish, but hasn't sent a value for yet
+struct PendingPublish {
+    name: String,
+    entry_type: EntryType,
+    properties: Properties,
+}
+
+/// A topic that has been announced to the client
+struct AnnouncedTopic {
+    name: String,
+    /// The properties of the topic as last sent to the client
+    properties: Properties,
+}
+
+/// State of a single NT4 client connected to the server
+///
+/// Entry ids are used directly as NT4 topic ids.
+#[derive(Default)]
+struct Session {
+    subscriptions: HashMap<i32, Subscribe>,
+    /// Topics that have been announced to the client, by id
+    announced: HashMap<u16, AnnouncedTopic>,
+    /// Entries this client publishes to, by pubuid
+    publishers: HashMap<i32, u16>,
+    pending_publishes: HashMap<i32, PendingPublish>,
+    /// Latest values waiting for the next periodic flush, for subscriptions without `all`
+    queued: HashMap<u16, ValueFrame>,
+}
+
+pub async fn client_conn(
+    addr: SocketAddr,
+    name: String,
+    conn: NT4Codec,
+    mut packet_rx: UnboundedReceiver<Box<dyn Packet>>,
+    state: Arc<Mutex<ServerState>>,
+    stop: Stop,
+) -> crate::Result<()> {
+    let (mut tx, mut rx) = conn.split();
+    let mut session = Session::default();
+
+    state
+        .lock()
+        .unwrap()
+        .client_connected(ConnectionInfo::new(addr, name, Protocol::NT4));
+
+    let mut period = session.period();
+    let mut flush = tokio::time::interval(period);
+
+    let mut stopping = stop.stopping();
+    // Why the connection ended, or `None` if the server is stopping
+    let ended = 'events: loop {
+        let frames = tokio::select! {
+            frame = rx.next() => {
+                let res = frame.map(|frame| {
+                    let mut state = state.lock().unwrap();
+                    state.mark_received(addr);
+                    session.handle_frame(frame?, addr, &mut state)
+                });
+                match res {
+                    Some(Ok(frames)) => frames,
+                    Some(Err(e)) => break 'events Some(e.to_string()),
+                    None => break 'events Some("The client closed the connection".to_string()),
+                }
+            },
+            packet = packet_rx.next() => match packet {
+                Some(packet) => match packet.to_received() {
+                    Some(packet) => session.translate(packet, &state.lock().unwrap()),
+                    None => continue,
+                },
+                None => break 'events None,
+            },
+            _ = flush.tick() => session.flush(),
+            _ = &mut stopping => break 'events None,
+        };
+
+        if session.period() != period {
+            period = session.period();
+            flush = tokio::time::interval(period);
+        }
+
+        for frame in frames {
+            if let Err(e) = tx.send(frame).await {
+                break 'events Some(e.to_string());
+            }
+        }
+    };
+
+    if ended.is_none() {
+        // Values queued before the server stopped are still sent
+        let mut frames = Vec::new();
+        while let Ok(packet) = packet_rx.try_recv() {
+            if let Some(packet) = packet.to_received() {
+                frames.extend(session.translate(packet, &state.lock().unwrap()));
+            }
+        }
+        frames.extend(session.flush());
+        for frame in frames {
+            if tx.send(frame).await.is_err() {
+                break;
+            }
+        }
+        let _ = tx.close().await;
+    }
+
+    let mut state = state.lock().unwrap();
+    state.clients.remove(&addr);
+    for (_, id) in session.publishers.drain() {
+        state.release_publisher(id);
+    }
+    let reason = ended.unwrap_or_else(|| "The server stopped".to_string());
+    state.client_disconnected(addr, reason);
+    Ok(())
+}
+
+impl Session {
+    /// The flush period requested by the most demanding subscription
+    fn period(&self) -> Duration {
+        self.subscriptions
+            .values()
+            .map(|sub| Duration::from_secs_f64(sub.options.periodic.max(0.005)))
+            .min()
+            .unwrap_or(DEFAULT_PERIOD)
+    }
+
+    fn matching<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Subscribe> + 'a {
+        self.subscriptions
+            .values()
+            .filter(move |sub| sub_matches(sub, name))
+    }
+
+    fn is_subscribed(&self, name: &str) -> bool {
+        self.matching(name).next().is_some()
+    }
+
+    fn wants_values(&self, name: &str) -> bool {
+        self.matching(name).any(|sub| !sub.options.topics_only)
+    }
+
+    fn wants_all(&self, name: &str) -> bool {
+        self.matching(name)
+            .any(|sub| !sub.options.topics_only && sub.options.all)
+    }
+
+    fn announce(&mut self, id: u16, data: &EntryData, pubuid: Option<i32>) -> ControlMessage {
+        self.announced.insert(
+            id,
+            AnnouncedTopic {
+                name: data.name.clone(),
+                properties: data.properties.clone(),
+            },
+        );
+        ControlMessage::Announce(Announce {
+            name: data.name.clone(),
+            id: id as i32,
+            type_str: data.entry_type().nt4_type_str().to_string(),
+            pubuid,
+            properties: data.properties.clone(),
+        })
+    }
+
+    /// Sends the client any changes to the properties of an announced topic
+    fn update_properties(&mut self, id: u16, data: &EntryData, control: &mut Vec<ControlMessage>) {
+        let topic = match self.announced.get_mut(&id) {
+            Some(topic) => topic,
+            None => return,
+        };
+
+        let update = properties_diff(&topic.properties, &data.properties);
+        topic.properties = data.properties.clone();
+        if !update.is_empty() {
+            control.push(ControlMessage::Properties(PropertiesUpdate {
+                name: data.name.clone(),
+                ack: false,
+                update,
+            }));
+        }
+    }
+
+    /// Queues a value to be sent to the client, respecting its subscription options
+    fn push_value(&mut self, name: &str, frame: ValueFrame, values: &mut Vec<ValueFrame>) {
+        let id = frame.topic_id as u16;
+        if self.wants_all(name) {
+            values.push(frame);
+        } else if self.wants_values(name) {
+            self.queued.insert(id, frame);
+        }
+    }
+
+    fn flush(&mut self) -> Vec<NT4Frame> {
+        if self.queued.is_empty() {
+            return Vec::new();
+        }
+        vec![NT4Frame::Values(
+            self.queued.drain().map(|(_, frame)| frame).collect(),
+        )]
+    }
+
+    fn handle_frame(
+        &mut self,
+        frame: NT4Frame,
+        addr: SocketAddr,
+        state: &mut ServerState,
+    ) -> crate::Result<Vec<NT4Frame>> {
+        let mut control = Vec::new();
+        let mut values = Vec::new();
+
+        match frame {
+            NT4Frame::Control(messages) => {
+                for msg in messages {
+                    self.handle_control(msg, addr, state, &mut control, &mut values)?;
+                }
+            }
+            NT4Frame::Values(frames) => {
+                for frame in frames {
+                    self.handle_value(frame, addr, state, &mut values)?;
+                }
+            }
+        }
+
+        Ok(into_frames(control, values))
+    }
+
+    /// Fails once this client has as many publishers or subscriptions as the server has room for
+    /// entries, unless the new one is replacing one it already has
+    fn check_limit(
+        what: &'static str,
+        count: usize,
+        replacing: bool,
+        state: &ServerState,
+    ) -> crate::Result<()> {
+        let limit = state.limits().max_entries;
+        if !replacing && count >= limit {
+            return Err(Error::LimitExceeded { what, limit });
+        }
+        Ok(())
+    }
+
+    fn handle_control(
+        &mut self,
+        msg: ControlMessage,
+        addr: SocketAddr,
+        state: &mut ServerState,
+        control: &mut Vec<ControlMessage>,
+        values: &mut Vec<ValueFrame>,
+    ) -> crate::Result<()> {
+        match msg {
+            ControlMessage::Publish(Publish {
+                name,
+                pubuid,
+                type_str,
+                properties,
+            }) => {
+                let replacing = self.publishers.contains_key(&pubuid)
+                    || self.pending_publishes.contains_key(&pubuid);
+                let count = self.publishers.len() + self.pending_publishes.len();
+                Self::check_limit("Publisher count", count, replacing, state)?;
+
+                let existing = state
+                    .entry_ids
+                    .get(&name)
+                    .and_then(|id| Some((*id, state.entries.get(id)?.clone())));
+
+                match existing {
+                    Some((id, data)) => {
+                        if let Some(publishers) = state.nt4_publishers.get_mut(&id) {
+                            *publishers += 1;
+                        }
+                        self.publishers.insert(pubuid, id);
+                        control.push(self.announce(id, &data, Some(pubuid)));
+                    }
+                    // NT3 entries can't exist without a value, so the entry is created once the first value arrives
+                    None => {
+                        if let Some(entry_type) = EntryType::from_nt4_type_str(&type_str) {
+                            let pending = PendingPublish {
+                                name,
+                                entry_type,
+                                properties,
+                            };
+                            self.pending_publishes.insert(pubuid, pending);
+                        }
+                    }
+                }
+            }
+            ControlMessage::Unpublish(unpublish) => {
+                self.pending_publishes.remove(&unpublish.pubuid);
+                if let Some(id) = self.publishers.remove(&unpublish.pubuid) {
+                    state.release_publisher(id);
+                }
+            }
+            ControlMessage::SetProperties(set) => {
+                // Topics that are yet to be created keep the update until their first value arrives
+                if let Some(pending) = self
+                    .pending_publishes
+                    .values_mut()
+                    .find(|pending| pending.name == set.name)
+                {
+                    for (key, value) in &set.update {
+                        if value.is_null() {
+                            pending.properties.remove(key);
+                        } else {
+                            pending.properties.insert(key.clone(), value.clone());
+                        }
+                    }
+                }
+
+                let id = state.entry_ids.get(&set.name).copied();
+                let entry = id.and_then(|id| Some((id, state.entries.get_mut(&id)?)));
+                if let Some((id, entry)) = entry {
+                    let old = entry.clone();
+                    entry.update_properties(&set.update);
+                    let flags = entry.flags;
+                    let entry = entry.clone();
+
+                    // NT4 clients turn this into a properties message, NT3 clients only see the persistent flag
+                    let packet = EntryFlagsUpdate::new(id, flags);
+                    for tx in state
+                        .clients
+                        .iter()
+                        .filter(|(client, _)| **client != addr)
+                        .map(|(_, tx)| tx)
+                    {
+                        let _ = tx.unbounded_send(Box::new(packet));
+                    }
+
+                    state.subscribers.notify(
+                        EntryEventKind::FlagsChanged,
+                        id,
+                        Some(&old),
+                        Some(&entry),
+                        EventSource::Remote,
+                    );
+
+                    if let Some(topic) = self.announced.get_mut(&id) {
+                        topic.properties = entry.properties;
+                    }
+                }
+
+                control.push(ControlMessage::Properties(PropertiesUpdate {
+                    name: set.name,
+                    ack: true,
+                    update: set.update,
+                }));
+            }
+            ControlMessage::Subscribe(subscribe) => {
+                let subuid = subscribe.subuid;
+                let replacing = self.subscriptions.contains_key(&subuid);
+                let count = self.subscriptions.len();
+                Self::check_limit("Subscription count", count, replacing, state)?;
+                self.subscriptions.insert(subuid, subscribe);
+
+                let mut entries = state
+                    .entries
+                    .iter()
+                    .filter(|(_, data)| sub_matches(&self.subscriptions[&subuid], &data.name))
+                    .collect::<Vec<_>>();
+                entries.sort_by_key(|(id, _)| **id);
+
+                for (id, data) in entries {
+                    if !self.announced.contains_key(id) {
+                        control.push(self.announce(*id, data, None));
+                    }
+                    // Uncached topics only send values as they are published
+                    if self.wants_values(&data.name) && data.is_cached() {
+                        let timestamp = data.timestamp.unwrap_or_else(|| state.server_time());
+                        values.push(ValueFrame::new(*id as i64, timestamp, data.value.clone()));
+                    }
+                }
+            }
+            ControlMessage::Unsubscribe(unsubscribe) => {
+                self.subscriptions.remove(&unsubscribe.subuid);
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn handle_value(
+        &mut self,
+        frame: ValueFrame,
+        addr: SocketAddr,
+        state: &mut ServerState,
+        values: &mut Vec<ValueFrame>,
+    ) -> crate::Result<()> {
+        let now = state.server_time();
+
+        // Time synchronization pings are answered immediately, with the client's time echoed back
+        if frame.topic_id == RTT_TOPIC_ID {
+            values.push(ValueFrame::new(RTT_TOPIC_ID, now, frame.entry_value));
+            return Ok(());
+        }
+
+        // Clients that haven't synchronized their clock send 0, asking the server to use its own time
+        let timestamp = if frame.timestamp == 0 {
+            now
+        } else {
+            frame.timestamp
+        };
+        let pubuid = frame.topic_id as i32;
+
+        if let Some(pending) = self.pending_publishes.remove(&pubuid) {
+            if frame.entry_value.entry_type() != pending.entry_type {
+                self.pending_publishes.insert(pubuid, pending);
+                return Ok(());
+            }
+            state.check_entry_limit()?;
+
+            let mut data = EntryData::new(pending.name, 0, frame.entry_value);
+            data.timestamp = Some(timestamp);
+            data.update_properties(&pending.properties);
+            // The assignment broadcast by add_entry comes back through this client's channel, and is
+            // announced with the pubuid once it arrives
+            let id = state.add_entry(data, EventSource::Remote)?;
+            self.publishers.insert(pubuid, id);
+            state.nt4_publishers.insert(id, 1);
+            return Ok(());
+        }
+
+        let id = match self.publishers.get(&pubuid) {
+            Some(id) => *id,
+            None => return Ok(()),
+        };
+
+        if let Some(entry) = state.entries.get_mut(&id) {
+            if entry.entry_type() != frame.entry_value.entry_type() {
+                return Ok(());
+            }
+            let old = entry.clone();
+            entry.value = frame.entry_value;
+            entry.seqnum = entry.seqnum.next();
+            entry.timestamp = Some(timestamp);
+
+            let entry = entry.clone();
+            let packet =
+                EntryUpdate::new(id, entry.seqnum.0, entry.entry_type(), entry.value.clone());
+            for tx in state
+                .clients
+                .iter()
+                .filter(|(client, _)| **client != addr)
+                .map(|(_, tx)| tx)
+            {
+                let _ = tx.unbounded_send(Box::new(packet.clone()));
+            }
+
+            state.fire_callbacks(CallbackType::Update, &entry);
+            state.subscribers.notify(
+                EntryEventKind::Updated,
+                id,
+                Some(&old),
+                Some(&entry),
+                EventSource::Remote,
+            );
+        }
+        Ok(())
+    }
+
+    /// Translates a packet broadcast by the `ServerState` into the NT4 messages this client asked for
+    fn translate(&mut self, packet: ReceivedPacket, state: &ServerState) -> Vec<NT4Frame> {
+        let mut control = Vec::new();
+        let mut values = Vec::new();
+        let timestamp = |id: u16| {
+            state
+                .entries
+                .get(&id)
+                .and_then(|data| data.timestamp)
+                .unwrap_or_else(|| state.server_time())
+        };
+
+        match packet {
+            ReceivedPacket::EntryAssignment(ea) => {
+                let pubuid = self
+                    .publishers
+                    .iter()
+                    .find(|(_, id)| **id == ea.entry_id)
+                    .map(|(pubuid, _)| *pubuid);
+                let data = match state.entries.get(&ea.entry_id) {
+                    Some(data) => data.clone(),
+                    None => EntryData::new(ea.entry_name, ea.entry_flags, ea.entry_value),
+                };
+
+                // Publishers are always told the id of their topic, even if they aren't subscribed to it
+                if pubuid.is_some() || self.is_subscribed(&data.name) {
+                    control.push(self.announce(ea.entry_id, &data, pubuid));
+                    let frame =
+                        ValueFrame::new(ea.entry_id as i64, timestamp(ea.entry_id), data.value);
+                    self.push_value(&data.name, frame, &mut values);
+                }
+            }
+            ReceivedPacket::EntryUpdate(eu) => {
+                let frame =
+                    ValueFrame::new(eu.entry_id as i64, timestamp(eu.entry_id), eu.entry_value);
+                if let Some(topic) = self.announced.get(&eu.entry_id) {
+                    let name = topic.name.clone();
+                    self.push_value(&name, frame, &mut values);
+                } else if let Some(data) = state.entries.get(&eu.entry_id) {
+                    if self.is_subscribed(&data.name) {
+                        let data = data.clone();
+                        control.push(self.announce(eu.entry_id, &data, None));
+                        self.push_value(&data.name, frame, &mut values);
+                    }
+                }
+            }
+            ReceivedPacket::EntryFlagsUpdate(efu) => {
+                if let Some(data) = state.entries.get(&efu.entry_id) {
+                    self.update_properties(efu.entry_id, data, &mut control);
+                }
+            }
+            ReceivedPacket::EntryDelete(ed) => {
+                self.queued.remove(&ed.entry_id);
+                self.publishers.retain(|_, id| *id != ed.entry_id);
+                if let Some(topic) = self.announced.remove(&ed.entry_id) {
+                    control.push(ControlMessage::Unannounce(Unannounce {
+                        name: topic.name,
+                        id: ed.entry_id as i32,
+                    }));
+                }
+            }
+            ReceivedPacket::ClearAllEntries(_) => {
+                self.queued.clear();
+                self.publishers.clear();
+                for (id, topic) in self.announced.drain() {
+                    control.push(ControlMessage::Unannounce(Unannounce {
+                        name: topic.name,
+                        id: id as i32,
+                    }));
+                }
+            }
+            _ => {}
+        }
+
+        into_frames(control, values)
+    }
+}
+
+fn sub_matches(sub: &Subscribe, name: &str) -> bool {
+    sub.topics.iter().any(|topic| {
+        if sub.options.prefix {
+            name.starts_with(topic.as_str())
+        } else {
+            name == topic
+        }
+    })
+}
+
+/// The update that turns the `old` properties into the `new` ones, with removed properties set to `null`
+fn properties_diff(old: &Properties, new: &Properties) -> Properties {
+    let mut update = Properties::new();
+    for (key, value) in new {
+        if old.get(key) != Some(value) {
+            update.insert(key.clone(), value.clone());
+        }
+    }
+    for key in old.keys().filter(|key| !new.contains_key(*key)) {
+        update.insert(key.clone(), serde_json::Value::Null);
+    }
+    update
+}
+
+/// Control messages are sent first, so that topics are announced before any of their values
+fn into_frames(control: Vec<ControlMessage>, values: Vec<ValueFrame>) -> Vec<NT4Frame> {
+    let mut frames = Vec::new();
+    if !control.is_empty() {
+        frames.push(NT4Frame::Control(control));
+    }
+    if !values.is_empty() {
+        frames.push(NT4Frame::Values(values));
+    }
+    frames
+}
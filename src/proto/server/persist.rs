@@ -0,0 +1,85 @@
+use crate::proto::server::ServerState;
+use crate::proto::State;
+use crate::EntryEvent;
+use futures_util::future::FusedFuture;
+use futures_util::{pin_mut, select, FutureExt, StreamExt};
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::warn;
+
+/// How long to wait after a change before saving, so that changes made together are written together
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+/// Saves the persistent entries of the server to `path` whenever one of them changes, until
+/// `stopping` completes. Changes that haven't been saved by then are saved before returning.
+pub async fn save_on_change(
+    path: PathBuf,
+    state: Arc<Mutex<ServerState>>,
+    stopping: impl Future<Output = ()>,
+) {
+    let mut events = state.lock().unwrap().subscribe(String::new()).fuse();
+    let stopping = stopping.fuse();
+    pin_mut!(stopping);
+
+    loop {
+        let mut changed = select! {
+            event = events.next() => match event {
+                Some(event) => affects_persistent(&event),
+                None => return,
+            },
+            _ = stopping => false,
+        };
+        if !changed && !stopping.is_terminated() {
+            continue;
+        }
+
+        let stopped = stopping.is_terminated() || {
+            let delay = tokio::time::delay_for(SAVE_DELAY).fuse();
+            pin_mut!(delay);
+            select! {
+                _ = delay => false,
+                _ = stopping => true,
+            }
+        };
+        // Anything that happened in the meantime is covered by this save
+        while let Some(Some(event)) = events.next().now_or_never() {
+            changed |= affects_persistent(&event);
+        }
+
+        if changed {
+            save(&path, &state).await;
+        }
+        if stopped {
+            return;
+        }
+    }
+}
+
+/// Saves the persistent entries on the blocking thread pool, as writing the file blocks
+async fn save(path: &Path, state: &Mutex<ServerState>) {
+    let entries = state
+        .lock()
+        .unwrap()
+        .entries()
+        .values()
+        .filter(|entry| entry.is_persistent())
+        .cloned()
+        .collect::<Vec<_>>();
+    let file = path.to_path_buf();
+    let res = tokio::task::spawn_blocking(move || crate::persist::save(&file, &entries)).await;
+    match res {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => warn!(?path, error = %e, "Failed to save persistent entries"),
+        Err(e) => warn!(?path, error = %e, "Saving persistent entries panicked"),
+    }
+}
+
+fn affects_persistent(event: &EntryEvent) -> bool {
+    event
+        .old
+        .iter()
+        .chain(event.new.iter())
+        .any(|entry| entry.is_persistent())
+}
@@ -5,20 +5,30 @@ use futures_util::sink::Sink;
 use futures_util::stream::Stream;
 use futures_util::task::{Context, Poll};
 use nt_network::codec::NTCodec;
-use nt_network::{Packet, ReceivedPacket};
+use nt_network::nt4::{self, NT4Frame};
+use nt_network::{DecodeError, DecodeLimits, Packet, ReceivedPacket};
 use tokio::net::TcpStream;
 use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
 use tokio_util::codec::{Decoder, Encoder};
+use tracing::{debug, trace};
 
+/// Adapts a websocket carrying NT3 packets into a stream and sink of them
 pub struct WSCodec {
     sock: WebSocketStream<TcpStream>,
+    codec: NTCodec,
     rd: BytesMut,
 }
 
 impl WSCodec {
     pub fn new(sock: WebSocketStream<TcpStream>) -> WSCodec {
+        Self::with_limits(sock, DecodeLimits::default())
+    }
+
+    /// A codec that fails to decode packets larger than `limits` allow
+    pub fn with_limits(sock: WebSocketStream<TcpStream>, limits: DecodeLimits) -> WSCodec {
         WSCodec {
             sock,
+            codec: NTCodec::with_limits(limits),
             rd: BytesMut::new(),
         }
     }
@@ -28,35 +38,43 @@ impl Stream for WSCodec {
     type Item = crate::Result<ReceivedPacket>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        if self.rd.is_empty() {
-            match futures_util::ready!(Stream::poll_next(Pin::new(&mut self.sock), cx)) {
-                Some(msg) => match msg {
-                    Ok(msg) => {
-                        self.rd.extend_from_slice(&msg.into_data()[..]);
-                        match NTCodec.decode(&mut self.rd) {
-                            Ok(Some(packet)) => Poll::Ready(Some(Ok(packet))),
-                            // Server should never split NT packets across multiple websocket packets
-                            Ok(None) => panic!("We shouldn't get here nominally"),
-                            Err(e) => Poll::Ready(Some(Err(e.into()))),
-                        }
-                    }
-                    Err(e) => Poll::Ready(Some(Err(e.into()))),
-                },
-                None => Poll::Ready(None),
-            }
-        } else {
-            match NTCodec.decode(&mut self.rd) {
-                Ok(Some(packet)) => Poll::Ready(Some(Ok(packet))),
-                // Server should never split NT packets across multiple websocket packets
-                Ok(None) => panic!("We shouldn't get here nominally"),
-                Err(e) => Poll::Ready(Some(Err(e.into()))),
+        while self.rd.is_empty() {
+            let msg = match futures_util::ready!(Stream::poll_next(Pin::new(&mut self.sock), cx)) {
+                Some(Ok(msg)) => msg,
+                Some(Err(e)) => {
+                    debug!(error = %e, "Failed to read websocket message");
+                    return Poll::Ready(Some(Err(e.into())));
+                }
+                None => return Poll::Ready(None),
+            };
+
+            match msg {
+                Message::Close(_) => return Poll::Ready(None),
+                // Pings are answered by tungstenite, there's nothing to decode in them
+                Message::Ping(_) | Message::Pong(_) => continue,
+                msg => {
+                    trace!(len = msg.len(), "Received websocket message");
+                    self.rd.extend_from_slice(&msg.into_data()[..]);
+                }
             }
         }
+
+        let this = &mut *self;
+        let res = match this.codec.decode(&mut this.rd) {
+            Ok(Some(packet)) => Ok(packet),
+            // NT packets are never split across websocket messages, so part of one is malformed
+            Ok(None) => Err(DecodeError::Incomplete),
+            Err(e) => Err(e),
+        };
+        if res.is_err() {
+            this.rd.clear();
+        }
+        Poll::Ready(Some(res.map_err(Into::into)))
     }
 }
 
 impl Sink<Box<dyn Packet>> for WSCodec {
-    type Error = anyhow::Error;
+    type Error = crate::error::Error;
 
     fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         Sink::poll_ready(Pin::new(&mut self.sock), cx).map_err(Into::into)
@@ -64,7 +82,8 @@ impl Sink<Box<dyn Packet>> for WSCodec {
 
     fn start_send(mut self: Pin<&mut Self>, item: Box<dyn Packet>) -> Result<(), Self::Error> {
         let mut wr = BytesMut::new();
-        NTCodec.encode(item, &mut wr).unwrap();
+        self.codec.encode(item, &mut wr)?;
+        trace!(len = wr.len(), "Sending websocket message");
 
         Sink::start_send(Pin::new(&mut self.sock), Message::Binary(wr.to_vec())).map_err(Into::into)
     }
@@ -77,3 +96,79 @@ impl Sink<Box<dyn Packet>> for WSCodec {
         Sink::poll_close(Pin::new(&mut self.sock), cx).map_err(Into::into)
     }
 }
+
+/// Adapts a websocket negotiated with the NT4 subprotocol into a stream and sink of `NT4Frame`s
+pub struct NT4Codec {
+    sock: WebSocketStream<TcpStream>,
+    limits: DecodeLimits,
+}
+
+impl NT4Codec {
+    pub fn new(sock: WebSocketStream<TcpStream>) -> NT4Codec {
+        Self::with_limits(sock, DecodeLimits::default())
+    }
+
+    /// A codec that fails to decode messages with strings or arrays larger than `limits` allow
+    pub fn with_limits(sock: WebSocketStream<TcpStream>, limits: DecodeLimits) -> NT4Codec {
+        NT4Codec { sock, limits }
+    }
+}
+
+impl Stream for NT4Codec {
+    type Item = crate::Result<NT4Frame>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        loop {
+            let msg = match futures_util::ready!(Stream::poll_next(Pin::new(&mut self.sock), cx)) {
+                Some(Ok(msg)) => msg,
+                Some(Err(e)) => return Poll::Ready(Some(Err(e.into()))),
+                None => return Poll::Ready(None),
+            };
+
+            let limits = self.limits;
+            let frame = match msg {
+                Message::Text(text) => nt4::decode_text(&text).and_then(|messages| {
+                    messages
+                        .iter()
+                        .try_for_each(|msg| limits.check_control(msg))?;
+                    Ok(NT4Frame::Control(messages))
+                }),
+                Message::Binary(data) => nt4::decode_binary(&data[..]).and_then(|values| {
+                    values
+                        .iter()
+                        .try_for_each(|v| limits.check_value_frame(v))?;
+                    Ok(NT4Frame::Values(values))
+                }),
+                Message::Close(_) => return Poll::Ready(None),
+                // Pings are answered by tungstenite, there's nothing to hand back for them
+                Message::Ping(_) | Message::Pong(_) => continue,
+            };
+            return Poll::Ready(Some(frame.map_err(Into::into)));
+        }
+    }
+}
+
+impl Sink<NT4Frame> for NT4Codec {
+    type Error = crate::error::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::poll_ready(Pin::new(&mut self.sock), cx).map_err(Into::into)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: NT4Frame) -> Result<(), Self::Error> {
+        let msg = match item {
+            NT4Frame::Control(messages) => Message::Text(nt4::encode_text(&messages)?),
+            NT4Frame::Values(values) => Message::Binary(nt4::encode_binary(&values)?),
+        };
+
+        Sink::start_send(Pin::new(&mut self.sock), msg).map_err(Into::into)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::poll_flush(Pin::new(&mut self.sock), cx).map_err(Into::into)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Sink::poll_close(Pin::new(&mut self.sock), cx).map_err(Into::into)
+    }
+}